@@ -40,6 +40,14 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
         });
     });
 
+    c.bench_function("insert batch 10k", |b| {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(false)));
+
+        let (keys, values) = random_data(10000);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().zip(values).collect();
+        b.iter(|| trie.insert_batch(entries.clone()).unwrap());
+    });
+
     c.bench_function("get based 10k", |b| {
         let mut trie = EthTrie::new(Arc::new(MemoryDB::new(false)));
 
@@ -0,0 +1,273 @@
+use std::sync::Arc;
+
+use keccak_hash::H256;
+
+use crate::db::{AsyncDB, MemoryDB, DB};
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, Trie, TrieResult};
+
+/// A trie backed by an `AsyncDB`, whose reads and writes of the db are awaited instead of
+/// blocking.
+///
+/// The trie logic is the one of `EthTrie`, run against an in-memory copy of the nodes it
+/// has needed so far. When an operation reaches a node that is not in memory yet, the node
+/// is fetched from the db and the operation is retried, so each node is fetched once no
+/// matter how many later operations go through it. `root_hash` commits the changes to the
+/// in-memory copy and then writes the new nodes to the db, and removes the replaced ones,
+/// with one `insert_batch` and one `remove_batch`.
+///
+/// Nodes fetched from the db stay in memory for the lifetime of the trie.
+#[derive(Debug)]
+pub struct AsyncEthTrie<A, H = KeccakHasher>
+where
+    A: AsyncDB,
+    H: Hasher,
+{
+    trie: EthTrie<MemoryDB, H>,
+    nodes: Arc<MemoryDB>,
+    db: Arc<A>,
+}
+
+impl<A> AsyncEthTrie<A>
+where
+    A: AsyncDB,
+{
+    /// Creates an empty trie.
+    pub fn new(db: Arc<A>) -> Self {
+        Self::with_hasher(db)
+    }
+}
+
+impl<A, H> AsyncEthTrie<A, H>
+where
+    A: AsyncDB,
+    H: Hasher,
+{
+    /// Creates an empty trie whose nodes are hashed with H.
+    pub fn with_hasher(db: Arc<A>) -> Self {
+        let nodes = Arc::new(MemoryDB::new(true));
+        AsyncEthTrie {
+            trie: EthTrie::with_hasher(nodes.clone()),
+            nodes,
+            db,
+        }
+    }
+
+    /// Opens the trie with the given root hash. Nothing is read from the db until the
+    /// first operation.
+    pub fn at_root(&self, root_hash: H256) -> Self {
+        let nodes = Arc::new(MemoryDB::new(true));
+        AsyncEthTrie {
+            trie: EthTrie::with_hasher(nodes.clone()).at_root(root_hash),
+            nodes,
+            db: self.db.clone(),
+        }
+    }
+
+    /// Returns the value for key stored in the trie.
+    pub async fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        loop {
+            let result = self.trie.get(key);
+            if let Err(TrieError::MissingTrieNode { node_hash, .. }) = result {
+                if self.fetch(node_hash).await? {
+                    continue;
+                }
+            }
+            return result;
+        }
+    }
+
+    /// Inserts value into trie and modifies it if it exists, like `EthTrie::insert`.
+    pub async fn insert(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
+        loop {
+            let result = self.trie.insert(key, value);
+            if let Err(TrieError::MissingTrieNode { node_hash, .. }) = result {
+                if self.fetch(node_hash).await? {
+                    continue;
+                }
+            }
+            return result;
+        }
+    }
+
+    /// Commits the changes to the db and returns the new root hash.
+    pub async fn root_hash(&mut self) -> TrieResult<H256> {
+        let report = self.trie.commit_with_report()?;
+
+        let mut keys = Vec::with_capacity(report.written.len());
+        let mut values = Vec::with_capacity(report.written.len());
+        for hash in report.written {
+            let value = self
+                .nodes
+                .get(hash.as_bytes())
+                .map_err(|e| TrieError::DB(e.to_string()))?
+                .ok_or(TrieError::MissingTrieNode {
+                    node_hash: hash,
+                    traversed: None,
+                    root_hash: Some(report.root),
+                    err_key: None,
+                })?;
+            keys.push(hash.as_bytes().to_vec());
+            values.push(value);
+        }
+        self.db
+            .insert_batch(keys, values)
+            .await
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        let removed_keys: Vec<Vec<u8>> = report
+            .deleted
+            .iter()
+            .map(|hash| hash.as_bytes().to_vec())
+            .collect();
+        self.db
+            .remove_batch(&removed_keys)
+            .await
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        Ok(report.root)
+    }
+
+    pub fn db(&self) -> &Arc<A> {
+        &self.db
+    }
+
+    // Copies the node with the given hash from the db to memory. Returns false if the db
+    // does not have it, or has data that does not hash to it.
+    async fn fetch(&self, node_hash: H256) -> TrieResult<bool> {
+        let data = self
+            .db
+            .get(node_hash.as_bytes())
+            .await
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        match data {
+            Some(data) if H::hash(&data) == node_hash => {
+                self.nodes
+                    .insert(node_hash.as_bytes(), data)
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    use super::*;
+    use crate::db::{DBFuture, SyncDB};
+
+    // Polls a future that never has to wait, as the futures of `SyncDB` never do.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    // Counts the reads of the wrapped db.
+    struct CountingDB {
+        db: SyncDB<MemoryDB>,
+        reads: AtomicUsize,
+    }
+
+    impl AsyncDB for CountingDB {
+        type Error = <MemoryDB as DB>::Error;
+
+        fn get<'a>(&'a self, key: &'a [u8]) -> DBFuture<'a, Option<Vec<u8>>, Self::Error> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.db.get(key)
+        }
+
+        fn insert_batch(
+            &self,
+            keys: Vec<Vec<u8>>,
+            values: Vec<Vec<u8>>,
+        ) -> DBFuture<'_, (), Self::Error> {
+            self.db.insert_batch(keys, values)
+        }
+
+        fn remove_batch<'a>(&'a self, keys: &'a [Vec<u8>]) -> DBFuture<'a, (), Self::Error> {
+            self.db.remove_batch(keys)
+        }
+
+        fn flush(&self) -> DBFuture<'_, (), Self::Error> {
+            self.db.flush()
+        }
+    }
+
+    #[test]
+    fn test_async_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let db = Arc::new(CountingDB {
+            db: SyncDB(memdb.clone()),
+            reads: AtomicUsize::new(0),
+        });
+
+        let mut trie = AsyncEthTrie::new(db.clone());
+        let expected_db = Arc::new(MemoryDB::new(true));
+        let mut expected = EthTrie::new(expected_db.clone());
+        for i in 0..50u32 {
+            let value = format!("value-{}-long-enough-to-be-hashed", i);
+            block_on(trie.insert(&i.to_be_bytes(), value.as_bytes())).unwrap();
+            expected.insert(&i.to_be_bytes(), value.as_bytes()).unwrap();
+        }
+        let root = block_on(trie.root_hash()).unwrap();
+        assert_eq!(root, expected.root_hash().unwrap());
+        assert_eq!(db.reads.load(Ordering::SeqCst), 0);
+
+        // A trie opened at the root fetches the nodes it needs, each one once
+        let mut trie = trie.at_root(root);
+        let value = block_on(trie.get(&7u32.to_be_bytes())).unwrap();
+        assert_eq!(value, Some(b"value-7-long-enough-to-be-hashed".to_vec()));
+        let reads = db.reads.load(Ordering::SeqCst);
+        assert!(reads > 0);
+        block_on(trie.get(&7u32.to_be_bytes())).unwrap();
+        assert_eq!(db.reads.load(Ordering::SeqCst), reads);
+        assert_eq!(block_on(trie.get(b"missing")).unwrap(), None);
+
+        // Changes are written back with the replaced nodes removed
+        block_on(trie.insert(&7u32.to_be_bytes(), b"changed")).unwrap();
+        expected.insert(&7u32.to_be_bytes(), b"changed").unwrap();
+        let root = block_on(trie.root_hash()).unwrap();
+        assert_eq!(root, expected.root_hash().unwrap());
+        let reopened = EthTrie::new(memdb.clone()).at_root(root);
+        for i in 0..50u32 {
+            assert_eq!(
+                reopened.get(&i.to_be_bytes()).unwrap(),
+                expected.get(&i.to_be_bytes()).unwrap()
+            );
+        }
+        // None of the replaced nodes are left behind
+        let kept = expected_db.keys().unwrap();
+        assert!(memdb.keys().unwrap().iter().all(|key| kept.contains(key)));
+    }
+
+    #[test]
+    fn test_async_trie_missing_node() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let trie = AsyncEthTrie::new(Arc::new(SyncDB(memdb)));
+        let trie = trie.at_root(H256::repeat_byte(1));
+        assert!(matches!(
+            block_on(trie.get(b"key")),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+    }
+}
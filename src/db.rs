@@ -1,10 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
-use crate::errors::MemDBError;
+use crate::errors::{MemDBError, TrieError};
+use crate::trie::TrieResult;
 
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
@@ -41,6 +45,14 @@ pub trait DB: Send + Sync {
     /// Flush data to the DB from the cache.
     fn flush(&self) -> Result<(), Self::Error>;
 
+    /// Returns the keys of all stored entries, in no particular order.
+    ///
+    /// Backends that cannot enumerate their keys keep the default, which returns
+    /// `TrieError::Unsupported`.
+    fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        Err(TrieError::Unsupported)
+    }
+
     #[cfg(test)]
     fn len(&self) -> Result<usize, Self::Error>;
     #[cfg(test)]
@@ -52,13 +64,52 @@ pub struct MemoryDB {
     // If "light" is true, the data is deleted from the database at the time of submission.
     light: bool,
     storage: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    // The number of entries removed so far
+    deletes: AtomicUsize,
+}
+
+/// A snapshot of the contents of a `MemoryDB`, returned by `MemoryDB::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryDbStats {
+    /// The number of stored entries.
+    pub entries: usize,
+    /// The total length of the stored keys and values.
+    pub bytes: usize,
+    /// The number of entries removed since the db was created. Always 0 for a db that
+    /// keeps removed entries.
+    pub deletes: usize,
 }
 
 impl MemoryDB {
+    /// Creates an empty db. If `light` is true, removed entries are deleted, as the nodes a
+    /// trie replaces on commit. Otherwise removals are ignored, and every node ever written
+    /// is kept, so older roots stay readable.
     pub fn new(light: bool) -> Self {
+        Self::with_capacity(light, 0)
+    }
+
+    /// Like `new`, with room for at least `capacity` entries before the map reallocates.
+    pub fn with_capacity(light: bool, capacity: usize) -> Self {
         MemoryDB {
             light,
-            storage: Arc::new(RwLock::new(HashMap::new())),
+            storage: Arc::new(RwLock::new(HashMap::with_capacity(capacity))),
+            deletes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Removes every entry, keeping the allocated capacity for reuse. Cleared entries are
+    /// not counted as deletes in `stats`.
+    pub fn clear(&self) {
+        self.storage.write().clear();
+    }
+
+    /// Returns the number of entries, their total size and the number of removed entries.
+    pub fn stats(&self) -> MemoryDbStats {
+        let storage = self.storage.read();
+        MemoryDbStats {
+            entries: storage.len(),
+            bytes: storage.iter().map(|(k, v)| k.len() + v.len()).sum(),
+            deletes: self.deletes.load(Ordering::Relaxed),
         }
     }
 }
@@ -80,8 +131,8 @@ impl DB for MemoryDB {
     }
 
     fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
-        if self.light {
-            self.storage.write().remove(key);
+        if self.light && self.storage.write().remove(key).is_some() {
+            self.deletes.fetch_add(1, Ordering::Relaxed);
         }
         Ok(())
     }
@@ -90,6 +141,10 @@ impl DB for MemoryDB {
         Ok(())
     }
 
+    fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        Ok(self.storage.read().keys().cloned().collect())
+    }
+
     #[cfg(test)]
     fn len(&self) -> Result<usize, Self::Error> {
         Ok(self.storage.try_read().unwrap().len())
@@ -100,6 +155,415 @@ impl DB for MemoryDB {
     }
 }
 
+/// Wraps another db with an in-memory cache of the most recently read entries, so that hot
+/// nodes such as the ones near the root are not fetched from a slow backend again and again.
+///
+/// Once the cache holds `capacity` entries, the least recently used one is evicted. Writes
+/// and removals go to the inner db and update the cache to match. The cache stays locked
+/// while a write reaches the inner db and while a missed read fetches from it, so a read
+/// racing a write can't leave the old value cached.
+#[derive(Debug)]
+pub struct CachedDB<D: DB> {
+    inner: D,
+    capacity: usize,
+    cache: Mutex<LruCache>,
+}
+
+// Entries are stamped with the tick of their last use, and `order` maps each stamp back to
+// its key, so the least recently used entry is the first one in `order`.
+#[derive(Debug, Default)]
+struct LruCache {
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl LruCache {
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        let key = self
+            .order
+            .remove(last_used)
+            .expect("every entry is ordered");
+        self.order.insert(tick, key);
+        *last_used = tick;
+        Some(value.clone())
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>, capacity: usize) {
+        self.remove(key);
+        if capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= capacity {
+            let oldest = *self.order.keys().next().expect("the cache is not empty");
+            let key = self.order.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+        self.tick += 1;
+        self.entries.insert(key.to_vec(), (value, self.tick));
+        self.order.insert(self.tick, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+}
+
+impl<D: DB> CachedDB<D> {
+    /// Wraps inner with a cache holding at most capacity entries.
+    pub fn new(inner: D, capacity: usize) -> Self {
+        CachedDB {
+            inner,
+            capacity,
+            cache: Mutex::new(LruCache::default()),
+        }
+    }
+}
+
+impl<D: DB> DB for CachedDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let mut cache = self.cache.lock();
+        if let Some(value) = cache.get(key) {
+            return Ok(Some(value));
+        }
+        let value = self.inner.get(key)?;
+        if let Some(value) = &value {
+            cache.put(key, value.clone(), self.capacity);
+        }
+        Ok(value)
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut cache = self.cache.lock();
+        cache.remove(key);
+        self.inner.insert(key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let mut cache = self.cache.lock();
+        cache.remove(key);
+        self.inner.remove(key)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut cache = self.cache.lock();
+        for key in keys.iter() {
+            cache.remove(key);
+        }
+        self.inner.insert_batch(keys, values)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        let mut cache = self.cache.lock();
+        for key in keys {
+            cache.remove(key);
+        }
+        self.inner.remove_batch(keys)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+
+    fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        self.inner.keys()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        self.inner.len()
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        self.inner.is_empty()
+    }
+}
+
+/// Wraps another db with an in-memory layer of pending changes, so that changes can be
+/// applied speculatively and then either written to the inner db with `commit_overlay` or
+/// dropped with `discard_overlay`.
+///
+/// Writes and removals only touch the overlay, and reads see the overlay first, falling
+/// back to the inner db. The inner db is not changed until the overlay is committed.
+#[derive(Debug)]
+pub struct OverlayDB<D: DB> {
+    inner: D,
+    overlay: Mutex<Overlay>,
+}
+
+// A key is in at most one of the two sets: the last change to it wins.
+#[derive(Debug, Default)]
+struct Overlay {
+    writes: HashMap<Vec<u8>, Vec<u8>>,
+    deletes: HashSet<Vec<u8>>,
+}
+
+impl<D: DB> OverlayDB<D> {
+    /// Wraps inner with an empty overlay.
+    pub fn new(inner: D) -> Self {
+        OverlayDB {
+            inner,
+            overlay: Mutex::new(Overlay::default()),
+        }
+    }
+
+    /// Returns the inner db, which does not see the pending changes.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Writes the pending changes to the inner db and clears the overlay. If the inner db
+    /// fails, the overlay is left as it was so the commit can be retried.
+    pub fn commit_overlay(&self) -> TrieResult<()> {
+        let mut overlay = self.overlay.lock();
+        let (keys, values) = overlay
+            .writes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .unzip();
+        self.inner
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        let deletes: Vec<Vec<u8>> = overlay.deletes.iter().cloned().collect();
+        self.inner
+            .remove_batch(&deletes)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        *overlay = Overlay::default();
+        Ok(())
+    }
+
+    /// Drops the pending changes, leaving the inner db as it was.
+    pub fn discard_overlay(&self) {
+        *self.overlay.lock() = Overlay::default();
+    }
+}
+
+impl<D: DB> DB for OverlayDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        {
+            let overlay = self.overlay.lock();
+            if let Some(value) = overlay.writes.get(key) {
+                return Ok(Some(value.clone()));
+            }
+            if overlay.deletes.contains(key) {
+                return Ok(None);
+            }
+        }
+        self.inner.get(key)
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut overlay = self.overlay.lock();
+        overlay.deletes.remove(key);
+        overlay.writes.insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let mut overlay = self.overlay.lock();
+        overlay.writes.remove(key);
+        overlay.deletes.insert(key.to_vec());
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+
+    fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        let overlay = self.overlay.lock();
+        let mut keys: Vec<Vec<u8>> = self
+            .inner
+            .keys()?
+            .into_iter()
+            .filter(|k| !overlay.deletes.contains(k) && !overlay.writes.contains_key(k))
+            .collect();
+        keys.extend(overlay.writes.keys().cloned());
+        Ok(keys)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        let overlay = self.overlay.lock();
+        let mut len = self.inner.len()?;
+        for key in overlay.deletes.iter() {
+            if self.inner.get(key)?.is_some() {
+                len -= 1;
+            }
+        }
+        for key in overlay.writes.keys() {
+            if self.inner.get(key)?.is_none() {
+                len += 1;
+            }
+        }
+        Ok(len)
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// A node store on disk, keeping every node in one column family of a RocksDB database.
+#[cfg(feature = "rocksdb")]
+#[derive(Debug)]
+pub struct RocksDB {
+    db: rocksdb::DB,
+}
+
+#[cfg(feature = "rocksdb")]
+const ROCKSDB_COLUMN: &str = "trie";
+
+#[cfg(feature = "rocksdb")]
+impl RocksDB {
+    /// Opens the database at path, creating it if it does not exist yet.
+    pub fn open(path: &std::path::Path) -> Result<Self, rocksdb::Error> {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = rocksdb::DB::open_cf(&opts, path, &[ROCKSDB_COLUMN])?;
+        Ok(RocksDB { db })
+    }
+
+    fn column(&self) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(ROCKSDB_COLUMN)
+            .expect("the column family is created on open")
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl DB for RocksDB {
+    type Error = rocksdb::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.db.get_cf(self.column(), key)
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.db.put_cf(self.column(), key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.db.delete_cf(self.column(), key)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        for (key, value) in keys.iter().zip(values) {
+            batch.put_cf(self.column(), key, value);
+        }
+        self.db.write(batch)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete_cf(self.column(), key);
+        }
+        self.db.write(batch)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.db.flush_cf(self.column())
+    }
+
+    fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+        Ok(self
+            .db
+            .iterator_cf(self.column(), rocksdb::IteratorMode::Start)
+            .map(|(key, _)| key.to_vec())
+            .collect())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> Result<usize, Self::Error> {
+        Ok(self
+            .db
+            .iterator_cf(self.column(), rocksdb::IteratorMode::Start)
+            .count())
+    }
+    #[cfg(test)]
+    fn is_empty(&self) -> Result<bool, Self::Error> {
+        Ok(self
+            .db
+            .iterator_cf(self.column(), rocksdb::IteratorMode::Start)
+            .next()
+            .is_none())
+    }
+}
+
+/// The future returned by the methods of `AsyncDB`.
+pub type DBFuture<'a, T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send + 'a>>;
+
+/// The asynchronous counterpart of `DB`, for stores such as a networked key-value service
+/// that should not be called with blocking calls. Used by `AsyncEthTrie`.
+///
+/// The methods return boxed futures so that the trait can be implemented without async
+/// functions in traits; an implementation usually returns `Box::pin(async move { ... })`.
+/// A synchronous `DB` can be used wherever an `AsyncDB` is expected through `SyncDB`.
+pub trait AsyncDB: Send + Sync {
+    type Error: Error;
+
+    fn get<'a>(&'a self, key: &'a [u8]) -> DBFuture<'a, Option<Vec<u8>>, Self::Error>;
+
+    /// Insert a batch of data into the cache.
+    fn insert_batch(
+        &self,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> DBFuture<'_, (), Self::Error>;
+
+    /// Remove a batch of data into the cache.
+    fn remove_batch<'a>(&'a self, keys: &'a [Vec<u8>]) -> DBFuture<'a, (), Self::Error>;
+
+    /// Flush data to the DB from the cache.
+    fn flush(&self) -> DBFuture<'_, (), Self::Error>;
+}
+
+/// Adapts a synchronous `DB` to `AsyncDB`. The returned futures call the db when they are
+/// first polled and block the task until it returns, so this is meant for dbs that do not
+/// block for long, such as `MemoryDB`.
+#[derive(Debug)]
+pub struct SyncDB<D: DB>(pub Arc<D>);
+
+impl<D> AsyncDB for SyncDB<D>
+where
+    D: DB,
+    D::Error: Send,
+{
+    type Error = D::Error;
+
+    fn get<'a>(&'a self, key: &'a [u8]) -> DBFuture<'a, Option<Vec<u8>>, Self::Error> {
+        Box::pin(async move { self.0.get(key) })
+    }
+
+    fn insert_batch(
+        &self,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> DBFuture<'_, (), Self::Error> {
+        Box::pin(async move { self.0.insert_batch(keys, values) })
+    }
+
+    fn remove_batch<'a>(&'a self, keys: &'a [Vec<u8>]) -> DBFuture<'a, (), Self::Error> {
+        Box::pin(async move { self.0.remove_batch(keys) })
+    }
+
+    fn flush(&self) -> DBFuture<'_, (), Self::Error> {
+        Box::pin(async move { self.0.flush() })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,4 +586,157 @@ mod tests {
         let contains = memdb.get(b"test").unwrap();
         assert_eq!(contains, None)
     }
+
+    #[test]
+    fn test_memdb_stats() {
+        let memdb = MemoryDB::with_capacity(true, 16);
+        assert_eq!(memdb.stats(), MemoryDbStats::default());
+        memdb.insert(b"test-key", b"test-value".to_vec()).unwrap();
+        memdb.insert(b"key", b"value".to_vec()).unwrap();
+        memdb.remove(b"key").unwrap();
+        memdb.remove(b"missing").unwrap();
+        assert_eq!(
+            memdb.stats(),
+            MemoryDbStats {
+                entries: 1,
+                bytes: 18,
+                deletes: 1,
+            }
+        );
+
+        let memdb = MemoryDB::new(false);
+        memdb.insert(b"key", b"value".to_vec()).unwrap();
+        memdb.remove(b"key").unwrap();
+        assert_eq!(memdb.stats().entries, 1);
+        assert_eq!(memdb.stats().deletes, 0);
+    }
+
+    #[test]
+    fn test_memdb_keys() {
+        let memdb = MemoryDB::new(true);
+        memdb.insert(b"test1", b"test".to_vec()).unwrap();
+        memdb.insert(b"test2", b"test".to_vec()).unwrap();
+
+        let mut keys = memdb.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"test1".to_vec(), b"test2".to_vec()]);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_rocksdb_reopen() {
+        use crate::trie::{EthTrie, Trie};
+
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let root = {
+            let db = Arc::new(RocksDB::open(&path).unwrap());
+            let mut trie = EthTrie::new(db);
+            for i in 0..100u32 {
+                trie.insert(
+                    format!("key-{}", i).as_bytes(),
+                    format!("value-{}", i).as_bytes(),
+                )
+                .unwrap();
+            }
+            trie.root_hash().unwrap()
+        };
+
+        let db = Arc::new(RocksDB::open(&path).unwrap());
+        assert_eq!(db.get(b"missing").unwrap(), None);
+        let trie = EthTrie::new(db).at_root(root);
+        for i in 0..100u32 {
+            let value = trie.get(format!("key-{}", i).as_bytes()).unwrap();
+            assert_eq!(value, Some(format!("value-{}", i).into_bytes()));
+        }
+        drop(trie);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cached_db() {
+        let cached = CachedDB::new(MemoryDB::new(true), 2);
+        cached.insert(b"a", b"1".to_vec()).unwrap();
+        cached.insert(b"b", b"2".to_vec()).unwrap();
+        cached.insert(b"c", b"3".to_vec()).unwrap();
+        assert!(cached.cache.lock().entries.is_empty());
+
+        assert_eq!(cached.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(cached.get(b"b").unwrap(), Some(b"2".to_vec()));
+        // Reading a makes b the least recently used entry
+        cached.get(b"a").unwrap();
+        assert_eq!(cached.get(b"c").unwrap(), Some(b"3".to_vec()));
+        {
+            let cache = cached.cache.lock();
+            assert_eq!(cache.entries.len(), 2);
+            assert!(cache.entries.contains_key(&b"a"[..]));
+            assert!(!cache.entries.contains_key(&b"b"[..]));
+        }
+
+        // Writes and removals are not hidden by the cache
+        cached.insert(b"a", b"4".to_vec()).unwrap();
+        assert_eq!(cached.get(b"a").unwrap(), Some(b"4".to_vec()));
+        cached.remove(b"c").unwrap();
+        assert_eq!(cached.get(b"c").unwrap(), None);
+        cached.remove_batch(&[b"a".to_vec()]).unwrap();
+        assert_eq!(cached.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_cached_db_latest_root() {
+        use crate::trie::{EthTrie, Trie};
+
+        let db = Arc::new(CachedDB::new(MemoryDB::new(true), 16));
+        let mut trie = EthTrie::new(db.clone());
+        trie.insert(b"a", b"1").unwrap();
+        let first = trie.root_hash().unwrap();
+        // Reading the latest root caches it
+        assert_eq!(
+            EthTrie::open_latest(db.clone())
+                .unwrap()
+                .root_hash()
+                .unwrap(),
+            first
+        );
+
+        // The next commit overwrites it through the wrapper
+        trie.insert(b"b", b"2").unwrap();
+        let second = trie.root_hash().unwrap();
+        assert_ne!(first, second);
+        let mut latest = EthTrie::open_latest(db.clone()).unwrap();
+        assert_eq!(latest.root_hash().unwrap(), second);
+        assert_eq!(latest.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_overlay_db() {
+        let overlay = OverlayDB::new(MemoryDB::new(true));
+        overlay.inner().insert(b"a", b"1".to_vec()).unwrap();
+        overlay.inner().insert(b"b", b"2".to_vec()).unwrap();
+
+        overlay.insert(b"a", b"3".to_vec()).unwrap();
+        overlay.insert(b"c", b"4".to_vec()).unwrap();
+        overlay.remove(b"b").unwrap();
+        assert_eq!(overlay.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(overlay.get(b"b").unwrap(), None);
+        assert_eq!(overlay.get(b"c").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(overlay.len().unwrap(), 2);
+        // The inner db is untouched
+        assert_eq!(overlay.inner().get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(overlay.inner().get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(overlay.inner().get(b"c").unwrap(), None);
+
+        overlay.discard_overlay();
+        assert_eq!(overlay.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(overlay.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(overlay.get(b"c").unwrap(), None);
+
+        overlay.remove(b"a").unwrap();
+        overlay.insert(b"c", b"4".to_vec()).unwrap();
+        overlay.commit_overlay().unwrap();
+        assert_eq!(overlay.inner().get(b"a").unwrap(), None);
+        assert_eq!(overlay.inner().get(b"c").unwrap(), Some(b"4".to_vec()));
+        let mut keys = overlay.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
 }
@@ -9,9 +9,28 @@ use crate::nibbles::Nibbles;
 #[derive(Debug, PartialEq)]
 pub enum TrieError {
     DB(String),
+    Io(String),
     Decoder(DecoderError),
-    InvalidData,
+    /// Data that could not be decoded or did not match its hash. `node_hash` is the hash
+    /// of the node being decoded, when known, and `len` the length of the data.
+    InvalidData {
+        node_hash: Option<H256>,
+        len: usize,
+    },
     InvalidProof,
+    EmptyValue,
+    StaleIterator,
+    Unsupported,
+    Cancelled,
+    NodeNotLoaded(H256),
+    PrefixConflict,
+    UncommittedChanges,
+    KeyNotFound,
+    /// A proof grew past the size limit it was built with. `size` is the number of bytes
+    /// of the nodes collected when the limit was crossed.
+    ProofTooLarge {
+        size: usize,
+    },
     MissingTrieNode {
         node_hash: H256,
         traversed: Option<Nibbles>,
@@ -26,9 +45,35 @@ impl fmt::Display for TrieError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let printable = match *self {
             TrieError::DB(ref err) => format!("trie error: {:?}", err),
+            TrieError::Io(ref err) => format!("trie error: {:?}", err),
             TrieError::Decoder(ref err) => format!("trie error: {:?}", err),
-            TrieError::InvalidData => "trie error: invalid data".to_owned(),
+            TrieError::InvalidData {
+                node_hash: Some(ref hash),
+                len,
+            } => format!(
+                "trie error: invalid data for node {:?} ({} bytes)",
+                hash, len
+            ),
+            TrieError::InvalidData {
+                node_hash: None,
+                len,
+            } => format!("trie error: invalid data ({} bytes)", len),
             TrieError::InvalidProof => "trie error: invalid proof".to_owned(),
+            TrieError::EmptyValue => "trie error: empty value".to_owned(),
+            TrieError::StaleIterator => "trie error: trie committed during iteration".to_owned(),
+            TrieError::Unsupported => "trie error: unsupported by the db".to_owned(),
+            TrieError::Cancelled => "trie error: operation cancelled".to_owned(),
+            TrieError::PrefixConflict => {
+                "trie error: key is a prefix of another key, or has one as a prefix".to_owned()
+            }
+            TrieError::UncommittedChanges => "trie error: trie has uncommitted changes".to_owned(),
+            TrieError::KeyNotFound => "trie error: key not found".to_owned(),
+            TrieError::ProofTooLarge { size } => {
+                format!("trie error: proof too large ({} bytes)", size)
+            }
+            TrieError::NodeNotLoaded(ref hash) => {
+                format!("trie error: node {:?} is not loaded", hash)
+            }
             TrieError::MissingTrieNode { .. } => "trie error: missing node".to_owned(),
         };
         write!(f, "{}", printable)
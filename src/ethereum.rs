@@ -0,0 +1,37 @@
+use ethereum_types::{Address, H256};
+use keccak_hash::keccak;
+
+/// Returns the key of a storage slot in a contract's storage trie, `keccak(slot)`.
+pub fn storage_slot_key(slot: H256) -> Vec<u8> {
+    keccak(slot.as_bytes()).as_bytes().to_vec()
+}
+
+/// Returns the key of an account in the state trie, `keccak(address)`.
+pub fn account_key(address: Address) -> Vec<u8> {
+    keccak(address.as_bytes()).as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_slot_key() {
+        assert_eq!(
+            hex::encode(storage_slot_key(H256::zero())),
+            "290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563"
+        );
+        assert_eq!(
+            hex::encode(storage_slot_key(H256::from_low_u64_be(1))),
+            "b10e2d527612073b26eecdfd717e6a320cf44b4afac2b0732d9fcbe2b7fa0cf6"
+        );
+    }
+
+    #[test]
+    fn test_account_key() {
+        assert_eq!(
+            hex::encode(account_key(Address::zero())),
+            "5380c7b7ae81a58eb98d9c78de4a1fd7fd9535fc953ed2be602daaa41767312a"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use keccak_hash::{keccak, H256};
+
+use crate::trie::EMPTY_TRIE_ROOT;
+
+/// The hash function a trie uses to refer to its nodes and to compute its root hash.
+///
+/// Nodes are referred to by 32-byte hashes, so the output is always an `H256`. A trie uses
+/// `KeccakHasher` unless given another hasher with `EthTrie::with_hasher`.
+pub trait Hasher {
+    fn hash(data: &[u8]) -> H256;
+
+    /// The root hash of a trie with no entries, which is the hash of the RLP empty string.
+    /// Hashers can override it with a precomputed constant.
+    fn empty_root() -> H256 {
+        Self::hash(&rlp::NULL_RLP)
+    }
+}
+
+/// Hashes with keccak-256, as Ethereum does.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeccakHasher;
+
+impl Hasher for KeccakHasher {
+    fn hash(data: &[u8]) -> H256 {
+        keccak(data)
+    }
+
+    fn empty_root() -> H256 {
+        EMPTY_TRIE_ROOT
+    }
+}
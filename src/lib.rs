@@ -2,13 +2,41 @@ mod nibbles;
 mod node;
 mod tests;
 
+mod async_trie;
 mod db;
 mod errors;
+#[cfg(feature = "ethereum")]
+mod ethereum;
+mod hasher;
+mod proof;
+mod root;
 mod trie;
+mod typed;
 
-pub use db::{MemoryDB, DB};
+pub use async_trie::AsyncEthTrie;
+#[cfg(feature = "rocksdb")]
+pub use db::RocksDB;
+pub use db::{AsyncDB, CachedDB, DBFuture, MemoryDB, MemoryDbStats, OverlayDB, SyncDB, DB};
 pub use errors::{MemDBError, TrieError};
-pub use trie::{EthTrie, Trie};
+#[cfg(feature = "ethereum")]
+pub use ethereum::{account_key, storage_slot_key};
+pub use hasher::{Hasher, KeccakHasher};
+pub use nibbles::{from_compact_bytes, to_compact, Nibbles};
+pub use node::{
+    encode_node_raw, node_from_fields, node_hash, node_to_fields, NodeFields, NodeKind, NodeRef,
+};
+pub use proof::{
+    compact_proof, proof_path_indices, verify_exclusion, verify_minimal, verify_proof_any,
+    verify_proof_compact, verify_proof_traced, verify_proof_value, verify_proofs, MinimalProof,
+    MinimalStep, Proof, ProofNode, BRANCH_VALUE, EXTENSION_HOP,
+};
+pub use root::{batch_roots, ordered_trie_root, root_of, root_with_keyer};
+pub use trie::{
+    is_empty_root, verify_proof, CancellationToken, Checkpoint, CommitReport, EthTrie, KeySetDiff,
+    ReadOnlyTrie, SealedTrie, Trie, TrieIntoIter, TrieResult, TrieStats, ValueGuard,
+    EMPTY_TRIE_ROOT,
+};
+pub use typed::TypedTrie;
 
 #[doc = include_str!("../README.md")]
 #[cfg(doctest)]
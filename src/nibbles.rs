@@ -1,5 +1,16 @@
 use std::cmp::min;
 
+/// A path through the trie, as a sequence of nibbles (half bytes). Each byte of a key
+/// becomes two nibbles, high half first, and the path of a full key ends with the leaf
+/// terminator 16.
+///
+/// ```
+/// use eth_trie::Nibbles;
+///
+/// let path = Nibbles::from_raw(&[0x12, 0xab], true);
+/// assert_eq!(path.get_data(), &[1, 2, 0xa, 0xb, 16]);
+/// assert!(path.is_leaf());
+/// ```
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Nibbles {
     hex_data: Vec<u8>,
@@ -12,6 +23,7 @@ impl Nibbles {
         }
     }
 
+    /// Splits the bytes of a key into nibbles, appending the leaf terminator if `is_leaf`.
     pub fn from_raw(raw: &[u8], is_leaf: bool) -> Self {
         let mut hex_data = vec![];
         for item in raw.iter() {
@@ -24,9 +36,27 @@ impl Nibbles {
         Nibbles { hex_data }
     }
 
+    /// Decodes a path in the compact encoding of `encode_compact`, with the leaf terminator
+    /// if the flag marks a leaf path. Panics if compact is
+    /// empty or its flag nibble is not 0 to 3; use `try_from_compact` for untrusted input.
     pub fn from_compact(compact: &[u8]) -> Self {
+        Self::try_from_compact(compact).expect("invalid data")
+    }
+
+    /// Like `from_compact`, returning None instead of panicking if compact is empty or its
+    /// flag nibble is not 0 to 3.
+    ///
+    /// ```
+    /// use eth_trie::Nibbles;
+    ///
+    /// let path = Nibbles::try_from_compact(&[0x31, 0x23]).unwrap();
+    /// assert_eq!(path.get_data(), &[1, 2, 3, 16]);
+    /// assert_eq!(Nibbles::try_from_compact(&[]), None);
+    /// assert_eq!(Nibbles::try_from_compact(&[0x40]), None);
+    /// ```
+    pub fn try_from_compact(compact: &[u8]) -> Option<Self> {
         let mut hex = vec![];
-        let flag = compact[0];
+        let flag = *compact.first()?;
 
         let mut is_leaf = false;
         match flag >> 4 {
@@ -37,7 +67,7 @@ impl Nibbles {
                 is_leaf = true;
                 hex.push(flag % 16);
             }
-            _ => panic!("invalid data"),
+            _ => return None,
         }
 
         for item in &compact[1..] {
@@ -48,13 +78,35 @@ impl Nibbles {
             hex.push(16);
         }
 
-        Nibbles { hex_data: hex }
+        Some(Nibbles { hex_data: hex })
     }
 
+    /// Returns true if the path ends with the leaf terminator. An empty path is not a leaf.
     pub fn is_leaf(&self) -> bool {
-        self.hex_data[self.hex_data.len() - 1] == 16
+        self.hex_data.last() == Some(&16)
     }
 
+    /// Encodes the path in the hex-prefix (compact) encoding used for the paths of leaf and
+    /// extension nodes. The high nibble of the first byte is a flag: bit 1 is set for a leaf
+    /// path, which ends with the terminator, and bit 0 for an odd number of nibbles. An odd
+    /// path stores its first nibble in the low half of the first byte, and an even path
+    /// leaves it 0. The remaining nibbles are packed two per byte, without the terminator.
+    ///
+    /// ```text
+    /// node type    path length    |    prefix    hexchar
+    /// --------------------------------------------------
+    /// extension    even           |    0000      0x0
+    /// extension    odd            |    0001      0x1
+    /// leaf         even           |    0010      0x2
+    /// leaf         odd            |    0011      0x3
+    /// ```
+    ///
+    /// ```
+    /// use eth_trie::Nibbles;
+    ///
+    /// assert_eq!(Nibbles::from_hex(&[1, 2, 3, 16]).encode_compact(), vec![0x31, 0x23]);
+    /// assert_eq!(Nibbles::from_hex(&[1, 2]).encode_compact(), vec![0x00, 0x12]);
+    /// ```
     pub fn encode_compact(&self) -> Vec<u8> {
         let mut compact = vec![];
         let is_leaf = self.is_leaf();
@@ -63,12 +115,6 @@ impl Nibbles {
         } else {
             &self.hex_data[0..]
         };
-        // node type    path length    |    prefix    hexchar
-        // --------------------------------------------------
-        // extension    even           |    0000      0x0
-        // extension    odd            |    0001      0x1
-        // leaf         even           |    0010      0x2
-        // leaf         odd            |    0011      0x3
         let v = if hex.len() % 2 == 1 {
             let v = 0x10 + hex[0];
             hex = &hex[1..];
@@ -109,10 +155,28 @@ impl Nibbles {
         self.len() == 0
     }
 
+    /// Returns the nibble at index i, which is 16 for the leaf terminator. Panics if i is
+    /// out of bounds.
     pub fn at(&self, i: usize) -> usize {
         self.hex_data[i] as usize
     }
 
+    /// Like `at`, returning None if i is out of bounds.
+    pub fn try_at(&self, i: usize) -> Option<usize> {
+        self.hex_data.get(i).map(|&n| n as usize)
+    }
+
+    /// Returns the number of leading nibbles that this path shares with `other_partial`.
+    ///
+    /// ```
+    /// use eth_trie::Nibbles;
+    ///
+    /// // 0x12 0x34 and 0x12 0x56 share the nibbles 1, 2
+    /// let a = Nibbles::from_raw(&[0x12, 0x34], true);
+    /// let b = Nibbles::from_raw(&[0x12, 0x56], true);
+    /// assert_eq!(a.common_prefix(&b), 2);
+    /// assert_eq!(a.common_prefix(&a), 5);
+    /// ```
     pub fn common_prefix(&self, other_partial: &Nibbles) -> usize {
         let s = min(self.len(), other_partial.len());
         let mut i = 0usize;
@@ -125,10 +189,22 @@ impl Nibbles {
         i
     }
 
+    /// Returns the nibbles from `index` to the end, as the remaining path below a node that
+    /// consumed the first `index` nibbles.
     pub fn offset(&self, index: usize) -> Nibbles {
         self.slice(index, self.hex_data.len())
     }
 
+    /// Returns the nibbles from `start` up to, but not including, `end`.
+    ///
+    /// ```
+    /// use eth_trie::Nibbles;
+    ///
+    /// let path = Nibbles::from_raw(&[0x12, 0xab], true);
+    /// assert_eq!(path.slice(1, 3).get_data(), &[2, 0xa]);
+    /// assert_eq!(path.offset(3).get_data(), &[0xb, 16]);
+    /// assert_eq!(path.at(2), 0xa);
+    /// ```
     pub fn slice(&self, start: usize, end: usize) -> Nibbles {
         Nibbles::from_hex(&self.hex_data[start..end])
     }
@@ -159,6 +235,23 @@ impl Nibbles {
     }
 }
 
+/// Encodes a byte key as the compact path of a leaf, as stored in the leaf node that holds
+/// the key. See `Nibbles::encode_compact`.
+///
+/// ```
+/// assert_eq!(eth_trie::to_compact(&[0x12, 0x34]), vec![0x20, 0x12, 0x34]);
+/// ```
+pub fn to_compact(key: &[u8]) -> Vec<u8> {
+    Nibbles::from_raw(key, true).encode_compact()
+}
+
+/// Decodes a compact path back to the bytes of its key, the inverse of `to_compact`. The
+/// path may be a leaf or an extension path; a trailing odd nibble is dropped, as it does
+/// not make a whole byte. Panics like `Nibbles::from_compact` on invalid input.
+pub fn from_compact_bytes(compact: &[u8]) -> Vec<u8> {
+    Nibbles::from_compact(compact).encode_raw().0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,4 +265,49 @@ mod tests {
         assert!(is_leaf);
         assert_eq!(raw, b"key1");
     }
+
+    #[test]
+    fn test_compact_round_trip() {
+        // Paths with an even and an odd number of nibbles, as leaves and extensions
+        for hex in [&[][..], &[1], &[1, 2], &[0xa, 0xb, 0xc], &[0, 0xf, 0, 0xf]] {
+            for &is_leaf in &[false, true] {
+                let mut data = hex.to_vec();
+                if is_leaf {
+                    data.push(16);
+                }
+                let path = Nibbles::from_hex(&data);
+                let compact = path.encode_compact();
+                assert_eq!(compact.len(), hex.len() / 2 + 1);
+                assert_eq!(
+                    compact[0] >> 4,
+                    (is_leaf as u8) << 1 | (hex.len() % 2) as u8
+                );
+                assert_eq!(Nibbles::from_compact(&compact), path);
+            }
+        }
+
+        // Byte keys of even and odd length
+        for key in [&b""[..], b"k", b"ke", b"key", b"key1"] {
+            let compact = to_compact(key);
+            assert_eq!(compact[0], 0x20);
+            assert_eq!(&compact[1..], key);
+            assert_eq!(from_compact_bytes(&compact), key);
+        }
+        assert_eq!(from_compact_bytes(&[0x00, 0x12]), vec![0x12]);
+    }
+
+    #[test]
+    fn test_malformed_compact() {
+        // An even extension path with no nibbles
+        let n = Nibbles::try_from_compact(&[0x00]).unwrap();
+        assert!(n.is_empty());
+        assert!(!n.is_leaf());
+        assert_eq!(n.try_at(0), None);
+
+        assert_eq!(Nibbles::try_from_compact(&[]), None);
+        assert_eq!(Nibbles::try_from_compact(&[0x4f, 0x12]), None);
+        let n = Nibbles::from_raw(b"k", false);
+        assert_eq!(n.try_at(1), Some(0xb));
+        assert_eq!(n.try_at(2), None);
+    }
 }
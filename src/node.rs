@@ -1,8 +1,14 @@
+use std::mem::size_of;
 use std::sync::{Arc, RwLock};
 
 use keccak_hash::H256;
+use rlp::{Prototype, Rlp, RlpStream};
 
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
 use crate::nibbles::Nibbles;
+use crate::trie::{TrieResult, HASHED_LENGTH};
 
 #[derive(Debug, Clone)]
 pub enum Node {
@@ -33,6 +39,64 @@ impl Node {
         let hash_node = Arc::new(HashNode { hash });
         Node::Hash(hash_node)
     }
+
+    // Copies the branch and extension nodes reachable from this node, so that the copy can
+    // be modified without affecting the original. Leaf and hash nodes are never modified in
+    // place, so they are shared.
+    pub(crate) fn deep_clone(&self) -> Node {
+        match self {
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+                Node::from_extension(borrow_ext.prefix.clone(), borrow_ext.node.deep_clone())
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                let mut children = empty_children();
+                for (child, original) in children.iter_mut().zip(borrow_branch.children.iter()) {
+                    *child = original.deep_clone();
+                }
+                Node::from_branch(children, borrow_branch.value.clone())
+            }
+            _ => self.clone(),
+        }
+    }
+
+    // Collects the hashes this node and its loaded descendants refer to, without loading
+    // anything. For a freshly decoded node these are the children stored separately in the db.
+    pub(crate) fn child_hashes(&self, hashes: &mut Vec<H256>) {
+        match self {
+            Node::Empty | Node::Leaf(_) => {}
+            Node::Extension(ext) => ext.read().unwrap().node.child_hashes(hashes),
+            Node::Branch(branch) => {
+                for child in branch.read().unwrap().children.iter() {
+                    child.child_hashes(hashes);
+                }
+            }
+            Node::Hash(hash_node) => hashes.push(hash_node.hash),
+        }
+    }
+
+    /// Approximate number of bytes held by this node and all of its loaded descendants.
+    pub fn memory_size(&self) -> usize {
+        let inner = match self {
+            Node::Empty => 0,
+            Node::Leaf(leaf) => size_of::<LeafNode>() + leaf.key.len() + leaf.value.len(),
+            Node::Extension(ext) => {
+                let ext = ext.read().unwrap();
+                size_of::<ExtensionNode>() + ext.prefix.len() + ext.node.memory_size()
+            }
+            Node::Branch(branch) => {
+                let branch = branch.read().unwrap();
+                let children: usize = branch.children.iter().map(|n| n.memory_size()).sum();
+                // The children array is part of the BranchNode itself
+                size_of::<BranchNode>() - size_of::<[Node; 16]>()
+                    + children
+                    + branch.value.as_ref().map_or(0, |v| v.len())
+            }
+            Node::Hash(_) => size_of::<HashNode>(),
+        };
+        size_of::<Node>() + inner
+    }
 }
 
 #[derive(Debug)]
@@ -93,3 +157,484 @@ pub fn empty_children() -> [Node; 16] {
         Node::Empty,
     ]
 }
+
+/// Returns the RLP encoding of a node, as it is stored in the db.
+///
+/// Children are referenced by their hash, or embedded if their encoding is shorter than
+/// 32 bytes. A hash node has no encoding of its own and is encoded as that reference.
+pub fn encode_node_raw(node: &NodeRef) -> Vec<u8> {
+    encode_node_raw_with::<KeccakHasher>(&node.node)
+}
+
+// Like `encode_node_raw`, with child nodes referenced by their hash under H.
+pub(crate) fn encode_node_raw_with<H: Hasher>(node: &Node) -> Vec<u8> {
+    match node {
+        Node::Empty => rlp::NULL_RLP.to_vec(),
+        Node::Leaf(leaf) => {
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&leaf.key.encode_compact());
+            stream.append(&leaf.value);
+            stream.out().to_vec()
+        }
+        Node::Branch(branch) => {
+            let borrow_branch = branch.read().unwrap();
+
+            let mut stream = RlpStream::new_list(17);
+            for child in borrow_branch.children.iter() {
+                append_child::<H>(&mut stream, child);
+            }
+            match &borrow_branch.value {
+                Some(v) => stream.append(v),
+                None => stream.append_empty_data(),
+            };
+            stream.out().to_vec()
+        }
+        Node::Extension(ext) => {
+            let borrow_ext = ext.read().unwrap();
+
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&borrow_ext.prefix.encode_compact());
+            append_child::<H>(&mut stream, &borrow_ext.node);
+            stream.out().to_vec()
+        }
+        Node::Hash(hash_node) => rlp::encode(&hash_node.hash.as_bytes()).to_vec(),
+    }
+}
+
+/// Returns the hash of a node, `keccak(encode_node_raw(node))`, or the hash a hash node
+/// refers to. For a root node this is the trie's root hash, even when the encoding is
+/// short enough to be embedded in a parent.
+pub fn node_hash(node: &NodeRef) -> H256 {
+    node_hash_with::<KeccakHasher>(&node.node)
+}
+
+// Like `node_hash`, hashing with H.
+pub(crate) fn node_hash_with<H: Hasher>(node: &Node) -> H256 {
+    match node {
+        Node::Hash(hash_node) => hash_node.hash,
+        _ => H::hash(&encode_node_raw_with::<H>(node)),
+    }
+}
+
+fn append_child<H: Hasher>(stream: &mut RlpStream, child: &Node) {
+    let reference = child_reference::<H>(child);
+    if reference.len() == HASHED_LENGTH {
+        stream.append(&reference);
+    } else {
+        stream.append_raw(&reference, 1);
+    }
+}
+
+// The hash of the child node, or its encoding if it is embedded in the parent.
+pub(crate) fn child_reference<H: Hasher>(child: &Node) -> Vec<u8> {
+    if let Node::Hash(hash_node) = child {
+        return hash_node.hash.as_bytes().to_vec();
+    }
+    let data = encode_node_raw_with::<H>(child);
+    if data.len() < HASHED_LENGTH {
+        data
+    } else {
+        H::hash(&data).as_bytes().to_vec()
+    }
+}
+
+pub fn decode_node(data: &[u8]) -> TrieResult<Node> {
+    let r = Rlp::new(data);
+
+    match r.prototype()? {
+        Prototype::Data(0) => Ok(Node::Empty),
+        Prototype::List(2) => {
+            let key =
+                Nibbles::try_from_compact(r.at(0)?.data()?).ok_or(TrieError::InvalidData {
+                    node_hash: None,
+                    len: data.len(),
+                })?;
+
+            if key.is_leaf() {
+                Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
+            } else {
+                let n = decode_node(r.at(1)?.as_raw())?;
+
+                Ok(Node::from_extension(key, n))
+            }
+        }
+        Prototype::List(17) => {
+            let mut nodes = empty_children();
+            #[allow(clippy::needless_range_loop)]
+            for i in 0..nodes.len() {
+                let rlp_data = r.at(i)?;
+                let n = decode_node(rlp_data.as_raw())?;
+                nodes[i] = n;
+            }
+
+            // The last element is a value node.
+            let value_rlp = r.at(16)?;
+            let value = if value_rlp.is_empty() {
+                None
+            } else {
+                Some(value_rlp.data()?.to_vec())
+            };
+
+            Ok(Node::from_branch(nodes, value))
+        }
+        _ => {
+            if r.is_data() && r.size() == HASHED_LENGTH {
+                let hash = H256::from_slice(r.data()?);
+                Ok(Node::from_hash(hash))
+            } else {
+                Err(TrieError::InvalidData {
+                    node_hash: None,
+                    len: data.len(),
+                })
+            }
+        }
+    }
+}
+
+// Decodes a node loaded from the db under the given hash. Decoding errors are reported as
+// `TrieError::InvalidData` carrying the hash and length of the node.
+pub(crate) fn decode_node_with_hash(node_hash: H256, data: &[u8]) -> TrieResult<Node> {
+    decode_node(data).map_err(|e| match e {
+        TrieError::InvalidData { .. } | TrieError::Decoder(_) => TrieError::InvalidData {
+            node_hash: Some(node_hash),
+            len: data.len(),
+        },
+        e => e,
+    })
+}
+
+/// The type of a node, as recorded in `NodeFields` and returned by `NodeRef::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Empty,
+    Leaf,
+    Extension,
+    Branch,
+    Hash,
+}
+
+/// A node split into its structural parts, for consumers such as ZK witness generators
+/// that cannot work with the RLP encoding directly.
+///
+/// A child reference is empty for a missing child, the 32-byte hash of the child, or the
+/// RLP encoding of the child when it is shorter than 32 bytes and embedded in its parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeFields {
+    pub kind: NodeKind,
+    /// Key nibbles of a leaf or the prefix nibbles of an extension, without terminator.
+    pub prefix: Vec<u8>,
+    /// The 16 child references of a branch. An extension stores its child in slot 0, and
+    /// a hash node stores its hash in slot 0.
+    pub children: [Vec<u8>; 16],
+    /// The value of a leaf or branch, empty if there is none.
+    pub value: Vec<u8>,
+}
+
+/// Splits a node into its `NodeFields`.
+pub fn node_to_fields(node: &NodeRef) -> NodeFields {
+    let mut fields = NodeFields {
+        kind: NodeKind::Empty,
+        prefix: vec![],
+        children: Default::default(),
+        value: vec![],
+    };
+    match &node.node {
+        Node::Empty => {}
+        Node::Leaf(leaf) => {
+            fields.kind = NodeKind::Leaf;
+            fields.prefix = leaf.key.slice(0, leaf.key.len() - 1).get_data().to_vec();
+            fields.value = leaf.value.clone();
+        }
+        Node::Extension(ext) => {
+            let borrow_ext = ext.read().unwrap();
+            fields.kind = NodeKind::Extension;
+            fields.prefix = borrow_ext.prefix.get_data().to_vec();
+            fields.children[0] = child_reference::<KeccakHasher>(&borrow_ext.node);
+        }
+        Node::Branch(branch) => {
+            let borrow_branch = branch.read().unwrap();
+            fields.kind = NodeKind::Branch;
+            for (i, child) in borrow_branch.children.iter().enumerate() {
+                if let Node::Empty = child {
+                    continue;
+                }
+                fields.children[i] = child_reference::<KeccakHasher>(child);
+            }
+            fields.value = borrow_branch.value.clone().unwrap_or_default();
+        }
+        Node::Hash(hash_node) => {
+            fields.kind = NodeKind::Hash;
+            fields.children[0] = hash_node.hash.as_bytes().to_vec();
+        }
+    }
+    fields
+}
+
+/// Rebuilds a node from its `NodeFields`. Children that were referenced by hash come back
+/// as hash nodes. Returns `TrieError::InvalidData` if the fields are inconsistent.
+pub fn node_from_fields(fields: &NodeFields) -> TrieResult<NodeRef> {
+    node_from_fields_at(fields).map(NodeRef::new)
+}
+
+fn node_from_fields_at(fields: &NodeFields) -> TrieResult<Node> {
+    let invalid = |len: usize| TrieError::InvalidData {
+        node_hash: None,
+        len,
+    };
+    if fields.prefix.iter().any(|&nibble| nibble > 15) {
+        return Err(invalid(fields.prefix.len()));
+    }
+    match fields.kind {
+        NodeKind::Empty => Ok(Node::Empty),
+        NodeKind::Leaf => {
+            if let Some(child) = fields.children.iter().find(|c| !c.is_empty()) {
+                return Err(invalid(child.len()));
+            }
+            let mut key = Nibbles::from_hex(&fields.prefix);
+            key.push(16);
+            Ok(Node::from_leaf(key, fields.value.clone()))
+        }
+        NodeKind::Extension => {
+            // An extension leads somewhere through at least one nibble, and has one child
+            if fields.prefix.is_empty() || fields.children[0].is_empty() {
+                return Err(invalid(fields.children[0].len()));
+            }
+            if let Some(child) = fields.children[1..].iter().find(|c| !c.is_empty()) {
+                return Err(invalid(child.len()));
+            }
+            let prefix = Nibbles::from_hex(&fields.prefix);
+            Ok(Node::from_extension(
+                prefix,
+                child_from_reference(&fields.children[0])?,
+            ))
+        }
+        NodeKind::Branch => {
+            let mut children = empty_children();
+            for (child, reference) in children.iter_mut().zip(fields.children.iter()) {
+                *child = child_from_reference(reference)?;
+            }
+            let value = if fields.value.is_empty() {
+                None
+            } else {
+                Some(fields.value.clone())
+            };
+            Ok(Node::from_branch(children, value))
+        }
+        NodeKind::Hash => {
+            if fields.children[0].len() != HASHED_LENGTH {
+                return Err(invalid(fields.children[0].len()));
+            }
+            Ok(Node::from_hash(H256::from_slice(&fields.children[0])))
+        }
+    }
+}
+
+fn child_from_reference(reference: &[u8]) -> TrieResult<Node> {
+    match reference.len() {
+        0 => Ok(Node::Empty),
+        HASHED_LENGTH => Ok(Node::from_hash(H256::from_slice(reference))),
+        _ => decode_node(reference),
+    }
+}
+
+/// A read-only handle to a node of a trie, for walking the trie node by node. The walk
+/// starts at `EthTrie::root_node`, and nodes stored by hash are loaded with `resolve`.
+///
+/// The handle shares the node with the trie, so it sees the changes the trie makes in
+/// place until the next commit.
+///
+/// The node itself stays internal to the crate: its encoding, hash and `NodeFields` are
+/// available through `encode_node_raw`, `node_hash` and `node_to_fields`.
+#[derive(Debug, Clone)]
+pub struct NodeRef {
+    node: Node,
+}
+
+impl NodeRef {
+    pub(crate) fn new(node: Node) -> Self {
+        NodeRef { node }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        match self.node {
+            Node::Empty => NodeKind::Empty,
+            Node::Leaf(_) => NodeKind::Leaf,
+            Node::Extension(_) => NodeKind::Extension,
+            Node::Branch(_) => NodeKind::Branch,
+            Node::Hash(_) => NodeKind::Hash,
+        }
+    }
+
+    /// Returns the non-empty children of a branch node, with the nibble leading to each
+    /// one. Empty for any other node.
+    pub fn children(&self) -> Vec<(u8, NodeRef)> {
+        match &self.node {
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                borrow_branch
+                    .children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child)| !matches!(child, Node::Empty))
+                    .map(|(i, child)| (i as u8, NodeRef::new(child.clone())))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns the child of an extension node.
+    pub fn child(&self) -> Option<NodeRef> {
+        match &self.node {
+            Node::Extension(ext) => Some(NodeRef::new(ext.read().unwrap().node.clone())),
+            _ => None,
+        }
+    }
+
+    /// Returns the prefix of an extension node, or the rest of the key of a leaf node
+    /// without the leaf terminator.
+    pub fn prefix(&self) -> Option<Nibbles> {
+        match &self.node {
+            Node::Leaf(leaf) => Some(leaf.key.slice(0, leaf.key.len() - 1)),
+            Node::Extension(ext) => Some(ext.read().unwrap().prefix.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a leaf node, or of a branch node that holds one.
+    pub fn value(&self) -> Option<Vec<u8>> {
+        match &self.node {
+            Node::Leaf(leaf) => Some(leaf.value.clone()),
+            Node::Branch(branch) => branch.read().unwrap().value.clone(),
+            _ => None,
+        }
+    }
+
+    /// Returns the hash a hash node refers to.
+    pub fn hash(&self) -> Option<H256> {
+        match &self.node {
+            Node::Hash(hash_node) => Some(hash_node.hash),
+            _ => None,
+        }
+    }
+
+    /// Loads the node a hash node refers to from db. Any other node is returned as it is.
+    /// Returns `TrieError::MissingTrieNode` if db does not hold the node.
+    pub fn resolve<D: DB>(&self, db: &D) -> TrieResult<NodeRef> {
+        let node_hash = match &self.node {
+            Node::Hash(hash_node) => hash_node.hash,
+            _ => return Ok(self.clone()),
+        };
+        let data = db
+            .get(node_hash.as_bytes())
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or(TrieError::MissingTrieNode {
+                node_hash,
+                traversed: None,
+                root_hash: None,
+                err_key: None,
+            })?;
+        Ok(NodeRef::new(decode_node_with_hash(node_hash, &data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keccak_hash::keccak;
+
+    use super::*;
+
+    fn assert_round_trip(node: &Node, kind: NodeKind) {
+        let node = &NodeRef::new(node.clone());
+        let fields = node_to_fields(node);
+        assert_eq!(fields.kind, kind);
+        let rebuilt = node_from_fields(&fields).unwrap();
+        assert_eq!(node_to_fields(&rebuilt), fields);
+        assert_eq!(encode_node_raw(&rebuilt), encode_node_raw(node));
+    }
+
+    #[test]
+    fn test_node_fields_round_trip() {
+        let short_leaf = Node::from_leaf(Nibbles::from_raw(b"k", true), b"v".to_vec());
+        let long_leaf = Node::from_leaf(
+            Nibbles::from_raw(b"key", true),
+            b"really-long-value-to-prevent-inlining".to_vec(),
+        );
+
+        assert_round_trip(&Node::Empty, NodeKind::Empty);
+        assert_round_trip(&short_leaf, NodeKind::Leaf);
+        assert_round_trip(&Node::from_hash(keccak(b"node")), NodeKind::Hash);
+
+        let ext = Node::from_extension(Nibbles::from_hex(&[1, 2, 3]), short_leaf.clone());
+        assert_round_trip(&ext, NodeKind::Extension);
+
+        let mut children = empty_children();
+        children[3] = short_leaf;
+        children[9] = long_leaf;
+        let branch = Node::from_branch(children, Some(b"branch-value".to_vec()));
+        assert_round_trip(&branch, NodeKind::Branch);
+
+        let fields = node_to_fields(&NodeRef::new(branch));
+        assert_eq!(fields.value, b"branch-value".to_vec());
+        assert!(fields.children[0].is_empty());
+        assert_eq!(fields.children[9].len(), 32);
+    }
+
+    #[test]
+    fn test_node_from_fields_invalid() {
+        let mut fields = node_to_fields(&NodeRef::new(Node::from_hash(keccak(b"node"))));
+        fields.children[0].pop();
+        assert_eq!(
+            node_from_fields(&fields).unwrap_err(),
+            TrieError::InvalidData {
+                node_hash: None,
+                len: 31
+            }
+        );
+    }
+
+    #[test]
+    fn test_node_from_fields_inconsistent() {
+        let leaf = Node::from_leaf(Nibbles::from_raw(b"k", true), b"v".to_vec());
+        let ext = Node::from_extension(Nibbles::from_hex(&[1, 2]), Node::from_hash(keccak(b"n")));
+        let is_invalid = |fields: &NodeFields| {
+            matches!(
+                node_from_fields(fields),
+                Err(TrieError::InvalidData {
+                    node_hash: None,
+                    ..
+                })
+            )
+        };
+
+        // Nibbles above 15
+        let mut fields = node_to_fields(&NodeRef::new(ext.clone()));
+        fields.prefix = vec![200, 17];
+        assert!(is_invalid(&fields));
+        let mut fields = node_to_fields(&NodeRef::new(leaf.clone()));
+        fields.prefix.push(16);
+        assert!(is_invalid(&fields));
+
+        // An extension without a prefix or without a child
+        let mut fields = node_to_fields(&NodeRef::new(ext.clone()));
+        fields.prefix.clear();
+        assert!(is_invalid(&fields));
+        let mut fields = node_to_fields(&NodeRef::new(ext.clone()));
+        fields.children[0].clear();
+        assert!(is_invalid(&fields));
+        let mut fields = node_to_fields(&NodeRef::new(ext.clone()));
+        fields.prefix.clear();
+        fields.children[0].clear();
+        assert!(is_invalid(&fields));
+
+        // Children in the slots an extension does not use
+        let mut fields = node_to_fields(&NodeRef::new(ext));
+        fields.children[5] = keccak(b"other").as_bytes().to_vec();
+        assert!(is_invalid(&fields));
+
+        // Children on a leaf
+        let mut fields = node_to_fields(&NodeRef::new(leaf));
+        fields.children[0] = keccak(b"other").as_bytes().to_vec();
+        assert!(is_invalid(&fields));
+    }
+}
@@ -0,0 +1,586 @@
+use std::sync::Arc;
+
+use keccak_hash::{keccak, H256};
+use rlp::{Prototype, Rlp, RlpStream};
+
+use crate::db::{MemoryDB, DB};
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::nibbles::Nibbles;
+use crate::trie::{verify_proof, EthTrie, Trie, TrieResult, EMPTY_TRIE_ROOT, HASHED_LENGTH};
+
+/// A merkle proof as returned by `get_proof`: the encoded nodes on the path to a key, from
+/// the root down. Converts to and from the plain `Vec<Vec<u8>>` used by the trie methods.
+///
+/// With the `serde` feature, a proof serializes as a list of `0x`-prefixed hex strings, one
+/// per node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Proof(pub Vec<Vec<u8>>);
+
+impl From<Vec<Vec<u8>>> for Proof {
+    fn from(nodes: Vec<Vec<u8>>) -> Self {
+        Proof(nodes)
+    }
+}
+
+impl From<Proof> for Vec<Vec<u8>> {
+    fn from(proof: Proof) -> Self {
+        proof.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Proof {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for node in self.0.iter() {
+            let mut hex = String::with_capacity(2 + node.len() * 2);
+            hex.push_str("0x");
+            for byte in node {
+                hex.push_str(&format!("{:02x}", byte));
+            }
+            seq.serialize_element(&hex)?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Proof {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let strings: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
+        let mut nodes = Vec::with_capacity(strings.len());
+        for (i, hex) in strings.iter().enumerate() {
+            let digits = hex.strip_prefix("0x").unwrap_or(hex);
+            if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(D::Error::custom(format!(
+                    "proof node {} is not valid hex: {:?}",
+                    i, hex
+                )));
+            }
+            let node = (0..digits.len())
+                .step_by(2)
+                .map(|j| u8::from_str_radix(&digits[j..j + 2], 16).unwrap())
+                .collect();
+            nodes.push(node);
+        }
+        Ok(Proof(nodes))
+    }
+}
+
+/// A proof node decoded into its parts, as returned by `EthTrie::get_proof_structured`.
+///
+/// A child reference is empty for a missing child, the 32-byte hash of the child, or the
+/// RLP encoding of the child when it is shorter than 32 bytes and embedded in its parent.
+/// Paths are nibbles, without the leaf terminator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofNode {
+    Branch {
+        children: Box<[Vec<u8>; 16]>,
+        value: Option<Vec<u8>>,
+    },
+    Extension {
+        prefix: Nibbles,
+        child: Vec<u8>,
+    },
+    Leaf {
+        key: Nibbles,
+        value: Vec<u8>,
+    },
+}
+
+impl ProofNode {
+    /// Decodes a node of a proof as returned by `get_proof`. Returns
+    /// `TrieError::InvalidData` if data is not a branch, extension or leaf node.
+    pub fn decode(data: &[u8]) -> TrieResult<Self> {
+        let r = Rlp::new(data);
+        let invalid = || TrieError::InvalidData {
+            node_hash: None,
+            len: data.len(),
+        };
+        match r.prototype()? {
+            Prototype::List(2) => {
+                let path = Nibbles::try_from_compact(r.at(0)?.data()?).ok_or_else(invalid)?;
+                if path.is_leaf() {
+                    Ok(ProofNode::Leaf {
+                        key: path.slice(0, path.len() - 1),
+                        value: r.at(1)?.data()?.to_vec(),
+                    })
+                } else {
+                    Ok(ProofNode::Extension {
+                        prefix: path,
+                        child: child_reference(&r.at(1)?)?,
+                    })
+                }
+            }
+            Prototype::List(17) => {
+                let mut children: Box<[Vec<u8>; 16]> = Default::default();
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = child_reference(&r.at(i)?)?;
+                }
+                let value = r.at(16)?;
+                let value = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.data()?.to_vec())
+                };
+                Ok(ProofNode::Branch { children, value })
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Returns the RLP encoding of the node, the inverse of `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ProofNode::Branch { children, value } => {
+                let mut stream = RlpStream::new_list(17);
+                for child in children.iter() {
+                    append_reference(&mut stream, child);
+                }
+                match value {
+                    Some(v) => stream.append(v),
+                    None => stream.append_empty_data(),
+                };
+                stream.out().to_vec()
+            }
+            ProofNode::Extension { prefix, child } => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&prefix.encode_compact());
+                append_reference(&mut stream, child);
+                stream.out().to_vec()
+            }
+            ProofNode::Leaf { key, value } => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&key.join(&Nibbles::from_hex(&[16])).encode_compact());
+                stream.append(value);
+                stream.out().to_vec()
+            }
+        }
+    }
+}
+
+// The reference to a child as stored in its parent: empty, a hash, or an embedded node.
+fn child_reference(item: &Rlp) -> TrieResult<Vec<u8>> {
+    if item.is_data() {
+        Ok(item.data()?.to_vec())
+    } else {
+        Ok(item.as_raw().to_vec())
+    }
+}
+
+fn append_reference(stream: &mut RlpStream, reference: &[u8]) {
+    if reference.is_empty() || reference.len() == HASHED_LENGTH {
+        stream.append(&reference);
+    } else {
+        stream.append_raw(reference, 1);
+    }
+}
+
+/// A proof in the compact form returned by `EthTrie::get_proof_minimal`, meant for uses
+/// such as zk circuits where the size of the witness matters.
+///
+/// Instead of the encoded nodes of a standard proof, it holds one step per node on the
+/// path to the key, from the root down. Each step keeps what is needed to encode its node
+/// again except the reference to the next node, which `verify_minimal` restores by encoding
+/// the steps from the bottom up. This is not RLP-compatible with standard proofs, and it
+/// can only be checked with `verify_minimal`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MinimalProof {
+    pub steps: Vec<MinimalStep>,
+}
+
+/// A node on the path of a `MinimalProof`. Child references are as in `ProofNode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MinimalStep {
+    /// A branch node whose child at index (0-15) continues the path, or, with index 16
+    /// (`BRANCH_VALUE`), at which the key ends. Only the other non-empty children are
+    /// kept, as pairs of their index and reference. If this is the last step and the path
+    /// continues, the child it continues to is empty.
+    Branch {
+        index: u8,
+        siblings: Vec<(u8, Vec<u8>)>,
+        value: Option<Vec<u8>>,
+    },
+    /// An extension node. The reference to its child is kept only if the key diverges from
+    /// the prefix, making this the last step.
+    Extension {
+        prefix: Nibbles,
+        child: Option<Vec<u8>>,
+    },
+    /// A leaf node, which is always the last step, with its key without terminator.
+    Leaf { key: Nibbles, value: Vec<u8> },
+}
+
+/// Verifies a proof in the format produced by `get_proof_minimal`. Returns the value if key
+/// exists, None if key does not exist, and `TrieError::InvalidProof` if the proof is wrong.
+pub fn verify_minimal(
+    root_hash: H256,
+    key: &[u8],
+    proof: &MinimalProof,
+) -> TrieResult<Option<Vec<u8>>> {
+    let steps = &proof.steps;
+    if steps.is_empty() {
+        return if root_hash == EMPTY_TRIE_ROOT {
+            Ok(None)
+        } else {
+            Err(TrieError::InvalidProof)
+        };
+    }
+
+    // Follow the key down the steps to find the value it ends at
+    let path = &Nibbles::from_raw(key, true);
+    let mut path_index = 0;
+    let mut value = None;
+    for (i, step) in steps.iter().enumerate() {
+        let partial = path.offset(path_index);
+        let last = i + 1 == steps.len();
+        match step {
+            MinimalStep::Branch {
+                index,
+                value: branch_value,
+                ..
+            } => {
+                if partial.try_at(0) != Some(*index as usize) {
+                    return Err(TrieError::InvalidProof);
+                }
+                if *index == BRANCH_VALUE {
+                    if !last {
+                        return Err(TrieError::InvalidProof);
+                    }
+                    value = branch_value.clone();
+                }
+                path_index += 1;
+            }
+            MinimalStep::Extension { prefix, child } => {
+                let matches = partial.common_prefix(prefix) == prefix.len();
+                if prefix.is_empty() || matches != child.is_none() || matches == last {
+                    return Err(TrieError::InvalidProof);
+                }
+                path_index += prefix.len();
+            }
+            MinimalStep::Leaf {
+                key: leaf_key,
+                value: leaf_value,
+            } => {
+                if !last {
+                    return Err(TrieError::InvalidProof);
+                }
+                if leaf_key.join(&Nibbles::from_hex(&[16])) == partial {
+                    value = Some(leaf_value.clone());
+                }
+            }
+        }
+    }
+
+    // Then encode the nodes from the bottom up, each one referring to the one below
+    let mut below: Vec<u8> = vec![];
+    for (i, step) in steps.iter().enumerate().rev() {
+        let encoded = match step {
+            MinimalStep::Branch {
+                index,
+                siblings,
+                value,
+            } => {
+                let mut children: [Vec<u8>; 16] = Default::default();
+                if *index != BRANCH_VALUE {
+                    children[*index as usize] = below;
+                }
+                for (sibling, reference) in siblings {
+                    let slot = children
+                        .get_mut(*sibling as usize)
+                        .ok_or(TrieError::InvalidProof)?;
+                    if *sibling == *index || !slot.is_empty() || reference.is_empty() {
+                        return Err(TrieError::InvalidProof);
+                    }
+                    *slot = reference.clone();
+                }
+                ProofNode::Branch {
+                    children: Box::new(children),
+                    value: value.clone(),
+                }
+            }
+            MinimalStep::Extension { prefix, child } => ProofNode::Extension {
+                prefix: prefix.clone(),
+                child: child.clone().unwrap_or(below),
+            },
+            MinimalStep::Leaf { key, value } => ProofNode::Leaf {
+                key: key.clone(),
+                value: value.clone(),
+            },
+        }
+        .encode();
+        below = if i == 0 || encoded.len() >= HASHED_LENGTH {
+            keccak(&encoded).as_bytes().to_vec()
+        } else {
+            encoded
+        };
+    }
+    if below != root_hash.as_bytes() {
+        return Err(TrieError::InvalidProof);
+    }
+    Ok(value)
+}
+
+/// Recorded by `proof_path_indices` for every extension node on the path.
+pub const EXTENSION_HOP: u8 = 0xff;
+
+/// Recorded by `proof_path_indices` when the key ends at a branch node, so its value is
+/// stored in the branch itself rather than in a child.
+pub const BRANCH_VALUE: u8 = 16;
+
+/// Verifies the proof for key and returns the navigation taken through the trie.
+///
+/// The result holds one entry per branch or extension node, from the root down. A branch
+/// records the index of the child that was followed (0-15), or `BRANCH_VALUE` if the key
+/// ends at that branch. An extension records `EXTENSION_HOP`. Leaf nodes add nothing.
+/// If the proof proves the absence of key, the path up to the point of divergence is
+/// returned. Returns `TrieError::InvalidProof` if the proof is missing nodes.
+pub fn proof_path_indices(root_hash: H256, key: &[u8], proof: Vec<Vec<u8>>) -> TrieResult<Vec<u8>> {
+    let trie = proof_trie::<KeccakHasher>(root_hash, proof);
+    trie.path_indices(key).or(Err(TrieError::InvalidProof))
+}
+
+/// Verifies a proof that key is not in the trie with the given root. Returns true if the
+/// proof is valid and shows that key is absent. Returns `TrieError::InvalidProof` if the
+/// proof is wrong, and also if it is valid but shows that key exists, so an inclusion proof
+/// can never be taken for a proof of absence.
+pub fn verify_exclusion(root_hash: H256, key: &[u8], proof: Vec<Vec<u8>>) -> TrieResult<bool> {
+    match verify_proof(root_hash, key, proof)? {
+        Some(_) => Err(TrieError::InvalidProof),
+        None => Ok(true),
+    }
+}
+
+/// Verifies the proof for key and checks that it proves exactly the expected value.
+/// Returns false if key has another value or is absent, and `TrieError::InvalidProof` if
+/// the proof is wrong.
+pub fn verify_proof_value(
+    root_hash: H256,
+    key: &[u8],
+    expected: &[u8],
+    proof: Vec<Vec<u8>>,
+) -> TrieResult<bool> {
+    let value = verify_proof(root_hash, key, proof)?;
+    Ok(value.as_deref() == Some(expected))
+}
+
+/// Verifies the proof for key like `verify_proof`, and also returns the hashes of the proof
+/// nodes the lookup went through, in order from the root down. Nodes of the proof that are
+/// not on the path of key are left out, as are nodes embedded in their parent.
+#[allow(clippy::type_complexity)]
+pub fn verify_proof_traced(
+    root_hash: H256,
+    key: &[u8],
+    proof: Vec<Vec<u8>>,
+) -> TrieResult<(Option<Vec<u8>>, Vec<H256>)> {
+    let trie = proof_trie::<KeccakHasher>(root_hash, proof);
+    trie.get_traced(key).or(Err(TrieError::InvalidProof))
+}
+
+/// Verifies many proofs like `verify_proof`, returning the value or None for each one, in
+/// order. The results are the same as from verifying each proof on its own, but all of them
+/// are checked in one `MemoryDB`, cleared between proofs, instead of a new one per proof.
+/// Returns `TrieError::InvalidProof` if any of the proofs is wrong.
+#[allow(clippy::type_complexity)]
+pub fn verify_proofs(items: &[(H256, Vec<u8>, Vec<Vec<u8>>)]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+    let proof_db = Arc::new(MemoryDB::new(true));
+    let mut values = Vec::with_capacity(items.len());
+    for (root_hash, key, proof) in items {
+        proof_db.clear();
+        let trie = proof_trie_in::<KeccakHasher>(proof_db.clone(), *root_hash, proof.clone());
+        values.push(trie.get(key).or(Err(TrieError::InvalidProof))?);
+    }
+    Ok(values)
+}
+
+/// Verifies the proof for key against each of the given roots, returning the first root
+/// the proof matches together with the proven value (None for a proof of absence).
+/// Returns None if the proof matches none of the roots.
+#[allow(clippy::type_complexity)]
+pub fn verify_proof_any(
+    roots: &[H256],
+    key: &[u8],
+    proof: Vec<Vec<u8>>,
+) -> TrieResult<Option<(H256, Option<Vec<u8>>)>> {
+    let node_hashes: Vec<H256> = proof.iter().map(keccak).collect();
+    for root in roots {
+        // Only try roots whose node is part of the proof
+        if !node_hashes.contains(root) {
+            continue;
+        }
+        let trie = proof_trie::<KeccakHasher>(*root, proof.clone());
+        if let Ok(value) = trie.get(key) {
+            return Ok(Some((*root, value)));
+        }
+    }
+    Ok(None)
+}
+
+/// Converts a proof for key, as returned by `get_proof`, to the compact encoding checked
+/// by `verify_proof_compact`.
+///
+/// The compact proof is an RLP list holding, from the root down, the proof nodes that are
+/// referenced by hash. Nodes shorter than 32 bytes other than the root are left out, since
+/// they are embedded in their parent anyway. In every node but the last, the reference to
+/// the next node is replaced by an empty string: the verifier finds that slot by following
+/// the key's nibbles and restores it by hashing the next node. This saves the 32-byte hash
+/// of each link in the path, at the cost of a few bytes of list framing per node.
+pub fn compact_proof(key: &[u8], proof: Vec<Vec<u8>>) -> TrieResult<Vec<u8>> {
+    compact_proof_with::<KeccakHasher>(key, proof)
+}
+
+// Like `compact_proof`, for a trie whose nodes are hashed with H.
+pub(crate) fn compact_proof_with<H: Hasher>(
+    key: &[u8],
+    proof: Vec<Vec<u8>>,
+) -> TrieResult<Vec<u8>> {
+    let nodes: Vec<Vec<u8>> = proof
+        .into_iter()
+        .enumerate()
+        .filter(|(i, node)| *i == 0 || node.len() >= HASHED_LENGTH)
+        .map(|(_, node)| node)
+        .collect();
+
+    let path = &Nibbles::from_raw(key, true);
+    let mut path_index = 0;
+    let mut stream = RlpStream::new_list(nodes.len());
+    for (i, node) in nodes.iter().enumerate() {
+        let r = Rlp::new(node);
+        match nodes.get(i + 1) {
+            Some(next) => {
+                let slot = next_slot(&r, path, &mut path_index)?.ok_or(TrieError::InvalidProof)?;
+                if r.at(slot)?.data()? != H::hash(next).as_bytes() {
+                    return Err(TrieError::InvalidProof);
+                }
+                stream.append(&replace_slot(&r, slot, &[])?);
+            }
+            None => {
+                stream.append(node);
+            }
+        }
+    }
+    Ok(stream.out().to_vec())
+}
+
+/// Verifies a proof in the format produced by `compact_proof`. Returns the value if key
+/// exists, None if key does not exist, and `TrieError::InvalidProof` if the proof is wrong.
+pub fn verify_proof_compact(
+    root_hash: H256,
+    key: &[u8],
+    compact: &[u8],
+) -> TrieResult<Option<Vec<u8>>> {
+    let nodes: Vec<Vec<u8>> = Rlp::new(compact)
+        .as_list()
+        .or(Err(TrieError::InvalidProof))?;
+    if nodes.is_empty() {
+        return if root_hash == EMPTY_TRIE_ROOT {
+            Ok(None)
+        } else {
+            Err(TrieError::InvalidProof)
+        };
+    }
+
+    // Find the slot of each node that refers to the next one, from the root down
+    let path = &Nibbles::from_raw(key, true);
+    let mut path_index = 0;
+    let mut slots = vec![];
+    for node in nodes[..nodes.len() - 1].iter() {
+        let slot = next_slot(&Rlp::new(node), path, &mut path_index)
+            .or(Err(TrieError::InvalidProof))?
+            .ok_or(TrieError::InvalidProof)?;
+        slots.push(slot);
+    }
+
+    // Then restore the references from the bottom up
+    let mut proof = vec![nodes[nodes.len() - 1].clone()];
+    for (node, slot) in nodes.iter().zip(slots).rev() {
+        let r = Rlp::new(node);
+        if r.at(slot)
+            .and_then(|item| item.data())
+            .map(|d| d.is_empty())
+            != Ok(true)
+        {
+            return Err(TrieError::InvalidProof);
+        }
+        let next_hash = keccak(&proof[proof.len() - 1]);
+        let restored =
+            replace_slot(&r, slot, next_hash.as_bytes()).or(Err(TrieError::InvalidProof))?;
+        proof.push(restored);
+    }
+    proof.reverse();
+
+    let trie = proof_trie::<KeccakHasher>(root_hash, proof);
+    trie.get(key).or(Err(TrieError::InvalidProof))
+}
+
+// Returns the index of the list item in a node that continues the path, advancing
+// path_index past the nibbles consumed by the node. Returns None if the path ends here.
+fn next_slot(node: &Rlp, path: &Nibbles, path_index: &mut usize) -> TrieResult<Option<usize>> {
+    match node.prototype()? {
+        Prototype::List(17) => {
+            if *path_index + 1 >= path.len() {
+                return Ok(None);
+            }
+            let slot = path.try_at(*path_index).ok_or(TrieError::InvalidProof)?;
+            *path_index += 1;
+            Ok(Some(slot))
+        }
+        Prototype::List(2) => {
+            let prefix =
+                Nibbles::try_from_compact(node.at(0)?.data()?).ok_or(TrieError::InvalidData {
+                    node_hash: None,
+                    len: node.as_raw().len(),
+                })?;
+            let partial = path.offset(*path_index);
+            if prefix.is_leaf() || partial.common_prefix(&prefix) != prefix.len() {
+                return Ok(None);
+            }
+            *path_index += prefix.len();
+            Ok(Some(1))
+        }
+        _ => Err(TrieError::InvalidData {
+            node_hash: None,
+            len: node.as_raw().len(),
+        }),
+    }
+}
+
+// Re-encodes a node with the item at slot replaced by the given data.
+fn replace_slot(node: &Rlp, slot: usize, data: &[u8]) -> TrieResult<Vec<u8>> {
+    let count = node.item_count()?;
+    let mut stream = RlpStream::new_list(count);
+    for i in 0..count {
+        if i == slot {
+            stream.append(&data);
+        } else {
+            stream.append_raw(node.at(i)?.as_raw(), 1);
+        }
+    }
+    Ok(stream.out().to_vec())
+}
+
+// Builds a trie backed only by the nodes of the proof.
+pub(crate) fn proof_trie<H: Hasher>(root_hash: H256, proof: Vec<Vec<u8>>) -> EthTrie<MemoryDB, H> {
+    proof_trie_in(Arc::new(MemoryDB::new(true)), root_hash, proof)
+}
+
+// Like `proof_trie`, adding the nodes of the proof to proof_db.
+pub(crate) fn proof_trie_in<H: Hasher>(
+    proof_db: Arc<MemoryDB>,
+    root_hash: H256,
+    proof: Vec<Vec<u8>>,
+) -> EthTrie<MemoryDB, H> {
+    for node_encoded in proof.into_iter() {
+        let hash = H::hash(&node_encoded);
+
+        if root_hash.eq(&hash) || node_encoded.len() >= HASHED_LENGTH {
+            proof_db.insert(hash.as_bytes(), node_encoded).unwrap();
+        }
+    }
+    EthTrie::with_hasher(proof_db).at_root(root_hash)
+}
@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use keccak_hash::H256;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::db::MemoryDB;
+use crate::trie::{EthTrie, Trie, TrieResult};
+
+/// Computes the root of a trie holding each item under the RLP encoding of its index, as
+/// done for the transactions and receipts of an Ethereum block.
+pub fn ordered_trie_root<T: AsRef<[u8]>>(items: &[T]) -> TrieResult<H256> {
+    root_with_keyer(items, |i, _| rlp::encode(&i).to_vec())
+}
+
+/// Computes the root of a trie holding each item under the key derived by `keyer` from
+/// its index and value. If two items get the same key, the later one wins.
+pub fn root_with_keyer<T, F>(items: &[T], keyer: F) -> TrieResult<H256>
+where
+    T: AsRef<[u8]>,
+    F: Fn(usize, &T) -> Vec<u8>,
+{
+    let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+    for (i, item) in items.iter().enumerate() {
+        trie.insert(&keyer(i, item), item.as_ref())?;
+    }
+    trie.root_hash()
+}
+
+/// Computes the root of a trie holding the given key/value pairs.
+pub fn root_of(pairs: &[(Vec<u8>, Vec<u8>)]) -> TrieResult<H256> {
+    let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+    for (key, value) in pairs {
+        trie.insert(key, value)?;
+    }
+    trie.root_hash()
+}
+
+/// Computes the root of each independent set of key/value pairs, such as the transaction
+/// tries of many blocks, in the order of `batches`. With the `rayon` feature enabled the
+/// roots are computed in parallel.
+pub fn batch_roots(batches: &[Vec<(Vec<u8>, Vec<u8>)>]) -> TrieResult<Vec<H256>> {
+    #[cfg(feature = "rayon")]
+    let roots = batches.par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let roots = batches.iter();
+
+    roots.map(|pairs| root_of(pairs)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trie::EMPTY_TRIE_ROOT;
+
+    #[test]
+    fn test_ordered_trie_root() {
+        let items: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| format!("tx-{}", i).into_bytes())
+            .collect();
+
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        for (i, item) in items.iter().enumerate() {
+            trie.insert(&rlp::encode(&i), item).unwrap();
+        }
+        assert_eq!(
+            ordered_trie_root(&items).unwrap(),
+            trie.root_hash().unwrap()
+        );
+
+        let empty: Vec<Vec<u8>> = vec![];
+        assert_eq!(ordered_trie_root(&empty).unwrap(), EMPTY_TRIE_ROOT);
+    }
+
+    #[test]
+    fn test_root_with_keyer() {
+        let items: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| format!("tx-{}", i).into_bytes())
+            .collect();
+
+        let by_index = root_with_keyer(&items, |i, _| rlp::encode(&i).to_vec()).unwrap();
+        assert_eq!(by_index, ordered_trie_root(&items).unwrap());
+
+        let by_value = root_with_keyer(&items, |_, item| item.clone()).unwrap();
+        assert_ne!(by_value, by_index);
+    }
+
+    #[test]
+    fn test_batch_roots() {
+        let batches: Vec<Vec<(Vec<u8>, Vec<u8>)>> = (0..20usize)
+            .map(|n| {
+                (0..n)
+                    .map(|i| {
+                        (
+                            rlp::encode(&i).to_vec(),
+                            format!("tx-{}-{}", n, i).into_bytes(),
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let roots = batch_roots(&batches).unwrap();
+        assert_eq!(roots.len(), batches.len());
+        assert_eq!(roots[0], EMPTY_TRIE_ROOT);
+        for (root, pairs) in roots.iter().zip(batches.iter()) {
+            assert_eq!(*root, root_of(pairs).unwrap());
+        }
+    }
+}
@@ -1,11 +1,19 @@
 #[cfg(test)]
 mod trie_tests {
     use hex::FromHex;
+    use keccak_hash::keccak;
     use rand::Rng;
     use std::sync::Arc;
 
     use crate::db::MemoryDB;
-    use crate::trie::{EthTrie, Trie};
+    use crate::errors::TrieError;
+    use crate::nibbles::Nibbles;
+    use crate::proof::{
+        proof_path_indices, verify_exclusion, verify_minimal, verify_proof_any,
+        verify_proof_compact, verify_proof_traced, verify_proof_value, verify_proofs, MinimalStep,
+        ProofNode, BRANCH_VALUE, EXTENSION_HOP,
+    };
+    use crate::trie::{verify_proof, EthTrie, Trie};
 
     fn assert_root(data: Vec<(&[u8], &[u8])>, hash: &str) {
         let memdb = Arc::new(MemoryDB::new(true));
@@ -659,4 +667,650 @@ mod trie_tests {
         let value = trie.verify_proof(root, b"k", proof).unwrap();
         assert_eq!(value, Some(b"v".to_vec()));
     }
+
+    #[test]
+    fn test_proof_path_indices() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        trie.insert(b"doe", b"reindeer").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"dogglesworth", b"cat").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // All keys share the extension "646f6", then branch on the next nibble
+        let proof = trie.get_proof(b"doe").unwrap();
+        let indices = proof_path_indices(root, b"doe", proof).unwrap();
+        assert_eq!(indices, vec![EXTENSION_HOP, 5]);
+
+        let proof = trie.get_proof(b"dog").unwrap();
+        let indices = proof_path_indices(root, b"dog", proof).unwrap();
+        assert_eq!(indices, vec![EXTENSION_HOP, 7, BRANCH_VALUE]);
+
+        let proof = trie.get_proof(b"dogglesworth").unwrap();
+        let indices = proof_path_indices(root, b"dogglesworth", proof).unwrap();
+        assert_eq!(indices, vec![EXTENSION_HOP, 7, 6]);
+
+        let proof = vec![b"aaa".to_vec(), b"ccc".to_vec()];
+        let result = proof_path_indices(root, b"doe", proof);
+        assert_eq!(result, Err(TrieError::InvalidProof));
+    }
+
+    #[test]
+    fn test_iter_with_proofs() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        let mut keys = vec![];
+        for i in 0..50u32 {
+            let key = format!("key-{}", i).into_bytes();
+            trie.insert(&key, format!("value-{}-with-some-padding", i).as_bytes())
+                .unwrap();
+            keys.push(key);
+        }
+        keys.push(b"not-exist".to_vec());
+        let root = trie.root_hash().unwrap();
+
+        let key_refs: Vec<&[u8]> = keys.iter().rev().map(|k| k.as_slice()).collect();
+        let results = trie.iter_with_proofs(&key_refs).unwrap();
+        assert_eq!(results.len(), key_refs.len());
+
+        for ((key, value, proof), expected_key) in results.into_iter().zip(key_refs) {
+            assert_eq!(key, expected_key);
+            assert_eq!(proof, trie.get_proof(&key).unwrap());
+            assert_eq!(trie.verify_proof(root, &key, proof).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_get_proof_limited() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        trie.insert(b"doe", b"reindeer").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"dogglesworth", b"cat").unwrap();
+        trie.root_hash().unwrap();
+
+        let full = trie.get_proof(b"dogglesworth").unwrap();
+        assert_eq!(full.len(), 4);
+
+        let (first, complete) = trie.get_proof_limited(b"dogglesworth", 2).unwrap();
+        assert!(!complete);
+        assert_eq!(first.len(), 2);
+
+        let (more, complete) = trie.get_proof_limited(b"dogglesworth", 4).unwrap();
+        assert!(complete);
+        let rebuilt: Vec<Vec<u8>> = first.into_iter().chain(more[2..].to_vec()).collect();
+        assert_eq!(rebuilt, full);
+
+        let (all, complete) = trie.get_proof_limited(b"dogglesworth", 10).unwrap();
+        assert!(complete);
+        assert_eq!(all, full);
+    }
+
+    #[test]
+    fn test_get_proof_bounded() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        trie.insert(b"uncommitted", b"value").unwrap();
+
+        for key in [&b"key-7"[..], b"key-70", b"key-700", b"uncommitted"] {
+            let proof = trie.get_proof(key).unwrap();
+            let size: usize = proof.iter().map(|n| n.len()).sum();
+            assert_eq!(trie.get_proof_bounded(key, size).unwrap(), proof);
+            assert_eq!(
+                trie.get_proof_bounded(key, size - 1),
+                Err(TrieError::ProofTooLarge { size })
+            );
+        }
+
+        // The walk stops at the node that crosses the limit
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+        let proof = trie.get_proof_bounded(b"key-7", usize::MAX).unwrap();
+        assert_eq!(
+            proof,
+            trie.get_proof_limited(b"key-7", usize::MAX).unwrap().0
+        );
+        assert_eq!(
+            trie.get_proof_bounded(b"key-7", 0),
+            Err(TrieError::ProofTooLarge {
+                size: proof[0].len()
+            })
+        );
+    }
+
+    #[test]
+    fn test_proof_compact() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..500 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(2..30))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(&random_bytes, &random_bytes).unwrap();
+            keys.push(random_bytes);
+        }
+        let root = trie.root_hash().unwrap();
+
+        let mut standard_size = 0;
+        let mut compact_size = 0;
+        for k in keys.iter() {
+            let compact = trie.get_proof_compact(k).unwrap();
+            let value = verify_proof_compact(root, k, &compact).unwrap();
+            assert_eq!(value.as_ref(), Some(k));
+
+            standard_size += trie
+                .get_proof(k)
+                .unwrap()
+                .iter()
+                .map(|n| n.len())
+                .sum::<usize>();
+            compact_size += compact.len();
+        }
+        // Each link saves a 32-byte hash, about 7% of the proofs in a trie of this size
+        assert!(compact_size * 20 < standard_size * 19);
+
+        // Proof of absence
+        let compact = trie.get_proof_compact(b"not-exist").unwrap();
+        assert_eq!(
+            verify_proof_compact(root, b"not-exist", &compact).unwrap(),
+            None
+        );
+
+        // Wrong root or tampered proof
+        let mut compact = trie.get_proof_compact(&keys[0]).unwrap();
+        let result = verify_proof_compact(keccak_hash::keccak(b"root"), &keys[0], &compact);
+        assert_eq!(result, Err(TrieError::InvalidProof));
+        let last = compact.len() - 1;
+        compact[last] ^= 1;
+        assert!(verify_proof_compact(root, &keys[0], &compact).is_err());
+    }
+
+    #[test]
+    fn test_update_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        for i in 0..200u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        trie.root_hash().unwrap();
+        let old_proof = trie.get_proof(b"key-7").unwrap();
+
+        trie.insert(b"unrelated", b"value").unwrap();
+        trie.insert(b"key-150", b"changed").unwrap();
+        let new_root = trie.root_hash().unwrap();
+
+        let proof = trie.update_proof(&old_proof, b"key-7").unwrap();
+        assert_eq!(proof, trie.get_proof(b"key-7").unwrap());
+        let value = trie.verify_proof(new_root, b"key-7", proof).unwrap();
+        assert_eq!(value, Some(b"value-7".to_vec()));
+    }
+
+    #[test]
+    fn test_verify_proof_any() {
+        // Keep the nodes of old roots
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        let mut roots = vec![];
+        for i in 0..5u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+            roots.push(trie.root_hash().unwrap());
+        }
+        // A proof taken at the third root
+        let mut old_trie = trie.at_root(roots[2]);
+        let proof = old_trie.get_proof(b"key-1").unwrap();
+
+        let matched = verify_proof_any(&roots, b"key-1", proof.clone()).unwrap();
+        assert_eq!(matched, Some((roots[2], Some(b"value-1".to_vec()))));
+
+        let result = verify_proof_any(&[roots[0], roots[4]], b"key-1", proof).unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_get_proofs() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(Arc::clone(&memdb));
+        for i in 0..200u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+
+        let keys: Vec<&[u8]> = vec![b"key-1", b"key-10", b"key-100", b"key-57", b"missing"];
+        let proof = trie.get_proofs(&keys).unwrap();
+
+        let mut hashes: Vec<_> = proof.iter().map(keccak).collect();
+        hashes.sort();
+        hashes.dedup();
+        assert_eq!(hashes.len(), proof.len());
+        let separate: usize = keys
+            .iter()
+            .map(|key| trie.get_proof(key).unwrap().len())
+            .sum();
+        assert!(proof.len() < separate);
+
+        for key in keys {
+            let value = trie.verify_proof(root, key, proof.clone()).unwrap();
+            assert_eq!(value, trie.get(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_verify_proof_without_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..50u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let proof = trie.get_proof(b"key-7").unwrap();
+        let absent = trie.get_proof(b"key-70").unwrap();
+        drop(trie);
+
+        let value = verify_proof(root, b"key-7", proof.clone()).unwrap();
+        assert_eq!(value, Some(b"value-7".to_vec()));
+        assert_eq!(verify_proof(root, b"key-70", absent).unwrap(), None);
+        assert_eq!(
+            verify_proof(root, b"key-7", proof[1..].to_vec()),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_verify_malformed_path() {
+        // An extension whose compact path has no nibbles
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append(&vec![0x00u8]);
+        stream.append(&keccak(b"child").as_bytes());
+        let node = stream.out().to_vec();
+        assert_eq!(
+            verify_proof(keccak(&node), b"key", vec![node]),
+            Err(TrieError::InvalidProof)
+        );
+
+        // A compact proof whose first node has an empty path
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append_empty_data();
+        stream.append_empty_data();
+        let node = stream.out().to_vec();
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append_raw(&node, 1);
+        stream.append_raw(&node, 1);
+        let compact = stream.out().to_vec();
+        assert_eq!(
+            verify_proof_compact(keccak(&node), b"key", &compact),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_verify_proofs() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..20u32 {
+            let value = format!("value-{}-long-enough-to-be-hashed", i);
+            trie.insert(&i.to_be_bytes(), value.as_bytes()).unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let mut other = EthTrie::new(memdb);
+        other.insert(b"other", b"value").unwrap();
+        let other_root = other.root_hash().unwrap();
+
+        let mut items = vec![];
+        for i in [3u32, 19, 25].iter() {
+            let key = i.to_be_bytes().to_vec();
+            items.push((root, key.clone(), trie.get_proof(&key).unwrap()));
+        }
+        items.push((
+            other_root,
+            b"other".to_vec(),
+            other.get_proof(b"other").unwrap(),
+        ));
+        let expected: Vec<_> = items
+            .iter()
+            .map(|(root, key, proof)| verify_proof(*root, key, proof.clone()).unwrap())
+            .collect();
+        assert_eq!(verify_proofs(&items), Ok(expected));
+        assert_eq!(verify_proofs(&[]), Ok(vec![]));
+
+        // Nodes of earlier proofs are not available to later ones
+        let mut partial = items[0].clone();
+        partial.2.pop();
+        items.push(partial);
+        assert_eq!(verify_proofs(&items), Err(TrieError::InvalidProof));
+    }
+
+    #[test]
+    fn test_verify_exclusion() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"branch-value").unwrap();
+        trie.insert(b"test1", b"value-1").unwrap();
+        trie.insert(b"test2", b"value-2").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // The proof ends at the branch, whose child for the next nibble of the key is empty
+        let absent = trie.get_proof(b"test3").unwrap();
+        assert_eq!(verify_exclusion(root, b"test3", absent.clone()), Ok(true));
+        assert_eq!(
+            verify_exclusion(root, b"tes", trie.get_proof(b"tes").unwrap()),
+            Ok(true)
+        );
+
+        // Proofs of keys that exist, including the branch value, are rejected
+        let present = trie.get_proof(b"test1").unwrap();
+        assert_eq!(
+            verify_exclusion(root, b"test1", present),
+            Err(TrieError::InvalidProof)
+        );
+        let branch = trie.get_proof(b"test").unwrap();
+        assert_eq!(
+            verify_exclusion(root, b"test", branch),
+            Err(TrieError::InvalidProof)
+        );
+
+        // So are proofs that do not match the root
+        assert_eq!(
+            verify_exclusion(keccak(b"other"), b"test3", absent),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_verify_proof_value() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"branch-value").unwrap();
+        trie.insert(b"test1", b"value-1").unwrap();
+        trie.insert(b"test2", b"value-2").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        let proof = trie.get_proof(b"test1").unwrap();
+        assert_eq!(
+            verify_proof_value(root, b"test1", b"value-1", proof.clone()),
+            Ok(true)
+        );
+        assert_eq!(
+            verify_proof_value(root, b"test1", b"value-2", proof.clone()),
+            Ok(false)
+        );
+        assert_eq!(
+            verify_proof_value(root, b"test1", b"value-", proof.clone()),
+            Ok(false)
+        );
+        let branch = trie.get_proof(b"test").unwrap();
+        assert_eq!(
+            verify_proof_value(root, b"test", b"branch-value", branch),
+            Ok(true)
+        );
+
+        // A proof of absence never matches
+        let absent = trie.get_proof(b"test3").unwrap();
+        assert_eq!(verify_proof_value(root, b"test3", b"", absent), Ok(false));
+
+        assert_eq!(
+            verify_proof_value(keccak(b"other"), b"test1", b"value-1", proof),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_get_proof_structured() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"doe", b"reindeer").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"dogglesworth", b"cat").unwrap();
+        trie.insert(b"horse", b"stallion-with-a-long-enough-value")
+            .unwrap();
+        trie.insert(b"ho", b"branch-value").unwrap();
+        trie.root_hash().unwrap();
+
+        for key in [
+            &b"doe"[..],
+            b"dog",
+            b"dogglesworth",
+            b"horse",
+            b"ho",
+            b"cat",
+        ]
+        .iter()
+        {
+            let proof = trie.get_proof(key).unwrap();
+            let structured = trie.get_proof_structured(key).unwrap();
+            let encoded: Vec<Vec<u8>> = structured.iter().map(ProofNode::encode).collect();
+            assert_eq!(encoded, proof);
+        }
+
+        // The root branches on the first nibble of "d" and "h", whose children are hashed
+        let structured = trie.get_proof_structured(b"horse").unwrap();
+        match &structured[0] {
+            ProofNode::Extension { prefix, child } => {
+                assert_eq!(prefix, &Nibbles::from_hex(&[6]));
+                assert_eq!(child.len(), 32);
+            }
+            node => panic!("unexpected root {:?}", node),
+        }
+        match structured.last().unwrap() {
+            ProofNode::Leaf { key, value } => {
+                assert_eq!(key.get_data().last(), Some(&5));
+                assert_eq!(value, b"stallion-with-a-long-enough-value");
+            }
+            node => panic!("unexpected last node {:?}", node),
+        }
+
+        assert!(matches!(
+            ProofNode::decode(&rlp::NULL_RLP),
+            Err(TrieError::InvalidData { .. })
+        ));
+    }
+
+    #[test]
+    fn test_get_proof_minimal() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert_eq!(
+            verify_minimal(
+                trie.root_hash().unwrap(),
+                b"key",
+                &trie.get_proof_minimal(b"key").unwrap()
+            ),
+            Ok(None)
+        );
+        for i in 0..50u32 {
+            let value = format!("value-{}-long-enough-to-be-hashed", i);
+            trie.insert(format!("key-{}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        trie.insert(b"key", b"branch-value").unwrap();
+        trie.insert(b"k", b"v").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        let keys: Vec<&[u8]> = vec![
+            b"key-7", b"key-42", b"key", b"k", b"key-50", b"key-", b"kex", b"other", b"",
+        ];
+        for key in keys {
+            let proof = trie.get_proof_minimal(key).unwrap();
+            let expected = trie.get(key).unwrap();
+            assert_eq!(verify_minimal(root, key, &proof), Ok(expected.clone()));
+            assert_eq!(
+                verify_proof(root, key, trie.get_proof(key).unwrap()),
+                Ok(expected)
+            );
+        }
+
+        // A branch step leaves out the child the path continues to
+        let proof = trie.get_proof_minimal(b"key-7").unwrap();
+        match proof
+            .steps
+            .iter()
+            .find(|step| matches!(step, MinimalStep::Branch { .. }))
+        {
+            Some(MinimalStep::Branch {
+                index, siblings, ..
+            }) => assert!(siblings.iter().all(|(i, _)| i != index)),
+            _ => panic!("no branch on the path"),
+        }
+
+        // Any change to the proof is detected
+        let mut tampered = proof.clone();
+        match tampered.steps.last_mut() {
+            Some(MinimalStep::Leaf { value, .. }) => value.push(0),
+            step => panic!("unexpected last step {:?}", step),
+        }
+        assert_eq!(
+            verify_minimal(root, b"key-7", &tampered),
+            Err(TrieError::InvalidProof)
+        );
+        let mut truncated = proof.clone();
+        truncated.steps.pop();
+        assert_eq!(
+            verify_minimal(root, b"key-7", &truncated),
+            Err(TrieError::InvalidProof)
+        );
+        assert_eq!(
+            verify_minimal(root, b"key-8", &proof),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_verify_proof_traced() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let value = [0xab; 40];
+        for i in 0..20u8 {
+            trie.insert(&[i, 1], &value).unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+
+        // Every node of the proof is on the path and long enough to be hashed
+        let proof = trie.get_proof(&[3, 1]).unwrap();
+        let expected: Vec<_> = proof.iter().map(keccak).collect();
+        assert_eq!(expected[0], root);
+
+        // Nodes of another path are not reported
+        let mut mixed = trie.get_proof(&[17, 1]).unwrap();
+        mixed.extend(proof.clone());
+        assert_eq!(
+            verify_proof_traced(root, &[3, 1], mixed),
+            Ok((Some(value.to_vec()), expected.clone()))
+        );
+
+        // A proof of absence ends where the key diverges
+        let absent = trie.get_proof(&[3, 2]).unwrap();
+        let (found, visited) = verify_proof_traced(root, &[3, 2], absent).unwrap();
+        assert_eq!(found, None);
+        assert_eq!(visited, expected[..visited.len()].to_vec());
+
+        assert_eq!(
+            verify_proof_traced(keccak(b"other"), &[3, 1], proof),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_proof_serde() {
+        use crate::proof::Proof;
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..50u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let proof: Proof = trie.get_proof(b"key-7").unwrap().into();
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.starts_with("[\"0x"));
+        let decoded: Proof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+        let value = verify_proof(root, b"key-7", decoded.into()).unwrap();
+        assert_eq!(value, Some(b"value-7".to_vec()));
+
+        assert!(serde_json::from_str::<Proof>(r#"["0x0g"]"#).is_err());
+        assert!(serde_json::from_str::<Proof>(r#"["0x123"]"#).is_err());
+        assert!(serde_json::from_str::<Proof>(r#"["0x\u00e9\u00e9"]"#).is_err());
+    }
+
+    #[test]
+    fn test_get_with_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..200u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        // Stored in a branch node
+        trie.insert(b"key-1", b"value").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        for key in [
+            &b"key-1"[..],
+            b"key-57",
+            b"key-199",
+            b"key-2000",
+            b"key",
+            b"other",
+        ] {
+            let (value, proof) = trie.get_with_proof(key).unwrap();
+            assert_eq!(value, trie.get(key).unwrap());
+            assert_eq!(proof, trie.get_proof(key).unwrap());
+            assert_eq!(verify_proof(root, key, proof).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_get_with_proof_missing_under_branch_value() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"ab", b"branchval").unwrap();
+        trie.insert(b"abc", b"value-c").unwrap();
+        trie.insert(b"abd", b"value-d").unwrap();
+
+        // The lookup of abz ends at the branch holding the value of ab
+        for _ in 0..2 {
+            for key in [&b"abz"[..], b"ab", b"abc", b"a"] {
+                let (value, proof) = trie.get_with_proof(key).unwrap();
+                assert_eq!(value, trie.get(key).unwrap());
+                assert_eq!(proof, trie.get_proof(key).unwrap());
+            }
+            let (value, proof) = trie.get_with_proof(b"abz").unwrap();
+            assert_eq!(value, None);
+            let root = trie.root_hash().unwrap();
+            assert_eq!(verify_proof(root, b"abz", proof).unwrap(), None);
+        }
+    }
 }
@@ -1,17 +1,62 @@
+use std::io::{ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
 use hashbrown::{HashMap, HashSet};
-use keccak_hash::{keccak, H256};
+use keccak_hash::{keccak, H256, KECCAK_NULL_RLP};
 use log::warn;
-use rlp::{Prototype, Rlp, RlpStream};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use rlp::{Rlp, RlpStream};
 
 use crate::db::{MemoryDB, DB};
 use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
 use crate::nibbles::Nibbles;
-use crate::node::{empty_children, BranchNode, Node};
+use crate::node::{
+    child_reference, decode_node, decode_node_with_hash, empty_children, encode_node_raw_with,
+    node_hash_with, BranchNode, LeafNode, Node, NodeRef,
+};
+use crate::proof::{
+    compact_proof_with, proof_trie, MinimalProof, MinimalStep, ProofNode, BRANCH_VALUE,
+    EXTENSION_HOP,
+};
 
 pub type TrieResult<T> = Result<T, TrieError>;
-const HASHED_LENGTH: usize = 32;
+pub(crate) const HASHED_LENGTH: usize = 32;
+
+// The db entry holding the root of the last commit, read by `EthTrie::open_latest`. Nodes
+// are stored under their 32-byte hash, so a key of any other length can never collide
+// with one.
+const LATEST_ROOT_KEY: &[u8] = b"eth_trie:latest_root";
+
+// The db entry holding the roots recorded with `with_root_history`, oldest first, as
+// concatenated 32-byte hashes.
+const ROOT_HISTORY_KEY: &[u8] = b"eth_trie:root_history";
+
+/// The root hash of a trie with no entries, `keccak(rlp(""))`. This is the same value as
+/// Ethereum's empty state root,
+/// `0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421`.
+pub const EMPTY_TRIE_ROOT: H256 = KECCAK_NULL_RLP;
+
+/// Returns true if root is the root hash of a trie with no entries.
+pub fn is_empty_root(root: H256) -> bool {
+    root == EMPTY_TRIE_ROOT
+}
+
+/// Verifies a proof for key against root_hash without an existing trie. Returns the value
+/// if key exists, None if key does not exist, and `TrieError::InvalidProof` if the proof
+/// is wrong.
+pub fn verify_proof(
+    root_hash: H256,
+    key: &[u8],
+    proof: Vec<Vec<u8>>,
+) -> TrieResult<Option<Vec<u8>>> {
+    let trie = proof_trie::<KeccakHasher>(root_hash, proof);
+    trie.get(key).or(Err(TrieError::InvalidProof))
+}
 
 pub trait Trie<D: DB> {
     /// Returns the value for key stored in the trie.
@@ -20,9 +65,18 @@ pub trait Trie<D: DB> {
     /// Checks that the key is present in the trie
     fn contains(&self, key: &[u8]) -> TrieResult<bool>;
 
-    /// Inserts value into trie and modifies it if it exists
+    /// Inserts value into trie and modifies it if it exists.
+    /// By default, inserting an empty value removes the key.
     fn insert(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()>;
 
+    /// Inserts every entry, in order, as if by calling `insert` for each one.
+    fn insert_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> TrieResult<()> {
+        for (key, value) in entries {
+            self.insert(&key, &value)?;
+        }
+        Ok(())
+    }
+
     /// Removes any existing value for key from the trie.
     fn remove(&mut self, key: &[u8]) -> TrieResult<bool>;
 
@@ -50,9 +104,10 @@ pub trait Trie<D: DB> {
 }
 
 #[derive(Debug)]
-pub struct EthTrie<D>
+pub struct EthTrie<D, H = KeccakHasher>
 where
     D: DB,
+    H: Hasher,
 {
     root: Node,
     root_hash: H256,
@@ -63,6 +118,259 @@ where
     cache: HashMap<Vec<u8>, Vec<u8>>,
     passing_keys: HashSet<Vec<u8>>,
     gen_keys: HashSet<Vec<u8>>,
+
+    // Whether inserting an empty value removes the key, or is rejected as an error
+    delete_on_empty_value: bool,
+
+    // Whether inserting a key that is a prefix of another key, or the other way around, is
+    // rejected as an error
+    strict_keys: bool,
+
+    // How many of the latest commit roots are recorded in the db, 0 if none are
+    root_history: usize,
+
+    // Bumped on every commit by this trie or any trie derived from it with `at_root`
+    generation: Arc<AtomicU64>,
+
+    // Asked for nodes that are missing from the db, shared with the tries derived from this one
+    node_fetcher: Option<Arc<NodeFetcher>>,
+
+    hasher: PhantomData<fn() -> H>,
+}
+
+// The callback set with `set_node_fetcher`, wrapped so the trie can still derive Debug.
+struct NodeFetcher(Box<dyn Fn(H256) -> Option<Vec<u8>> + Send + Sync>);
+
+impl std::fmt::Debug for NodeFetcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NodeFetcher")
+    }
+}
+
+/// A value read from the trie with `get_guard` or `iter_refs`, which derefs to the value bytes.
+///
+/// A value stored in a leaf node is borrowed from that node instead of being copied. Leaf
+/// nodes are never modified in place, so the guard does not block writes to the trie: it
+/// keeps the value it was created with, even if the key is later changed or removed.
+/// Values stored in branch nodes are copied into the guard.
+#[derive(Debug)]
+pub struct ValueGuard {
+    value: GuardedValue,
+}
+
+#[derive(Debug)]
+enum GuardedValue {
+    Leaf(Arc<LeafNode>),
+    Owned(Vec<u8>),
+}
+
+impl ValueGuard {
+    /// Returns the value as an owned vector.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.value {
+            GuardedValue::Leaf(leaf) => leaf.value.clone(),
+            GuardedValue::Owned(value) => value,
+        }
+    }
+}
+
+impl Deref for ValueGuard {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.value {
+            GuardedValue::Leaf(leaf) => &leaf.value,
+            GuardedValue::Owned(value) => value,
+        }
+    }
+}
+
+/// The difference between the keys of a trie and an expected key set, as reported by
+/// `verify_key_set`. Both lists are sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeySetDiff {
+    /// Expected keys that are not in the trie.
+    pub missing: Vec<Vec<u8>>,
+    /// Keys in the trie that were not expected.
+    pub extra: Vec<Vec<u8>>,
+}
+
+impl KeySetDiff {
+    /// Returns true if the trie holds exactly the expected keys.
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+/// The uncommitted state of a trie, saved by `EthTrie::checkpoint` and restored by
+/// `EthTrie::rollback`.
+#[derive(Debug)]
+pub struct Checkpoint {
+    root: Node,
+    root_hash: H256,
+    cache: HashMap<Vec<u8>, Vec<u8>>,
+    passing_keys: HashSet<Vec<u8>>,
+    gen_keys: HashSet<Vec<u8>>,
+}
+
+/// The db changes made by a commit, returned by `EthTrie::commit_with_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitReport {
+    /// The root hash after the commit.
+    pub root: H256,
+    /// The hashes of the nodes written to the db.
+    pub written: Vec<H256>,
+    /// The hashes of the nodes removed from the db because the commit replaced them.
+    pub deleted: Vec<H256>,
+}
+
+/// Counts describing the shape of a trie, returned by `EthTrie::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    pub leaf_nodes: usize,
+    pub extension_nodes: usize,
+    pub branch_nodes: usize,
+    /// Nodes shorter than 32 bytes when encoded, which are embedded in their parent.
+    pub inline_nodes: usize,
+    /// Nodes stored in the db under their hash, including the root.
+    pub hashed_nodes: usize,
+    /// The largest number of nodes on a path from the root, counting the root as 1.
+    pub max_depth: usize,
+    /// The total length of the values, including values stored in branch nodes.
+    pub value_bytes: usize,
+}
+
+/// A committed trie that can only be read, returned by `EthTrie::seal`.
+///
+/// `SealedTrie` has no methods that modify the trie, so finalized state cannot be changed
+/// by accident:
+///
+/// ```compile_fail
+/// use std::sync::Arc;
+/// use eth_trie::{EthTrie, MemoryDB, Trie};
+///
+/// let trie = EthTrie::new(Arc::new(MemoryDB::new(true))).seal().unwrap();
+/// trie.insert(b"test", b"test");
+/// ```
+#[derive(Debug)]
+pub struct SealedTrie<D, H = KeccakHasher>
+where
+    D: DB,
+    H: Hasher,
+{
+    trie: EthTrie<D, H>,
+}
+
+impl<D, H> SealedTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Returns the root hash the trie was sealed at.
+    pub fn root_hash(&self) -> H256 {
+        self.trie.root_hash
+    }
+
+    /// Returns the value for key stored in the trie.
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(key)
+    }
+
+    /// Checks that the key is present in the trie
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    /// Returns the proof for key, in the same form as `Trie::get_proof`.
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let (proof, _) = self.trie.get_proof_limited(key, usize::MAX)?;
+        Ok(proof)
+    }
+
+    pub fn iter(&self) -> TrieIterator<'_, D, H> {
+        self.trie.iter()
+    }
+}
+
+/// A borrowed view of a trie that can only be read, returned by `EthTrie::as_readonly`.
+///
+/// Unlike `SealedTrie`, the view does not require the trie to be committed, and reads see
+/// the changes that are not committed yet. It has no methods that modify the trie or commit
+/// it, including `root_hash`:
+///
+/// ```compile_fail
+/// use std::sync::Arc;
+/// use eth_trie::{EthTrie, MemoryDB, Trie};
+///
+/// let trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+/// trie.as_readonly().insert(b"test", b"test");
+/// ```
+#[derive(Debug)]
+pub struct ReadOnlyTrie<'a, D, H = KeccakHasher>
+where
+    D: DB,
+    H: Hasher,
+{
+    trie: &'a EthTrie<D, H>,
+}
+
+impl<'a, D, H> ReadOnlyTrie<'a, D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Returns the value for key stored in the trie.
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get(key)
+    }
+
+    /// Checks that the key is present in the trie
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    /// Returns the proof for key, in the same form as `Trie::get_proof`.
+    pub fn get_proof(&self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let (proof, _) = self.trie.get_proof_limited(key, usize::MAX)?;
+        Ok(proof)
+    }
+
+    pub fn iter(&self) -> TrieIterator<'a, D, H> {
+        self.trie.iter()
+    }
+}
+
+/// A flag for aborting a long-running walk over the trie, such as `verify_integrity_cancellable`,
+/// from another thread. Clones share the same flag.
+///
+/// The walk checks the token before each node it visits and fails with
+/// `TrieError::Cancelled` once it is cancelled.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signals every operation using this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> TrieResult<()> {
+        if self.is_cancelled() {
+            Err(TrieError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 enum EncodedNode {
@@ -107,23 +415,218 @@ impl From<Node> for TraceNode {
     }
 }
 
-pub struct TrieIterator<'a, D>
+pub struct TrieIterator<'a, D, H = KeccakHasher>
 where
     D: DB,
+    H: Hasher,
 {
-    trie: &'a EthTrie<D>,
+    trie: &'a EthTrie<D, H>,
     nibble: Nibbles,
     nodes: Vec<TraceNode>,
+    generation: u64,
+    error: Option<TrieError>,
+    // Nodes whose path is longer than this many nibbles are skipped
+    max_depth: usize,
+    token: CancellationToken,
+}
+
+impl<'a, D, H> TrieIterator<'a, D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Returns the error that ended the iteration early, if any.
+    ///
+    /// Iteration stops with `TrieError::StaleIterator` once this trie, or a trie sharing its
+    /// history through `at_root`, commits while the iterator is alive. Such a commit may
+    /// remove nodes the iterator has not visited yet, so no further entries are yielded.
+    /// Tries created separately with `EthTrie::new` on the same db are not tracked.
+    ///
+    /// It stops with `TrieError::Cancelled` once the token passed to `cancellable` is
+    /// cancelled.
+    pub fn error(&self) -> Option<&TrieError> {
+        self.error.as_ref()
+    }
+
+    /// Makes the iterator check `token` before each node it visits, and stop with
+    /// `TrieError::Cancelled` once it is cancelled.
+    pub fn cancellable(mut self, token: &CancellationToken) -> Self {
+        self.token = token.clone();
+        self
+    }
+
+    // Positions the iterator before the first key under `node` that is not smaller than
+    // `start`, given as the nibbles of the start key that remain below `node`. The nodes on
+    // the way down are pushed with the status they would have after visiting everything
+    // smaller, so `next` carries on from there.
+    fn seek(&mut self, mut node: Node, mut start: &[u8]) -> TrieResult<()> {
+        loop {
+            match node {
+                Node::Empty => return Ok(()),
+                Node::Hash(ref hash_node) => {
+                    let node_hash = hash_node.hash;
+                    match self.trie.recover_from_db(node_hash)? {
+                        Some(n) => node = n,
+                        None => {
+                            warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
+                            return Ok(());
+                        }
+                    }
+                }
+                Node::Leaf(ref leaf) => {
+                    let key = leaf.key.get_data();
+                    if &key[..key.len() - 1] >= start {
+                        self.nodes.push(node.clone().into());
+                    }
+                    return Ok(());
+                }
+                Node::Branch(ref branch) => {
+                    if start.is_empty() {
+                        self.nodes.push(node.clone().into());
+                        return Ok(());
+                    }
+                    // The value of the branch is smaller than start, and so are the
+                    // children before start[0]
+                    let index = start[0];
+                    let child = branch.read().unwrap().children[index as usize].clone();
+                    let mut trace: TraceNode = node.clone().into();
+                    trace.status = TraceStatus::Child(index);
+                    trace.advance();
+                    self.nodes.push(trace);
+                    self.nibble.push(index);
+
+                    node = child;
+                    start = &start[1..];
+                }
+                Node::Extension(ref ext) => {
+                    let (prefix, child) = {
+                        let borrow_ext = ext.read().unwrap();
+                        (borrow_ext.prefix.clone(), borrow_ext.node.clone())
+                    };
+                    let prefix_data = prefix.get_data();
+                    let match_len = prefix_data
+                        .iter()
+                        .zip(start.iter())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if match_len < prefix_data.len() {
+                        // The whole subtree is either smaller or larger than start
+                        if match_len == start.len() || prefix_data[match_len] > start[match_len] {
+                            self.nodes.push(node.clone().into());
+                        }
+                        return Ok(());
+                    }
+                    let mut trace: TraceNode = node.clone().into();
+                    trace.status = TraceStatus::End;
+                    self.nodes.push(trace);
+                    self.nibble.extend(&prefix);
+
+                    node = child;
+                    start = &start[match_len..];
+                }
+            }
+        }
+    }
+
+    // Positions the iterator on the subtree holding the keys under `node` that start with
+    // `prefix`, given as the nibbles of the prefix that remain below `node`. Only that
+    // subtree is pushed, with `nibble` seeded with the path to it, so iteration ends once
+    // the subtree is done.
+    fn seek_prefix(&mut self, mut node: Node, mut prefix: &[u8]) -> TrieResult<()> {
+        loop {
+            match node {
+                Node::Empty => return Ok(()),
+                Node::Hash(ref hash_node) => {
+                    let node_hash = hash_node.hash;
+                    match self.trie.recover_from_db(node_hash)? {
+                        Some(n) => node = n,
+                        None => {
+                            warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
+                            return Ok(());
+                        }
+                    }
+                }
+                Node::Leaf(ref leaf) => {
+                    let key = leaf.key.get_data();
+                    if key[..key.len() - 1].starts_with(prefix) {
+                        self.nodes.push(node.clone().into());
+                    }
+                    return Ok(());
+                }
+                Node::Branch(ref branch) => {
+                    if prefix.is_empty() {
+                        self.nodes.push(node.clone().into());
+                        return Ok(());
+                    }
+                    let index = prefix[0];
+                    let child = branch.read().unwrap().children[index as usize].clone();
+                    self.nibble.push(index);
+
+                    node = child;
+                    prefix = &prefix[1..];
+                }
+                Node::Extension(ref ext) => {
+                    let (ext_prefix, child) = {
+                        let borrow_ext = ext.read().unwrap();
+                        (borrow_ext.prefix.clone(), borrow_ext.node.clone())
+                    };
+                    let ext_data = ext_prefix.get_data();
+                    if ext_data.starts_with(prefix) {
+                        // The prefix ends inside the extension, so every key below matches
+                        self.nodes.push(node.clone().into());
+                        return Ok(());
+                    }
+                    if !prefix.starts_with(ext_data) {
+                        return Ok(());
+                    }
+                    self.nibble.extend(&ext_prefix);
+
+                    node = child;
+                    prefix = &prefix[ext_data.len()..];
+                }
+            }
+        }
+    }
 }
 
-impl<'a, D> Iterator for TrieIterator<'a, D>
+impl<'a, D, H> Iterator for TrieIterator<'a, D, H>
 where
     D: DB,
+    H: Hasher,
 {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        let value = self.next_value()?;
+        Some((self.nibble.encode_raw().0, value))
+    }
+}
+
+impl<'a, D, H> TrieIterator<'a, D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    // Moves to the next entry and returns its value. The key of the entry is left in
+    // `nibble`, so callers that do not need it can skip encoding it.
+    fn next_value(&mut self) -> Option<Vec<u8>> {
+        self.next_guard().map(ValueGuard::into_vec)
+    }
+
+    // Like `next_value`, but shares the value with its leaf node instead of copying it.
+    fn next_guard(&mut self) -> Option<ValueGuard> {
+        if self.error.is_none() && self.trie.generation.load(Ordering::SeqCst) != self.generation {
+            self.error = Some(TrieError::StaleIterator);
+        }
+        if self.error.is_some() {
+            return None;
+        }
+
         loop {
+            if let Err(e) = self.token.check() {
+                self.error = Some(e);
+                return None;
+            }
             let mut now = self.nodes.last().cloned();
             if let Some(ref mut now) = now {
                 self.nodes.last_mut().unwrap().advance();
@@ -152,18 +655,24 @@ where
 
                     (TraceStatus::Doing, Node::Extension(ref ext)) => {
                         self.nibble.extend(&ext.read().unwrap().prefix);
-                        self.nodes.push((ext.read().unwrap().node.clone()).into());
+                        if self.nibble.len() <= self.max_depth {
+                            self.nodes.push((ext.read().unwrap().node.clone()).into());
+                        }
                     }
 
                     (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
                         self.nibble.extend(&leaf.key);
-                        return Some((self.nibble.encode_raw().0, leaf.value.clone()));
+                        return Some(ValueGuard {
+                            value: GuardedValue::Leaf(leaf.clone()),
+                        });
                     }
 
                     (TraceStatus::Doing, Node::Branch(ref branch)) => {
                         let value_option = branch.read().unwrap().value.clone();
                         if let Some(value) = value_option {
-                            return Some((self.nibble.encode_raw().0, value));
+                            return Some(ValueGuard {
+                                value: GuardedValue::Owned(value),
+                            });
                         } else {
                             continue;
                         }
@@ -171,18 +680,21 @@ where
 
                     (TraceStatus::Doing, Node::Hash(ref hash_node)) => {
                         let node_hash = hash_node.hash;
-                        if let Ok(n) = self.trie.recover_from_db(node_hash) {
-                            self.nodes.pop();
-                            match n {
-                                Some(node) => self.nodes.push(node.into()),
-                                None => {
-                                    warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
-                                    continue;
+                        match self.trie.recover_from_db(node_hash) {
+                            Ok(n) => {
+                                self.nodes.pop();
+                                match n {
+                                    Some(node) => self.nodes.push(node.into()),
+                                    None => {
+                                        warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
+                                        continue;
+                                    }
                                 }
                             }
-                        } else {
-                            //error!();
-                            return None;
+                            Err(e) => {
+                                self.error = Some(e);
+                                return None;
+                            }
                         }
                     }
 
@@ -193,8 +705,10 @@ where
                             self.nibble.pop();
                             self.nibble.push(i);
                         }
-                        self.nodes
-                            .push((branch.read().unwrap().children[i as usize].clone()).into());
+                        if self.nibble.len() <= self.max_depth {
+                            self.nodes
+                                .push((branch.read().unwrap().children[i as usize].clone()).into());
+                        }
                     }
 
                     (_, Node::Empty) => {
@@ -209,1221 +723,6675 @@ where
     }
 }
 
-impl<D> EthTrie<D>
+/// An iterator that takes ownership of a trie and yields its entries in key order, returned
+/// by `EthTrie::into_iter`.
+///
+/// Nodes that are not shared with another trie are taken apart as they are visited, so their
+/// values are moved out instead of copied. Nodes stored in the db are loaded as needed.
+#[derive(Debug)]
+pub struct TrieIntoIter<D, H = KeccakHasher>
 where
     D: DB,
+    H: Hasher,
 {
-    pub fn iter(&self) -> TrieIterator<D> {
-        let nodes = vec![(self.root.clone()).into()];
-        TrieIterator {
-            trie: self,
-            nibble: Nibbles::from_raw(&[], false),
-            nodes,
-        }
+    trie: EthTrie<D, H>,
+    // Nodes still to visit, with the path leading to each, the next one last
+    nodes: Vec<(Node, Nibbles)>,
+    error: Option<TrieError>,
+}
+
+impl<D, H> TrieIntoIter<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Returns the error that ended the iteration early, if any.
+    pub fn error(&self) -> Option<&TrieError> {
+        self.error.as_ref()
     }
-    pub fn new(db: Arc<D>) -> Self {
-        Self {
-            root: Node::Empty,
-            root_hash: keccak(&rlp::NULL_RLP.to_vec()),
+}
 
-            cache: HashMap::new(),
-            passing_keys: HashSet::new(),
-            gen_keys: HashSet::new(),
+impl<D, H> Iterator for TrieIntoIter<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    type Item = (Vec<u8>, Vec<u8>);
 
-            db,
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        while let Some((node, path)) = self.nodes.pop() {
+            match node {
+                Node::Empty => {}
+                Node::Leaf(leaf) => {
+                    let leaf = Arc::try_unwrap(leaf).unwrap_or_else(|leaf| LeafNode {
+                        key: leaf.key.clone(),
+                        value: leaf.value.clone(),
+                    });
+                    return Some((path.join(&leaf.key).encode_raw().0, leaf.value));
+                }
+                Node::Branch(branch) => {
+                    let mut branch = match Arc::try_unwrap(branch) {
+                        Ok(branch) => branch.into_inner().unwrap(),
+                        Err(branch) => {
+                            let borrow_branch = branch.read().unwrap();
+                            BranchNode {
+                                children: borrow_branch.children.clone(),
+                                value: borrow_branch.value.clone(),
+                            }
+                        }
+                    };
+                    for (i, child) in branch.children.iter_mut().enumerate().rev() {
+                        let child = std::mem::replace(child, Node::Empty);
+                        if !matches!(child, Node::Empty) {
+                            self.nodes
+                                .push((child, path.join(&Nibbles::from_hex(&[i as u8]))));
+                        }
+                    }
+                    if let Some(value) = branch.value {
+                        return Some((path.encode_raw().0, value));
+                    }
+                }
+                Node::Extension(ext) => {
+                    let (prefix, child) = match Arc::try_unwrap(ext) {
+                        Ok(ext) => {
+                            let ext = ext.into_inner().unwrap();
+                            (ext.prefix, ext.node)
+                        }
+                        Err(ext) => {
+                            let borrow_ext = ext.read().unwrap();
+                            (borrow_ext.prefix.clone(), borrow_ext.node.clone())
+                        }
+                    };
+                    self.nodes.push((child, path.join(&prefix)));
+                }
+                Node::Hash(hash_node) => {
+                    let node_hash = hash_node.hash;
+                    match self.trie.recover_from_db(node_hash) {
+                        Ok(Some(node)) => self.nodes.push((node, path)),
+                        Ok(None) => {
+                            warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
+                        }
+                        Err(e) => {
+                            self.error = Some(e);
+                            return None;
+                        }
+                    }
+                }
+            }
         }
+        None
     }
+}
 
-    pub fn at_root(&self, root_hash: H256) -> Self {
-        Self {
-            root: Node::from_hash(root_hash),
-            root_hash,
-
-            cache: HashMap::new(),
-            passing_keys: HashSet::new(),
-            gen_keys: HashSet::new(),
+/// Consumes the trie, yielding its entries in key order, including the changes that are not
+/// committed yet. See `TrieIntoIter`.
+impl<D, H> IntoIterator for EthTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    type Item = (Vec<u8>, Vec<u8>);
+    type IntoIter = TrieIntoIter<D, H>;
 
-            db: self.db.clone(),
+    fn into_iter(mut self) -> TrieIntoIter<D, H> {
+        let root = std::mem::replace(&mut self.root, Node::Empty);
+        TrieIntoIter {
+            trie: self,
+            nodes: vec![(root, Nibbles::from_hex(&[]))],
+            error: None,
         }
     }
 }
 
-impl<D> Trie<D> for EthTrie<D>
+impl<D, H> EthTrie<D, H>
 where
     D: DB,
+    H: Hasher,
 {
-    /// Returns the value for key stored in the trie.
-    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
-        let path = &Nibbles::from_raw(key, true);
-        let result = self.get_at(&self.root, path, 0);
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
-        } else {
-            result
+    pub fn iter(&self) -> TrieIterator<'_, D, H> {
+        let nodes = vec![(self.root.clone()).into()];
+        TrieIterator {
+            trie: self,
+            nibble: Nibbles::from_raw(&[], false),
+            nodes,
+            generation: self.generation.load(Ordering::SeqCst),
+            error: None,
+            max_depth: usize::MAX,
+            token: CancellationToken::new(),
         }
     }
 
-    /// Checks that the key is present in the trie
-    fn contains(&self, key: &[u8]) -> TrieResult<bool> {
-        let path = &Nibbles::from_raw(key, true);
-        Ok(self.get_at(&self.root, path, 0)?.map_or(false, |_| true))
+    /// Iterates over the entries in key order like `iter`, but only descends to the nodes
+    /// whose path from the root is at most `max_depth` nibbles long. Values stored in
+    /// deeper nodes are skipped without loading those nodes from the db, so this gives a
+    /// quick look at the top levels of a large trie.
+    pub fn iter_max_depth(&self, max_depth: usize) -> TrieIterator<'_, D, H> {
+        let mut iter = self.iter();
+        iter.max_depth = max_depth;
+        iter
     }
 
-    /// Inserts value into trie and modifies it if it exists
-    fn insert(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
-        if value.is_empty() {
-            self.remove(key)?;
-            return Ok(());
-        }
-        let root = self.root.clone();
-        let path = &Nibbles::from_raw(key, true);
-        let result = self.insert_at(root, path, 0, value.to_vec());
-
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
-        } else {
-            self.root = result?;
-            Ok(())
+    /// Iterates over the entries in key order, starting at the first key that is equal to
+    /// or greater than `start`. Keys are ordered byte by byte, with a key sorting before the
+    /// keys it is a prefix of, the same order `iter` yields them in.
+    pub fn iter_from(&self, start: &[u8]) -> TrieIterator<'_, D, H> {
+        let mut iter = TrieIterator {
+            trie: self,
+            nibble: Nibbles::from_raw(&[], false),
+            nodes: vec![],
+            generation: self.generation.load(Ordering::SeqCst),
+            error: None,
+            max_depth: usize::MAX,
+            token: CancellationToken::new(),
+        };
+        let start = Nibbles::from_raw(start, false);
+        if let Err(e) = iter.seek(self.root.clone(), start.get_data()) {
+            iter.error = Some(e);
         }
+        iter
     }
 
-    /// Removes any existing value for key from the trie.
-    fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
-        let path = &Nibbles::from_raw(key, true);
-        let result = self.delete_at(&self.root.clone(), path, 0);
+    /// Iterates over the entries whose key starts with `prefix`, in key order. Only the
+    /// subtree below the prefix is visited, and the yielded keys are complete keys
+    /// including the prefix.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TrieIterator<'_, D, H> {
+        let mut iter = TrieIterator {
+            trie: self,
+            nibble: Nibbles::from_raw(&[], false),
+            nodes: vec![],
+            generation: self.generation.load(Ordering::SeqCst),
+            error: None,
+            max_depth: usize::MAX,
+            token: CancellationToken::new(),
+        };
+        let prefix = Nibbles::from_raw(prefix, false);
+        if let Err(e) = iter.seek_prefix(self.root.clone(), prefix.get_data()) {
+            iter.error = Some(e);
+        }
+        iter
+    }
 
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
+    /// Removes the value for key, like `remove`, but returns `TrieError::KeyNotFound`
+    /// instead of false if the key does not exist. The trie is left unchanged then.
+    pub fn remove_existing(&mut self, key: &[u8]) -> TrieResult<()> {
+        if self.remove(key)? {
+            Ok(())
         } else {
-            let (n, removed) = result?;
-            self.root = n;
-            Ok(removed)
+            Err(TrieError::KeyNotFound)
         }
     }
 
-    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
-    /// Returns the root hash of the trie.
-    fn root_hash(&mut self) -> TrieResult<H256> {
-        self.commit()
+    /// Removes every entry whose key starts with `prefix`, returning the number of entries
+    /// removed. The nodes that held them are freed from the db on the next commit.
+    pub fn remove_prefix(&mut self, prefix: &[u8]) -> TrieResult<usize> {
+        let prefix = &Nibbles::from_raw(prefix, false);
+        let root = self.root.clone();
+        let (root, removed) = self.remove_prefix_at(root, prefix, 0)?;
+        self.root = root;
+        Ok(removed)
     }
 
-    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
-    /// on the path to the value at key. The value itself is also included in the last
-    /// node and can be retrieved by verifying the proof.
-    ///
-    /// If the trie does not contain a value for key, the returned proof contains all
-    /// nodes of the longest existing prefix of the key (at least the root node), ending
-    /// with the node that proves the absence of the key.
-    fn get_proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
-        let key_path = &Nibbles::from_raw(key, true);
-        let result = self.get_path_at(&self.root, key_path, 0);
+    /// Returns true if any key starting with `prefix` exists. Only the nodes on the path of
+    /// the prefix are visited.
+    pub fn contains_prefix(&self, prefix: &[u8]) -> TrieResult<bool> {
+        let path = &Nibbles::from_raw(prefix, false);
+        with_err_key(self.contains_prefix_at(&self.root, path, 0), prefix)
+    }
 
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
-        } else {
-            let path = result?;
-            Ok(path
-                .into_iter()
-                .rev()
-                .map(|n| self.encode_raw(&n))
-                .collect())
+    /// Counts the entries of the trie by walking it, including values stored in branch
+    /// nodes.
+    pub fn len(&self) -> TrieResult<usize> {
+        let mut iter = self.iter();
+        let count = iter.by_ref().count();
+        match iter.error.take() {
+            Some(err) => Err(err),
+            None => Ok(count),
         }
     }
 
-    /// return value if key exists, None if key not exist, Error if proof is wrong
-    fn verify_proof(
-        &self,
-        root_hash: H256,
-        key: &[u8],
-        proof: Vec<Vec<u8>>,
-    ) -> TrieResult<Option<Vec<u8>>> {
-        let proof_db = Arc::new(MemoryDB::new(true));
-        for node_encoded in proof.into_iter() {
-            let hash = keccak(&node_encoded);
-
-            if root_hash.eq(&hash) || node_encoded.len() >= HASHED_LENGTH {
-                proof_db.insert(hash.as_bytes(), node_encoded).unwrap();
+    /// Counts the entries whose key is in the range `[start, end)`, in the order `iter`
+    /// yields them. The walk starts at `start` like `iter_from` and stops at the first key
+    /// that is not below `end`, and the values are not copied.
+    pub fn count_range(&self, start: &[u8], end: &[u8]) -> TrieResult<usize> {
+        if start >= end {
+            return Ok(0);
+        }
+        let mut iter = self.iter_from(start);
+        let mut count = 0;
+        while iter.next_guard().is_some() {
+            if iter.nibble.encode_raw().0.as_slice() >= end {
+                break;
             }
+            count += 1;
+        }
+        match iter.error.take() {
+            Some(err) => Err(err),
+            None => Ok(count),
         }
-        let trie = EthTrie::new(proof_db).at_root(root_hash);
-        trie.get(key).or(Err(TrieError::InvalidProof))
     }
-}
-
-impl<D> EthTrie<D>
-where
-    D: DB,
-{
-    fn get_at(
-        &self,
-        source_node: &Node,
-        path: &Nibbles,
-        path_index: usize,
-    ) -> TrieResult<Option<Vec<u8>>> {
-        let partial = &path.offset(path_index);
-        match source_node {
-            Node::Empty => Ok(None),
-            Node::Leaf(leaf) => {
-                if &leaf.key == partial {
-                    Ok(Some(leaf.value.clone()))
-                } else {
-                    Ok(None)
-                }
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(borrow_branch.value.clone())
-                } else {
-                    let index = partial.at(0);
-                    self.get_at(&borrow_branch.children[index], path, path_index + 1)
-                }
-            }
-            Node::Extension(extension) => {
-                let extension = extension.read().unwrap();
+    /// Returns a read-only handle to the root node, to walk the trie node by node. After a
+    /// commit the root has been loaded from the db, and its children are hash nodes to be
+    /// loaded with `NodeRef::resolve`.
+    pub fn root_node(&self) -> NodeRef {
+        NodeRef::new(self.root.clone())
+    }
 
-                let prefix = &extension.prefix;
-                let match_len = partial.common_prefix(prefix);
-                if match_len == prefix.len() {
-                    self.get_at(&extension.node, path, path_index + match_len)
-                } else {
-                    Ok(None)
-                }
-            }
-            Node::Hash(hash_node) => {
-                let node_hash = hash_node.hash;
-                let node =
-                    self.recover_from_db(node_hash)?
-                        .ok_or_else(|| TrieError::MissingTrieNode {
-                            node_hash,
-                            traversed: Some(path.slice(0, path_index)),
-                            root_hash: Some(self.root_hash),
-                            err_key: None,
-                        })?;
-                self.get_at(&node, path, path_index)
-            }
+    /// Returns true if the trie holds no entries, including changes that are not committed
+    /// yet.
+    pub fn is_empty(&self) -> bool {
+        match &self.root {
+            Node::Empty => true,
+            Node::Hash(hash_node) => hash_node.hash == empty_root::<H>(),
+            _ => false,
         }
     }
 
-    fn insert_at(
-        &mut self,
-        n: Node,
-        path: &Nibbles,
-        path_index: usize,
-        value: Vec<u8>,
-    ) -> TrieResult<Node> {
-        let partial = path.offset(path_index);
-        match n {
-            Node::Empty => Ok(Node::from_leaf(partial, value)),
-            Node::Leaf(leaf) => {
-                let old_partial = &leaf.key;
-                let match_index = partial.common_prefix(old_partial);
-                if match_index == old_partial.len() {
-                    return Ok(Node::from_leaf(leaf.key.clone(), value));
-                }
+    /// Iterates over the keys in order, like `iter` without the values.
+    pub fn keys(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
 
-                let mut branch = BranchNode {
-                    children: empty_children(),
-                    value: None,
-                };
+    /// Iterates over the values in key order, like `iter` without building the keys.
+    pub fn values(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || iter.next_value())
+    }
 
-                let n = Node::from_leaf(old_partial.offset(match_index + 1), leaf.value.clone());
-                branch.insert(old_partial.at(match_index), n);
+    /// Iterates over the entries in key order like `iter`, but yields each value as a
+    /// `ValueGuard` instead of a copy. A value stored in a leaf node is shared with that
+    /// node, so large values are not copied just to be read.
+    ///
+    /// The guard owns its share of the value and is independent of the trie and the
+    /// iterator: it can be kept after both are dropped, and keeps the value it was yielded
+    /// with even if the trie is changed afterwards.
+    pub fn iter_refs(&self) -> impl Iterator<Item = (Vec<u8>, ValueGuard)> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || {
+            let value = iter.next_guard()?;
+            Some((iter.nibble.encode_raw().0, value))
+        })
+    }
 
-                let n = Node::from_leaf(partial.offset(match_index + 1), value);
-                branch.insert(partial.at(match_index), n);
+    /// Iterates over the entries in key order like `iter`, but yields the nibble path of each
+    /// entry instead of its byte key. Every path ends with the leaf terminator, as built by
+    /// `Nibbles::from_raw(key, true)`, so `encode_raw` turns it back into the key.
+    pub fn iter_nibbles(&self) -> impl Iterator<Item = (Nibbles, Vec<u8>)> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || {
+            let value = iter.next_value()?;
+            // Values stored in branch nodes have no terminator in their path yet
+            Some((leaf_path(&iter.nibble), value))
+        })
+    }
 
-                if match_index == 0 {
-                    return Ok(Node::Branch(Arc::new(RwLock::new(branch))));
-                }
+    /// Iterates over the entries in key order for as long as `pred` holds for the key,
+    /// stopping at the first key for which it returns false.
+    ///
+    /// Because the iterator yields keys in sorted order, this can scan a contiguous range
+    /// such as all keys sharing a prefix without visiting the rest of the trie.
+    pub fn iter_while<'a, F>(&'a self, mut pred: F) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a
+    where
+        F: FnMut(&[u8]) -> bool + 'a,
+    {
+        self.iter().take_while(move |(key, _)| pred(key))
+    }
 
-                // if include a common prefix
-                Ok(Node::from_extension(
-                    partial.slice(0, match_index),
-                    Node::Branch(Arc::new(RwLock::new(branch))),
-                ))
-            }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.write().unwrap();
+    /// Creates an empty trie whose nodes are hashed with H instead of keccak, as in
+    /// `EthTrie::<_, MyHasher>::with_hasher(db)`. Its root hashes differ from those of an
+    /// Ethereum trie with the same entries, and its proofs are only accepted by the
+    /// `verify_proof` method of a trie with the same hasher.
+    ///
+    /// The associated functions that work on a bare db, such as `open_latest` and
+    /// `validate_store`, assume keccak.
+    pub fn with_hasher(db: Arc<D>) -> Self {
+        Self {
+            root: Node::Empty,
+            root_hash: empty_root::<H>(),
 
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = Some(value);
-                    return Ok(Node::Branch(branch.clone()));
-                }
+            cache: HashMap::new(),
+            passing_keys: HashSet::new(),
+            gen_keys: HashSet::new(),
 
-                let child = borrow_branch.children[partial.at(0)].clone();
-                let new_child = self.insert_at(child, path, path_index + 1, value)?;
-                borrow_branch.children[partial.at(0)] = new_child;
-                Ok(Node::Branch(branch.clone()))
-            }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.write().unwrap();
+            db,
+            delete_on_empty_value: true,
+            strict_keys: false,
+            root_history: 0,
+            generation: Arc::new(AtomicU64::new(0)),
+            node_fetcher: None,
+            hasher: PhantomData,
+        }
+    }
 
-                let prefix = &borrow_ext.prefix;
-                let sub_node = borrow_ext.node.clone();
-                let match_index = partial.common_prefix(prefix);
+    pub fn at_root(&self, root_hash: H256) -> Self {
+        Self {
+            root: Node::from_hash(root_hash),
+            root_hash,
 
-                if match_index == 0 {
-                    let mut branch = BranchNode {
-                        children: empty_children(),
-                        value: None,
-                    };
-                    branch.insert(
-                        prefix.at(0),
-                        if prefix.len() == 1 {
-                            sub_node
-                        } else {
-                            Node::from_extension(prefix.offset(1), sub_node)
-                        },
-                    );
-                    let node = Node::Branch(Arc::new(RwLock::new(branch)));
+            cache: HashMap::new(),
+            passing_keys: HashSet::new(),
+            gen_keys: HashSet::new(),
 
-                    return self.insert_at(node, path, path_index, value);
-                }
+            db: self.db.clone(),
+            delete_on_empty_value: self.delete_on_empty_value,
+            strict_keys: self.strict_keys,
+            root_history: self.root_history,
+            generation: self.generation.clone(),
+            node_fetcher: self.node_fetcher.clone(),
+            hasher: PhantomData,
+        }
+    }
 
-                if match_index == prefix.len() {
-                    let new_node =
-                        self.insert_at(sub_node, path, path_index + match_index, value)?;
-                    return Ok(Node::from_extension(prefix.clone(), new_node));
-                }
+    /// Returns a trie rooted at the subtree holding all keys that start with `prefix`, and
+    /// the nibbles of the prefix that was stripped from them. Keys in the returned trie are
+    /// relative to the prefix: the value of `prefix ++ key` in this trie is the value of
+    /// `key` in the subtree. The subtree shares this trie's db.
+    ///
+    /// Returns None if no key starts with `prefix`.
+    pub fn extract_subtree(&self, prefix: &[u8]) -> TrieResult<Option<(EthTrie<D, H>, Nibbles)>> {
+        let prefix = Nibbles::from_raw(prefix, false);
+        let subtree = match self.subtree_at(&self.root, &prefix, 0)? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        Ok(Some((self.with_root_node(subtree.deep_clone())?, prefix)))
+    }
 
-                let new_ext = Node::from_extension(prefix.offset(match_index), sub_node);
-                let new_node = self.insert_at(new_ext, path, path_index + match_index, value)?;
-                borrow_ext.prefix = prefix.slice(0, match_index);
-                borrow_ext.node = new_node;
-                Ok(Node::Extension(ext.clone()))
-            }
+    /// Returns the root hash of every subtree whose prefix is exactly `depth` nibbles long,
+    /// with that prefix, in key order. Each hash is the root hash the subtree would have as
+    /// a trie of its own, as returned for it by `extract_subtree`, including for subtrees
+    /// small enough to be embedded in their parent.
+    ///
+    /// Only the nodes above `depth` are loaded from the db. Changes that are not committed
+    /// yet are taken into account.
+    pub fn subtree_roots(&self, depth: usize) -> TrieResult<Vec<(Nibbles, H256)>> {
+        let mut roots = vec![];
+        self.subtree_roots_at(&self.root, Nibbles::from_hex(&[]), depth, &mut roots)?;
+        Ok(roots)
+    }
+
+    /// Returns a trie holding every entry of this trie under `prefix ++ key`. The values are
+    /// unchanged.
+    ///
+    /// The existing nodes are reused: the current root is wrapped in, or merged into, a
+    /// single extension or leaf node, so committing the result only writes that new node
+    /// plus any changes of this trie that are not committed yet. The result shares this
+    /// trie's db.
+    pub fn with_prefix(&self, prefix: &[u8]) -> TrieResult<EthTrie<D, H>> {
+        let prefix = Nibbles::from_raw(prefix, false);
+        let (root, stored_hash) = match &self.root {
             Node::Hash(hash_node) => {
                 let node_hash = hash_node.hash;
-                self.passing_keys.insert(node_hash.as_bytes().to_vec());
-                let node =
-                    self.recover_from_db(node_hash)?
-                        .ok_or_else(|| TrieError::MissingTrieNode {
-                            node_hash,
-                            traversed: Some(path.slice(0, path_index)),
-                            root_hash: Some(self.root_hash),
-                            err_key: None,
-                        })?;
-                self.insert_at(node, path, path_index, value)
+                let root = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: None,
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                (root, Some(node_hash))
             }
-        }
-    }
+            root => (root.deep_clone(), None),
+        };
 
-    fn delete_at(
-        &mut self,
-        old_node: &Node,
-        path: &Nibbles,
-        path_index: usize,
-    ) -> TrieResult<(Node, bool)> {
-        let partial = &path.offset(path_index);
-        let (new_node, deleted) = match old_node {
-            Node::Empty => Ok((Node::Empty, false)),
-            Node::Leaf(leaf) => {
-                if &leaf.key == partial {
-                    return Ok((Node::Empty, true));
+        let new_root = if prefix.is_empty() {
+            root
+        } else {
+            match root {
+                Node::Empty => Node::Empty,
+                Node::Leaf(leaf) => Node::from_leaf(prefix.join(&leaf.key), leaf.value.clone()),
+                Node::Extension(ext) => {
+                    let borrow_ext = ext.read().unwrap();
+                    Node::from_extension(prefix.join(&borrow_ext.prefix), borrow_ext.node.clone())
+                }
+                root => {
+                    // A stored root too large to be inlined is referred to by its hash, so
+                    // it is not written again
+                    let child = match stored_hash {
+                        Some(node_hash)
+                            if encode_node_raw_with::<H>(&root).len() >= HASHED_LENGTH =>
+                        {
+                            Node::from_hash(node_hash)
+                        }
+                        _ => root,
+                    };
+                    Node::from_extension(prefix, child)
                 }
-                Ok((Node::Leaf(leaf.clone()), false))
             }
-            Node::Branch(branch) => {
-                let mut borrow_branch = branch.write().unwrap();
+        };
+        self.with_root_node(new_root)
+    }
 
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = None;
-                    return Ok((Node::Branch(branch.clone()), true));
-                }
+    /// Sets a callback that is asked for the encoded node with a given hash whenever a node
+    /// is missing from the db, for example to fetch it from a peer. A fetched node is
+    /// written to the db and used as if it had been there. If the callback returns None,
+    /// or data that does not match the hash, the lookup fails with
+    /// `TrieError::MissingTrieNode` as before.
+    ///
+    /// The callback is shared with the tries created from this one, such as with
+    /// `at_root` or `clone`.
+    pub fn set_node_fetcher(&mut self, f: Box<dyn Fn(H256) -> Option<Vec<u8>> + Send + Sync>) {
+        self.node_fetcher = Some(Arc::new(NodeFetcher(f)));
+    }
 
-                let index = partial.at(0);
-                let child = &borrow_branch.children[index];
+    /// Returns true if the last committed root of this trie is the empty root. Changes that
+    /// are not committed yet are not taken into account.
+    pub fn is_empty_trie(&self) -> bool {
+        self.root_hash == empty_root::<H>()
+    }
 
-                let (new_child, deleted) = self.delete_at(child, path, path_index + 1)?;
-                if deleted {
-                    borrow_branch.children[index] = new_child;
-                }
+    /// Returns the root hash the trie would have if it were committed now, without writing
+    /// to the db or changing the trie. Nodes that were not changed are referred to by their
+    /// hash, so nothing is read from the db either.
+    pub fn compute_root(&self) -> TrieResult<H256> {
+        Ok(node_hash_with::<H>(&self.root))
+    }
 
-                Ok((Node::Branch(branch.clone()), deleted))
-            }
-            Node::Extension(ext) => {
-                let mut borrow_ext = ext.write().unwrap();
+    /// Commits the trie like `root_hash`, but keeps the in-memory nodes as they are instead
+    /// of reloading the root from the db. This saves a db read and a decode when the trie is
+    /// about to be dropped.
+    ///
+    /// The trie stays usable, but the kept nodes are treated as new: the next commit encodes
+    /// and writes all of them again, and nodes replaced by later changes to them are not
+    /// removed from the db. Prefer `root_hash` for a trie that keeps being changed.
+    pub fn commit_no_reload(&mut self) -> TrieResult<H256> {
+        let encoded = self.write_node(&self.root.clone());
+        let (root_hash, _) = self.flush_encoded(encoded, |_, _| Ok(()))?;
+        Ok(root_hash)
+    }
 
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+    /// Commits the trie like `root_hash` and reports which nodes the commit wrote to and
+    /// removed from the db. `written` holds the keys passed to `insert_batch` and `deleted`
+    /// the keys passed to `remove_batch`, both in no particular order. A db that keeps old
+    /// nodes may ignore the removal, so the deleted nodes can still be in the db.
+    pub fn commit_with_report(&mut self) -> TrieResult<CommitReport> {
+        let mut written = vec![];
+        let encoded = self.write_node(&self.root.clone());
+        let (root, removed_keys) = self.flush_encoded(encoded, |hash, _| {
+            written.push(H256::from_slice(hash));
+            Ok(())
+        })?;
+        self.root = self
+            .recover_from_db(root)?
+            .expect("The root that was just created is missing");
+        Ok(CommitReport {
+            root,
+            written,
+            deleted: removed_keys.iter().map(|k| H256::from_slice(k)).collect(),
+        })
+    }
 
-                if match_len == prefix.len() {
-                    let (new_node, deleted) =
-                        self.delete_at(&borrow_ext.node, path, path_index + match_len)?;
+    /// Commits the trie and returns the RLP encoding of its root node, whose hash is the
+    /// root hash. The encoding may be shorter than 32 bytes for a small trie, and is
+    /// `rlp::NULL_RLP` for an empty one.
+    pub fn root_node_rlp(&mut self) -> TrieResult<Vec<u8>> {
+        let root_hash = self.commit()?;
+        self.db
+            .get(root_hash.as_bytes())
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or(TrieError::MissingTrieNode {
+                node_hash: root_hash,
+                traversed: None,
+                root_hash: Some(root_hash),
+                err_key: None,
+            })
+    }
 
-                    if deleted {
-                        borrow_ext.node = new_node;
-                    }
+    /// Saves the current state of the trie, including changes that are not committed yet,
+    /// so that later changes can be undone with `rollback`.
+    ///
+    /// The in-memory nodes are copied, since changes modify them in place. A checkpoint only
+    /// covers changes made before the next commit: a commit may remove nodes from the db that
+    /// the saved state still refers to.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            root: self.root.deep_clone(),
+            root_hash: self.root_hash,
+            cache: self.cache.clone(),
+            passing_keys: self.passing_keys.clone(),
+            gen_keys: self.gen_keys.clone(),
+        }
+    }
 
-                    Ok((Node::Extension(ext.clone()), deleted))
-                } else {
-                    Ok((Node::Extension(ext.clone()), false))
-                }
-            }
-            Node::Hash(hash_node) => {
-                let hash = hash_node.hash;
-                self.passing_keys.insert(hash.as_bytes().to_vec());
+    /// Restores the state saved by `checkpoint`, discarding every change made since then.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.root = checkpoint.root;
+        self.root_hash = checkpoint.root_hash;
+        self.cache = checkpoint.cache;
+        self.passing_keys = checkpoint.passing_keys;
+        self.gen_keys = checkpoint.gen_keys;
+    }
 
-                let node =
-                    self.recover_from_db(hash)?
-                        .ok_or_else(|| TrieError::MissingTrieNode {
-                            node_hash: hash,
-                            traversed: Some(path.slice(0, path_index)),
-                            root_hash: Some(self.root_hash),
-                            err_key: None,
-                        })?;
-                self.delete_at(&node, path, path_index)
+    /// Returns a new trie at the root of this committed trie, sharing its db. The two tries
+    /// can then be changed and committed independently, and share the nodes they have in
+    /// common. Returns `TrieError::UncommittedChanges` if the trie has changes that are not
+    /// committed, or if its root node is not in the db, since the fork would not see them.
+    ///
+    /// With a db that deletes replaced nodes, such as `MemoryDB::new(true)`, a commit of
+    /// either trie may remove nodes the other still refers to. Use a db that keeps them.
+    pub fn fork(&self) -> TrieResult<Self> {
+        if self.compute_root()? != self.root_hash || !self.is_stored(self.root_hash)? {
+            return Err(TrieError::UncommittedChanges);
+        }
+        Ok(self.at_root(self.root_hash))
+    }
+
+    /// Returns a view of the trie that can only be read, to hand to code that must not
+    /// change it.
+    pub fn as_readonly(&self) -> ReadOnlyTrie<'_, D, H> {
+        ReadOnlyTrie { trie: self }
+    }
+
+    /// Commits any pending changes and returns the trie as a `SealedTrie`, which can be read
+    /// and proven against but no longer modified.
+    pub fn seal(mut self) -> TrieResult<SealedTrie<D, H>> {
+        self.commit()?;
+        Ok(SealedTrie { trie: self })
+    }
+
+    /// Sets whether inserting an empty value removes the key, which is the default.
+    ///
+    /// When disabled, such an insert fails with `TrieError::EmptyValue` and leaves the trie
+    /// unchanged, so an empty value that slips in by accident cannot silently delete data.
+    pub fn with_delete_on_empty_value(mut self, delete_on_empty_value: bool) -> Self {
+        self.delete_on_empty_value = delete_on_empty_value;
+        self
+    }
+
+    /// Sets whether `insert` rejects a key that is a strict prefix of a key already in the
+    /// trie, or that has one as a strict prefix, with `TrieError::PrefixConflict`. Such keys
+    /// are accepted by default, with the value of the shorter key stored in a branch node.
+    /// The check is made on the way down the trie, so rejected inserts leave it unchanged.
+    pub fn with_strict_keys(mut self, strict_keys: bool) -> Self {
+        self.strict_keys = strict_keys;
+        self
+    }
+
+    /// Sets how many roots of past commits are recorded in the db, to be listed with
+    /// `EthTrie::root_history`. Every commit appends its root to the list, unless it is the
+    /// same as the last one, and the oldest roots are dropped once there are more than
+    /// `max_roots`. Recording is off by default, and a `max_roots` of 0 turns it off.
+    ///
+    /// The list is shared by all tries committing to the same db. Recording a root does not
+    /// keep its nodes from being removed by later commits to a db that deletes them.
+    pub fn with_root_history(mut self, max_roots: usize) -> Self {
+        self.root_history = max_roots;
+        self
+    }
+
+    /// Commits the trie like `root_hash`, additionally streaming every node flushed to the
+    /// db into `w`. Returns the new root hash.
+    ///
+    /// Each node is written as one record: the 32-byte node hash, the length of the encoded
+    /// node as a big-endian `u32`, then the RLP-encoded node itself. Only the nodes written
+    /// by this commit are emitted, so a stream taken from a trie opened with `at_root`
+    /// holds just the nodes that changed since that root.
+    pub fn commit_to_writer<W: Write>(&mut self, w: &mut W) -> TrieResult<H256> {
+        self.commit_with(|hash, data| {
+            w.write_all(hash)
+                .and_then(|_| w.write_all(&(data.len() as u32).to_be_bytes()))
+                .and_then(|_| w.write_all(data))
+                .map_err(|e| TrieError::Io(e.to_string()))
+        })
+    }
+
+    /// Serializes every node reachable from the last committed root into one blob, which
+    /// `EthTrie::import` turns back into a trie on any db. Changes that are not committed
+    /// yet are not included.
+    ///
+    /// The blob is an RLP list of `[hash, encoded node]` pairs, starting with the root.
+    pub fn export(&self) -> TrieResult<Vec<u8>> {
+        let mut nodes = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = vec![self.root_hash];
+        while let Some(hash) = pending.pop() {
+            if !seen.insert(hash) {
+                continue;
             }
-        }?;
+            let data = if hash == empty_root::<H>() {
+                rlp::NULL_RLP.to_vec()
+            } else {
+                self.db
+                    .get(hash.as_bytes())
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash: hash,
+                        traversed: None,
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?
+            };
+            decode_node_with_hash(hash, &data)?.child_hashes(&mut pending);
+            nodes.push((hash, data));
+        }
 
-        if deleted {
-            Ok((self.degenerate(new_node)?, deleted))
-        } else {
-            Ok((new_node, deleted))
+        let mut stream = RlpStream::new_list(nodes.len());
+        for (hash, data) in nodes {
+            stream.begin_list(2);
+            stream.append(&hash.as_bytes());
+            stream.append(&data);
         }
+        Ok(stream.out().to_vec())
     }
 
-    // This refactors the trie after a node deletion, as necessary.
-    // For example, if a deletion removes a child of a branch node, leaving only one child left, it
-    // needs to be modified into an extension and maybe combined with its parent and/or child node.
-    fn degenerate(&mut self, n: Node) -> TrieResult<Node> {
-        match n {
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
+    /// Moves the value stored at `from` to `to`, returning whether `from` was present.
+    ///
+    /// If `from` is absent the trie is left untouched. Any value already stored at `to` is
+    /// overwritten. On an error, such as a node missing from the db, the trie is also left
+    /// as it was, never with the value at both keys.
+    pub fn rename(&mut self, from: &[u8], to: &[u8]) -> TrieResult<bool> {
+        let mut root = self.root.clone();
+        let loaded = self.load_path(&mut root, &Nibbles::from_raw(from, true), 0);
+        self.root = root;
+        let value = match with_err_key(loaded, from)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if from != to {
+            let mut root = self.root.clone();
+            let loaded = self.load_path(&mut root, &Nibbles::from_raw(to, true), 0);
+            self.root = root;
+            with_err_key(loaded, to)?;
+            // Every node either step reads is loaded, so only a rejected key can fail the
+            // insert, before it changes anything, and the removal can't fail at all
+            self.insert(to, &value)?;
+            self.remove(from)?;
+        }
+        Ok(true)
+    }
 
-                let mut used_indexs = vec![];
-                for (index, node) in borrow_branch.children.iter().enumerate() {
-                    match node {
-                        Node::Empty => continue,
-                        _ => used_indexs.push(index),
-                    }
-                }
+    /// Estimates the number of bytes held in memory by the trie.
+    ///
+    /// This counts the size of every node currently loaded in memory (nodes that are
+    /// still only referenced by hash count as a single hash node), the key and value bytes
+    /// they hold, and the keys and encoded nodes waiting in the commit caches. Allocator
+    /// overhead and unused capacity are not included, so the result is an approximation.
+    /// Committing writes out the caches and reloads only the root node, which releases
+    /// most of this memory.
+    ///
+    /// Inserting a new key never decreases the estimate, so it can be compared against a
+    /// budget while a large batch is built up, to decide when to commit.
+    pub fn in_memory_size(&self) -> usize {
+        let cache_size: usize = self.cache.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let keys_size: usize = self
+            .gen_keys
+            .iter()
+            .chain(self.passing_keys.iter())
+            .map(|k| k.len())
+            .sum();
+        self.root.memory_size() + cache_size + keys_size
+    }
 
-                // if only a value node, transmute to leaf.
-                if used_indexs.is_empty() && borrow_branch.value.is_some() {
-                    let key = Nibbles::from_raw(&[], true);
-                    let value = borrow_branch.value.clone().unwrap();
-                    Ok(Node::from_leaf(key, value))
-                // if only one node. make an extension.
-                } else if used_indexs.len() == 1 && borrow_branch.value.is_none() {
-                    let used_index = used_indexs[0];
-                    let n = borrow_branch.children[used_index].clone();
+    /// Walks the whole trie once and counts its nodes by type and by how they are stored,
+    /// along with its depth and the size of its values. Nodes that are not committed yet
+    /// are classified by the length of their encoding, as the next commit would.
+    pub fn stats(&self) -> TrieResult<TrieStats> {
+        self.stats_cancellable(&CancellationToken::new())
+    }
 
-                    let new_node = Node::from_extension(Nibbles::from_hex(&[used_index as u8]), n);
-                    self.degenerate(new_node)
-                } else {
-                    Ok(Node::Branch(branch.clone()))
+    /// Runs `stats`, failing with `TrieError::Cancelled` once `token` is cancelled.
+    pub fn stats_cancellable(&self, token: &CancellationToken) -> TrieResult<TrieStats> {
+        let mut stats = TrieStats::default();
+        let path = &mut Nibbles::from_hex(&[]);
+        self.stats_at(&self.root, path, 1, true, &mut stats, token)?;
+        Ok(stats)
+    }
+
+    /// Looks up many keys at once, returning the value and proof of each key, in the order
+    /// of `keys`. Each proof has the same form as the output of `get_proof`.
+    ///
+    /// The keys are looked up in key order, in a single walk of the trie, and nodes shared
+    /// between the paths of several keys are only loaded from the db once.
+    #[allow(clippy::type_complexity)]
+    pub fn iter_with_proofs(
+        &self,
+        keys: &[&[u8]],
+    ) -> TrieResult<Vec<(Vec<u8>, Option<Vec<u8>>, Vec<Vec<u8>>)>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by_key(|&i| keys[i]);
+
+        let mut loaded = HashMap::new();
+        let mut results = vec![None; keys.len()];
+        for i in order {
+            let key = keys[i];
+            let key_path = &Nibbles::from_raw(key, true);
+            let path = with_err_key(self.get_path_at(&self.root, key_path, 0, &mut loaded), key)?;
+            let value = path_value(&path, key_path);
+            let proof = path.iter().rev().map(encode_node_raw_with::<H>).collect();
+            results[i] = Some((key.to_vec(), value, proof));
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Returns a single proof for all of `keys`: the union of the nodes of each key's proof,
+    /// from the root down, with every node included once.
+    ///
+    /// Nodes shorter than 32 bytes other than the root are left out, since they are
+    /// embedded in their parent. The result can be passed to `verify_proof` for any of the
+    /// keys.
+    pub fn get_proofs(&self, keys: &[&[u8]]) -> TrieResult<Vec<Vec<u8>>> {
+        let mut loaded = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut proof = vec![];
+        for key in keys {
+            let key_path = &Nibbles::from_raw(key, true);
+            let path = with_err_key(self.get_path_at(&self.root, key_path, 0, &mut loaded), key)?;
+            for (i, node) in path.iter().rev().enumerate() {
+                let encoded = encode_node_raw_with::<H>(node);
+                if (i == 0 || encoded.len() >= HASHED_LENGTH) && seen.insert(H::hash(&encoded)) {
+                    proof.push(encoded);
                 }
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
+        }
+        Ok(proof)
+    }
 
-                let prefix = &borrow_ext.prefix;
-                match borrow_ext.node.clone() {
-                    Node::Extension(sub_ext) => {
-                        let borrow_sub_ext = sub_ext.read().unwrap();
+    /// Returns the values for `keys`, in the same order, like calling `get` for each key.
+    ///
+    /// The keys are sorted and looked up in a single walk of the trie, so nodes shared by
+    /// several keys are visited, and loaded from the db, only once. A missing node is
+    /// reported for the first key, in sorted order, whose path goes through it.
+    pub fn get_many(&self, keys: &[&[u8]]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let mut values = vec![None; keys.len()];
+        self.visit_many(keys, |i, value| values[i] = Some(value.to_vec()))?;
+        Ok(values)
+    }
 
-                        let new_prefix = prefix.join(&borrow_sub_ext.prefix);
-                        let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
-                        self.degenerate(new_n)
-                    }
-                    Node::Leaf(leaf) => {
-                        let new_prefix = prefix.join(&leaf.key);
-                        Ok(Node::from_leaf(new_prefix, leaf.value.clone()))
-                    }
-                    // try again after recovering node from the db.
-                    Node::Hash(hash_node) => {
-                        let node_hash = hash_node.hash;
-                        self.passing_keys.insert(node_hash.as_bytes().to_vec());
+    /// Returns whether each of `keys` is present, in the same order, like calling `contains`
+    /// for each key. The trie is walked once for all keys as in `get_many`, and the values
+    /// are not copied.
+    pub fn contains_many(&self, keys: &[&[u8]]) -> TrieResult<Vec<bool>> {
+        let mut found = vec![false; keys.len()];
+        self.visit_many(keys, |i, _| found[i] = true)?;
+        Ok(found)
+    }
 
-                        let new_node =
-                            self.recover_from_db(node_hash)?
-                                .ok_or(TrieError::MissingTrieNode {
-                                    node_hash,
-                                    traversed: None,
-                                    root_hash: Some(self.root_hash),
-                                    err_key: None,
-                                })?;
+    // Looks up all keys in one walk of the trie, passing the index and value of each key
+    // that exists to on_value.
+    fn visit_many<F>(&self, keys: &[&[u8]], mut on_value: F) -> TrieResult<()>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut paths: Vec<(usize, Nibbles)> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (i, Nibbles::from_raw(key, true)))
+            .collect();
+        paths.sort_by(|a, b| a.1.get_data().cmp(b.1.get_data()));
+        self.get_many_at(&self.root, &paths, 0, keys, &mut on_value)
+    }
 
-                        let n = Node::from_extension(borrow_ext.prefix.clone(), new_node);
-                        self.degenerate(n)
-                    }
-                    _ => Ok(Node::Extension(ext.clone())),
+    /// Returns the value for key together with its proof, in the same form as the output of
+    /// `get` and `get_proof`, while only walking the trie once. If key is absent, the value
+    /// is None and the proof proves its absence.
+    #[allow(clippy::type_complexity)]
+    pub fn get_with_proof(&self, key: &[u8]) -> TrieResult<(Option<Vec<u8>>, Vec<Vec<u8>>)> {
+        let key_path = &Nibbles::from_raw(key, true);
+        let path = with_err_key(
+            self.get_path_at(&self.root, key_path, 0, &mut HashMap::new()),
+            key,
+        )?;
+
+        let value = path_value(&path, key_path);
+        let proof = path.iter().rev().map(encode_node_raw_with::<H>).collect();
+        Ok((value, proof))
+    }
+
+    /// Returns the proof for key like `get_proof`, with every node decoded into a
+    /// `ProofNode`. Encoding the nodes gives back the proof returned by `get_proof`.
+    pub fn get_proof_structured(&self, key: &[u8]) -> TrieResult<Vec<ProofNode>> {
+        let (proof, _) = self.get_proof_limited(key, usize::MAX)?;
+        proof.iter().map(|node| ProofNode::decode(node)).collect()
+    }
+
+    /// Returns the proof for key in the compact form checked by `verify_minimal`, which
+    /// leaves out the reference from every node on the path to the next one. See
+    /// `MinimalProof`.
+    pub fn get_proof_minimal(&self, key: &[u8]) -> TrieResult<MinimalProof> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut steps = vec![];
+        with_err_key(self.minimal_proof_at(&self.root, path, 0, &mut steps), key)?;
+        Ok(MinimalProof { steps })
+    }
+
+    /// Returns at most `max_nodes` nodes of the proof for key, starting from the root, and
+    /// whether that is the complete proof.
+    ///
+    /// A partial proof is always a prefix of the output of `get_proof`, so a client can
+    /// fetch a long proof in chunks by asking again with a larger limit.
+    pub fn get_proof_limited(
+        &self,
+        key: &[u8],
+        max_nodes: usize,
+    ) -> TrieResult<(Vec<Vec<u8>>, bool)> {
+        let key_path = &Nibbles::from_raw(key, true);
+        let path = with_err_key(
+            self.get_path_at(&self.root, key_path, 0, &mut HashMap::new()),
+            key,
+        )?;
+        let complete = path.len() <= max_nodes;
+        let proof = path
+            .iter()
+            .rev()
+            .take(max_nodes)
+            .map(encode_node_raw_with::<H>)
+            .collect();
+        Ok((proof, complete))
+    }
+
+    /// Returns the proof for key like `get_proof`, or `TrieError::ProofTooLarge` if its
+    /// nodes add up to more than `max_bytes`.
+    ///
+    /// The proof is built from the root down, and the nodes below the one that crosses the
+    /// limit are not loaded from the db.
+    pub fn get_proof_bounded(&self, key: &[u8], max_bytes: usize) -> TrieResult<Vec<Vec<u8>>> {
+        let key_path = &Nibbles::from_raw(key, true);
+        let mut proof = vec![];
+        let mut size = 0;
+        with_err_key(
+            self.get_proof_bounded_at(&self.root, key_path, 0, max_bytes, &mut size, &mut proof),
+            key,
+        )?;
+        Ok(proof)
+    }
+
+    /// Inserts value at key like `insert`, returning the value previously stored at key.
+    ///
+    /// An empty value removes the key, following the same rules as `insert`, and also
+    /// returns the removed value. Either way the old value is found on the same descent that
+    /// changes the trie.
+    pub fn replace(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<Option<Vec<u8>>> {
+        if value.is_empty() && !self.delete_on_empty_value {
+            return Err(TrieError::EmptyValue);
+        }
+        let root = self.root.clone();
+        let path = &Nibbles::from_raw(&key, true);
+        let (root, old_value) = if value.is_empty() {
+            with_err_key(self.delete_at(&root, path, 0), &key)?
+        } else {
+            with_err_key(self.insert_at(root, path, 0, value), &key)?
+        };
+        self.root = root;
+        Ok(old_value)
+    }
+
+    /// Inserts value at key, returning the value previously stored at key. The same as
+    /// `replace`.
+    pub fn insert_get(&mut self, key: Vec<u8>, value: Vec<u8>) -> TrieResult<Option<Vec<u8>>> {
+        self.replace(key, value)
+    }
+
+    /// Appends extra to the value stored at key, or inserts extra if key does not exist.
+    ///
+    /// The old value is read on the same descent that writes the new one. Appending nothing
+    /// leaves the trie unchanged, so an absent key is never given an empty value.
+    pub fn append(&mut self, key: &[u8], extra: &[u8]) -> TrieResult<()> {
+        if extra.is_empty() {
+            return Ok(());
+        }
+        let root = self.root.clone();
+        let path = &Nibbles::from_raw(key, true);
+        self.root = with_err_key(
+            self.update_at(root, path, 0, |old| {
+                let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+                value.extend_from_slice(extra);
+                value
+            }),
+            key,
+        )?
+        .0;
+        Ok(())
+    }
+
+    /// Estimates how many nodes the next commit would write to and remove from the db if
+    /// `ops` were applied, returned as `(nodes_added, nodes_removed)`. Each op inserts the
+    /// value at the key, or removes the key if the value is `None`.
+    ///
+    /// The ops are applied to a copy of the in-memory trie, which is left unchanged, and
+    /// nothing is written to the db. The counts include changes that are not committed yet.
+    /// A written node may already be present in the db, so `nodes_added` can overstate how
+    /// much the db grows.
+    pub fn estimate_batch_impact(
+        &self,
+        ops: &[(Vec<u8>, Option<Vec<u8>>)],
+    ) -> TrieResult<(usize, usize)> {
+        let mut trie = self.clone();
+        for (key, value) in ops {
+            match value {
+                Some(value) => trie.insert(key, value)?,
+                None => {
+                    trie.remove(key)?;
                 }
             }
-            _ => Ok(n),
         }
+
+        // The root is always stored, even when it is small enough to be inlined
+        let inline_root = match trie.write_node(&trie.root.clone()) {
+            EncodedNode::Hash(_) => 0,
+            EncodedNode::Inline(_) => 1,
+        };
+        let removed = trie
+            .passing_keys
+            .iter()
+            .filter(|h| !trie.gen_keys.contains(*h))
+            .count();
+        Ok((trie.cache.len() + inline_root, removed))
     }
 
-    // Get nodes path along the key, only the nodes whose encode length is greater than
-    // hash length are added.
-    // For embedded nodes whose data are already contained in their parent node, we don't need to
-    // add them in the path.
-    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
-    // all data stored in db, including nodes whose encoded data is less than hash length.
-    fn get_path_at(
-        &self,
-        source_node: &Node,
-        path: &Nibbles,
-        path_index: usize,
-    ) -> TrieResult<Vec<Node>> {
-        let partial = &path.offset(path_index);
-        match source_node {
-            Node::Empty => {
-                Ok(vec![])
-            },
-            Node::Leaf(_) => {
-                Ok(vec![source_node.clone()])
-            },
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
+    /// Returns the value for key like `get`, but without copying the value out of the trie
+    /// when it is stored in a leaf node. See `ValueGuard`.
+    pub fn get_guard(&self, key: &[u8]) -> TrieResult<Option<ValueGuard>> {
+        let path = &Nibbles::from_raw(key, true);
+        with_err_key(self.get_at(&self.root, path, 0, &mut 0), key)
+    }
 
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(vec![source_node.clone()])
-                } else {
-                    let node = &borrow_branch.children[partial.at(0)];
-                    let mut rest = self.get_path_at(&node, path, path_index + 1)?;
-                    rest.push(source_node.clone());
-                    Ok(rest)
-                }
+    /// Appends the value for key to `out`, returning whether key exists. Values stored in
+    /// leaf nodes are copied straight into `out` without an intermediate allocation, so a
+    /// caller can reuse one buffer across many reads.
+    pub fn get_into(&self, key: &[u8], out: &mut Vec<u8>) -> TrieResult<bool> {
+        match self.get_guard(key)? {
+            Some(value) => {
+                out.extend_from_slice(&value);
+                Ok(true)
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
+            None => Ok(false),
+        }
+    }
 
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+    /// Returns the value for key like `get`, but never reads from the db. If the path to the
+    /// value runs through a node that is not loaded in memory, the lookup fails with
+    /// `TrieError::NodeNotLoaded` holding its hash.
+    ///
+    /// Committing replaces the loaded nodes with references to the db, so this is only
+    /// useful for a trie whose nodes have not been committed since they were changed.
+    pub fn get_in_memory(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut node = self.root.clone();
+        let mut path_index = 0;
+        loop {
+            let partial = path.offset(path_index);
+            node = match &node {
+                Node::Empty => return Ok(None),
+                Node::Leaf(leaf) => {
+                    return Ok(if leaf.key == partial {
+                        Some(leaf.value.clone())
+                    } else {
+                        None
+                    });
+                }
+                Node::Branch(branch) => {
+                    let borrow_branch = branch.read().unwrap();
+                    if partial.is_empty() || partial.at(0) == 16 {
+                        return Ok(borrow_branch.value.clone());
+                    }
+                    path_index += 1;
+                    borrow_branch.children[partial.at(0)].clone()
+                }
+                Node::Extension(extension) => {
+                    let extension = extension.read().unwrap();
+                    let prefix = &extension.prefix;
+                    if partial.common_prefix(prefix) != prefix.len() {
+                        return Ok(None);
+                    }
+                    path_index += prefix.len();
+                    extension.node.clone()
+                }
+                Node::Hash(hash_node) => return Err(TrieError::NodeNotLoaded(hash_node.hash)),
+            };
+        }
+    }
 
-                if match_len == prefix.len() {
-                    let mut rest = self.get_path_at(&borrow_ext.node, path, path_index + match_len)?;
-                    rest.push(source_node.clone());
-                    Ok(rest)
-                } else {
-                    Ok(vec![source_node.clone()])
+    /// Loads every node holding keys that start with prefix from the db into memory, so that
+    /// later lookups under the prefix, including `get_in_memory`, read nothing from the db.
+    ///
+    /// The loaded nodes replace the references to them in the trie until the next commit,
+    /// which writes them back unchanged and turns them into references again.
+    pub fn preload_prefix(&mut self, prefix: &[u8]) -> TrieResult<()> {
+        let prefix = &Nibbles::from_raw(prefix, false);
+        let root = self.root.clone();
+        self.root = self.preload_at(root, prefix, 0)?;
+        Ok(())
+    }
+
+    /// Returns whether the node holding the value of key is embedded in its parent, which
+    /// is the case for nodes shorter than 32 bytes when encoded, or None if key does not
+    /// exist. The value holder is the leaf for the key, or the branch node for a value
+    /// stored in a branch. The root node is always stored by hash, so it is never inlined.
+    ///
+    /// An inlined node adds no entry of its own to a proof for key.
+    pub fn is_inlined(&self, key: &[u8]) -> TrieResult<Option<bool>> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut node = self.root.clone();
+        let mut path_index = 0;
+        let mut hashed = true;
+        loop {
+            let partial = path.offset(path_index);
+            let inlined = || !hashed && encode_node_raw_with::<H>(&node).len() < HASHED_LENGTH;
+            let (next, next_hashed) = match &node {
+                Node::Empty => return Ok(None),
+                Node::Leaf(leaf) => {
+                    return Ok(if leaf.key == partial {
+                        Some(inlined())
+                    } else {
+                        None
+                    });
+                }
+                Node::Branch(branch) => {
+                    let borrow_branch = branch.read().unwrap();
+                    if partial.is_empty() || partial.at(0) == 16 {
+                        return Ok(borrow_branch.value.as_ref().map(|_| inlined()));
+                    }
+                    path_index += 1;
+                    (borrow_branch.children[partial.at(0)].clone(), false)
+                }
+                Node::Extension(extension) => {
+                    let extension = extension.read().unwrap();
+                    let prefix = &extension.prefix;
+                    if partial.common_prefix(prefix) != prefix.len() {
+                        return Ok(None);
+                    }
+                    path_index += prefix.len();
+                    (extension.node.clone(), false)
+                }
+                Node::Hash(hash_node) => {
+                    let node_hash = hash_node.hash;
+                    let loaded = self.recover_from_db(node_hash)?.ok_or_else(|| {
+                        TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: Some(key.to_vec()),
+                        }
+                    })?;
+                    (loaded, true)
+                }
+            };
+            node = next;
+            hashed = next_hashed;
+        }
+    }
+
+    /// Returns the value for a 32-byte key, such as `keccak(address)` in the state trie.
+    pub fn get_h256(&self, key: H256) -> TrieResult<Option<Vec<u8>>> {
+        self.get(key.as_bytes())
+    }
+
+    /// Inserts value at a 32-byte key, like `insert`.
+    pub fn insert_h256(&mut self, key: H256, value: &[u8]) -> TrieResult<()> {
+        self.insert(key.as_bytes(), value)
+    }
+
+    /// Removes the value at a 32-byte key, returning whether it existed.
+    pub fn remove_h256(&mut self, key: H256) -> TrieResult<bool> {
+        self.remove(key.as_bytes())
+    }
+
+    /// Returns true if a 32-byte key is present.
+    pub fn contains_h256(&self, key: H256) -> TrieResult<bool> {
+        self.contains(key.as_bytes())
+    }
+
+    /// Returns the number of nodes on the path from the root to the value of key, counting
+    /// branch, extension and leaf nodes, including those loaded from the db. A value stored
+    /// in the root leaf has depth 1. Returns None if key does not exist.
+    pub fn depth_of(&self, key: &[u8]) -> TrieResult<Option<usize>> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut depth = 0;
+        let value = with_err_key(self.get_at(&self.root, path, 0, &mut depth), key)?;
+        Ok(value.map(|_| depth))
+    }
+
+    /// Returns the keys whose values differ between this trie and other, in key order, as
+    /// `(key, old_value, new_value)` triples where the old value comes from this trie and the
+    /// new value from other. A None old value means the key was added, a None new value that
+    /// it was removed.
+    ///
+    /// The two tries are walked side by side, and subtrees stored under the same hash in both
+    /// are skipped without being loaded, so the cost grows with the size of the change rather
+    /// than the size of the tries.
+    #[allow(clippy::type_complexity)]
+    pub fn diff(
+        &self,
+        other: &EthTrie<D, H>,
+    ) -> TrieResult<Vec<(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>> {
+        let mut changes = vec![];
+        let mut path = Nibbles::from_hex(&[]);
+        self.diff_at(
+            other,
+            self.root.clone(),
+            other.root.clone(),
+            &mut path,
+            &mut changes,
+        )?;
+        Ok(changes)
+    }
+
+    /// Inserts value at a key given as a nibble path, which unlike a byte key may have an
+    /// odd number of nibbles. The leaf terminator is added to path if it is missing. Empty
+    /// values are handled as in `insert`.
+    ///
+    /// Keys inserted this way can only be read back with `get_nibbles` and `iter_nibbles`,
+    /// unless path is the nibble path of a byte key. Likewise, byte keys are only found by
+    /// `get_nibbles` through their full nibble path. Since the key is not a byte string,
+    /// `MissingTrieNode` errors from these methods have no `err_key`; `traversed` still
+    /// holds the path.
+    pub fn insert_nibbles(&mut self, path: &Nibbles, value: &[u8]) -> TrieResult<()> {
+        if value.is_empty() {
+            if !self.delete_on_empty_value {
+                return Err(TrieError::EmptyValue);
+            }
+            self.remove_nibbles(path)?;
+            return Ok(());
+        }
+        let root = self.root.clone();
+        let path = &leaf_path(path);
+        self.root = self.insert_at(root, path, 0, value.to_vec())?.0;
+        Ok(())
+    }
+
+    /// Returns the value for a key given as a nibble path. See `insert_nibbles`.
+    pub fn get_nibbles(&self, path: &Nibbles) -> TrieResult<Option<Vec<u8>>> {
+        let path = &leaf_path(path);
+        Ok(self
+            .get_at(&self.root, path, 0, &mut 0)?
+            .map(ValueGuard::into_vec))
+    }
+
+    /// Removes the value for a key given as a nibble path, returning whether it existed.
+    /// See `insert_nibbles`.
+    pub fn remove_nibbles(&mut self, path: &Nibbles) -> TrieResult<bool> {
+        let path = &leaf_path(path);
+        let (n, removed) = self.delete_at(&self.root.clone(), path, 0)?;
+        self.root = n;
+        Ok(removed.is_some())
+    }
+
+    /// Walks the whole trie and compares its keys with `expected`.
+    pub fn verify_key_set(
+        &self,
+        expected: &std::collections::HashSet<Vec<u8>>,
+    ) -> TrieResult<KeySetDiff> {
+        let mut diff = KeySetDiff::default();
+        let mut found = HashSet::new();
+
+        let mut iter = self.iter();
+        for (key, _) in &mut iter {
+            if !expected.contains(&key) {
+                diff.extra.push(key.clone());
+            }
+            found.insert(key);
+        }
+        if let Some(err) = iter.error.take() {
+            return Err(err);
+        }
+
+        diff.missing = expected
+            .iter()
+            .filter(|key| !found.contains(*key))
+            .cloned()
+            .collect();
+        diff.missing.sort();
+        Ok(diff)
+    }
+
+    /// Returns the current proof for key, given an earlier proof for the same key.
+    ///
+    /// Nodes of the old proof that are still part of the path are decoded from the old proof
+    /// instead of being loaded from the db, so only nodes that changed since are fetched.
+    /// The result is the same as a fresh `get_proof`.
+    pub fn update_proof(&self, old_proof: &[Vec<u8>], key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let mut loaded = HashMap::new();
+        for node_encoded in old_proof {
+            if node_encoded.len() >= HASHED_LENGTH {
+                if let Ok(node) = decode_node(node_encoded) {
+                    loaded.insert(H::hash(node_encoded), node);
+                }
+            }
+        }
+
+        let key_path = &Nibbles::from_raw(key, true);
+        let path = with_err_key(self.get_path_at(&self.root, key_path, 0, &mut loaded), key)?;
+        Ok(path.iter().rev().map(encode_node_raw_with::<H>).collect())
+    }
+
+    /// Returns the proof for key in the compact encoding described in `compact_proof`,
+    /// to be checked with `verify_proof_compact`.
+    pub fn get_proof_compact(&self, key: &[u8]) -> TrieResult<Vec<u8>> {
+        let (proof, _) = self.get_proof_limited(key, usize::MAX)?;
+        compact_proof_with::<H>(key, proof)
+    }
+
+    /// Walks every node reachable from the root and checks that it is present in the db
+    /// and stored under its own hash. A missing node is reported as
+    /// `TrieError::MissingTrieNode`, and a node that does not match its hash as
+    /// `TrieError::InvalidData`.
+    pub fn verify_integrity(&self) -> TrieResult<()> {
+        self.verify_integrity_cancellable(&CancellationToken::new())
+    }
+
+    /// Runs `verify_integrity`, failing with `TrieError::Cancelled` once `token` is
+    /// cancelled.
+    pub fn verify_integrity_cancellable(&self, token: &CancellationToken) -> TrieResult<()> {
+        self.verify_integrity_at(&self.root, &mut Nibbles::from_hex(&[]), token)
+    }
+
+    // The implementation of `mark_and_sweep`, for any hasher.
+    fn sweep_unreachable(
+        db: &Arc<D>,
+        roots: &[H256],
+        token: &CancellationToken,
+    ) -> TrieResult<usize> {
+        let empty_root = empty_root::<H>();
+        let mut reachable = HashSet::new();
+        reachable.insert(empty_root);
+        let mut pending = vec![];
+        for root in roots {
+            let exists = db
+                .get(root.as_bytes())
+                .map_err(|e| TrieError::DB(e.to_string()))?
+                .is_some();
+            if !exists && *root != empty_root {
+                return Err(TrieError::MissingTrieNode {
+                    node_hash: *root,
+                    traversed: None,
+                    root_hash: Some(*root),
+                    err_key: None,
+                });
+            }
+            pending.push(*root);
+        }
+
+        while let Some(hash) = pending.pop() {
+            token.check()?;
+            if !reachable.insert(hash) {
+                continue;
+            }
+            if let Some(data) = db
+                .get(hash.as_bytes())
+                .map_err(|e| TrieError::DB(e.to_string()))?
+            {
+                decode_node_with_hash(hash, &data)?.child_hashes(&mut pending);
+            }
+        }
+
+        let unreachable: Vec<Vec<u8>> = db
+            .keys()?
+            .into_iter()
+            .filter(|key| key.len() == HASHED_LENGTH && !reachable.contains(&H256::from_slice(key)))
+            .collect();
+        token.check()?;
+        db.remove_batch(&unreachable)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(unreachable.len())
+    }
+
+    /// Removes every node from the db of this trie that cannot be reached from its last
+    /// committed root or from `live_roots`, returning the number of nodes removed. See
+    /// `mark_and_sweep`.
+    ///
+    /// Changes that are not committed yet may refer to nodes that are only reachable from
+    /// older roots, so commit before collecting garbage.
+    pub fn gc(&self, live_roots: &[H256]) -> TrieResult<usize> {
+        self.gc_cancellable(live_roots, &CancellationToken::new())
+    }
+
+    /// Runs `gc`, failing with `TrieError::Cancelled` once `token` is cancelled. Nothing is
+    /// removed from the db if it is cancelled.
+    pub fn gc_cancellable(
+        &self,
+        live_roots: &[H256],
+        token: &CancellationToken,
+    ) -> TrieResult<usize> {
+        let mut roots = live_roots.to_vec();
+        roots.push(self.root_hash);
+        Self::sweep_unreachable(&self.db, &roots, token)
+    }
+
+    /// Commits the trie like `root_hash`, encoding the children of each branch node in
+    /// parallel. The root hash and the nodes written to the db are the same as with
+    /// `root_hash`.
+    #[cfg(feature = "rayon")]
+    pub fn root_hash_parallel(&mut self) -> TrieResult<H256> {
+        let mut written = vec![];
+        let encoded = write_node_parallel::<H>(&self.root, &mut written);
+        for (hash, data) in written {
+            self.gen_keys.insert(hash.clone());
+            self.cache.insert(hash, data);
+        }
+        self.commit_encoded(encoded, |_, _| Ok(()))
+    }
+}
+
+impl<D> EthTrie<D>
+where
+    D: DB,
+{
+    pub fn new(db: Arc<D>) -> Self {
+        Self::with_hasher(db)
+    }
+
+    /// Creates a trie holding `entries`, inserted with `insert_batch`, so an empty value
+    /// removes the key as with `insert`. The trie is not committed.
+    pub fn from_iter<I>(db: Arc<D>, entries: I) -> TrieResult<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut trie = Self::new(db);
+        trie.insert_batch(entries.into_iter().collect())?;
+        Ok(trie)
+    }
+
+    /// Opens the trie at the root of the last commit to `db`, or an empty trie if nothing
+    /// was committed to it yet.
+    ///
+    /// Every commit records its root in `db`, so when several tries share a db, such as
+    /// tries opened with `at_root`, this is the root of whichever committed last.
+    pub fn open_latest(db: Arc<D>) -> TrieResult<Self> {
+        let latest = db
+            .get(LATEST_ROOT_KEY)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        let trie = Self::new(db);
+        match latest {
+            None => Ok(trie),
+            Some(root) if root.len() == HASHED_LENGTH => Ok(trie.at_root(H256::from_slice(&root))),
+            Some(root) => Err(TrieError::InvalidData {
+                node_hash: None,
+                len: root.len(),
+            }),
+        }
+    }
+
+    /// Returns the roots recorded in `db` by tries set up with `with_root_history`, in
+    /// commit order, oldest first. Empty if none were recorded.
+    pub fn root_history(db: &Arc<D>) -> TrieResult<Vec<H256>> {
+        read_root_history(db.as_ref())
+    }
+
+    /// Checks every node stored in `db`, independently of any root, and returns the hashes
+    /// of the nodes that failed, sorted.
+    ///
+    /// A node fails if its key is not the hash of its data, if it cannot be decoded, or if
+    /// it refers to a child that is not in the db. Entries whose key is not 32 bytes long
+    /// are not nodes and are skipped. Requires `DB::keys`.
+    pub fn validate_store(db: &Arc<D>) -> TrieResult<Vec<H256>> {
+        Self::validate_store_cancellable(db, &CancellationToken::new())
+    }
+
+    /// Runs `validate_store`, failing with `TrieError::Cancelled` once `token` is cancelled.
+    pub fn validate_store_cancellable(
+        db: &Arc<D>,
+        token: &CancellationToken,
+    ) -> TrieResult<Vec<H256>> {
+        let mut failed = vec![];
+        for key in db.keys()? {
+            token.check()?;
+            if key.len() != HASHED_LENGTH {
+                continue;
+            }
+            let hash = H256::from_slice(&key);
+            let data = match db.get(&key).map_err(|e| TrieError::DB(e.to_string()))? {
+                Some(data) => data,
+                None => continue,
+            };
+            if keccak(&data) != hash {
+                failed.push(hash);
+                continue;
+            }
+            let node = match decode_node(&data) {
+                Ok(node) => node,
+                Err(_) => {
+                    failed.push(hash);
+                    continue;
+                }
+            };
+
+            let mut children = vec![];
+            node.child_hashes(&mut children);
+            for child in children {
+                let exists = db
+                    .get(child.as_bytes())
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                    .is_some();
+                if !exists {
+                    failed.push(hash);
+                    break;
+                }
+            }
+        }
+        failed.sort();
+        Ok(failed)
+    }
+
+    /// Removes every node stored in `db` that cannot be reached from any of `roots`, and
+    /// returns the number of nodes removed. Entries whose key is not 32 bytes long are not
+    /// nodes and are kept, as is the node of the empty trie. Requires `DB::keys`.
+    ///
+    /// Fails with `TrieError::MissingTrieNode` before removing anything if one of the roots
+    /// is not in the db, so that a wrong root does not wipe the store. Nodes missing further
+    /// down are skipped.
+    pub fn mark_and_sweep(db: &Arc<D>, roots: &[H256]) -> TrieResult<usize> {
+        Self::mark_and_sweep_cancellable(db, roots, &CancellationToken::new())
+    }
+
+    /// Runs `mark_and_sweep`, failing with `TrieError::Cancelled` once `token` is cancelled.
+    /// Nothing is removed from the db if it is cancelled.
+    pub fn mark_and_sweep_cancellable(
+        db: &Arc<D>,
+        roots: &[H256],
+        token: &CancellationToken,
+    ) -> TrieResult<usize> {
+        Self::sweep_unreachable(db, roots, token)
+    }
+
+    /// Loads a blob produced by `export` into `db` and returns the trie it holds.
+    ///
+    /// Every node is checked against the hash stored alongside it before anything is
+    /// written, so a corrupt or truncated blob fails with `TrieError::InvalidData` or
+    /// `TrieError::Decoder` and leaves `db` untouched.
+    pub fn import(db: Arc<D>, blob: &[u8]) -> TrieResult<Self> {
+        let r = Rlp::new(blob);
+        let count = r.item_count()?;
+        if count == 0 {
+            return Err(TrieError::InvalidData {
+                node_hash: None,
+                len: blob.len(),
+            });
+        }
+        let mut keys = Vec::with_capacity(count);
+        let mut values = Vec::with_capacity(count);
+        for i in 0..count {
+            let pair = r.at(i)?;
+            if pair.item_count()? != 2 {
+                return Err(TrieError::InvalidData {
+                    node_hash: None,
+                    len: pair.as_raw().len(),
+                });
+            }
+            let hash = pair.at(0)?.data()?;
+            let data = pair.at(1)?.data()?;
+            if hash.len() != HASHED_LENGTH {
+                return Err(TrieError::InvalidData {
+                    node_hash: None,
+                    len: data.len(),
+                });
+            }
+            if keccak(data).as_bytes() != hash {
+                return Err(TrieError::InvalidData {
+                    node_hash: Some(H256::from_slice(hash)),
+                    len: data.len(),
+                });
+            }
+            keys.push(hash.to_vec());
+            values.push(data.to_vec());
+        }
+
+        let root_hash = H256::from_slice(&keys[0]);
+        db.insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+        Ok(Self::new(db).at_root(root_hash))
+    }
+
+    /// Reads the node records produced by `commit_to_writer` from `r` and inserts them into
+    /// `db`, returning the number of nodes loaded.
+    ///
+    /// Every node is checked against the hash stored alongside it, and a mismatch is reported
+    /// as `TrieError::InvalidData`. Records that were loaded before the failure are kept.
+    pub fn load_from_reader<R: Read>(db: &Arc<D>, r: &mut R) -> TrieResult<usize> {
+        let mut count = 0;
+        let mut hash = [0u8; HASHED_LENGTH];
+        loop {
+            // The stream may only end between two records.
+            match r.read(&mut hash[..1]) {
+                Ok(0) => return Ok(count),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TrieError::Io(e.to_string())),
+            }
+            let mut len = [0u8; 4];
+            r.read_exact(&mut hash[1..])
+                .and_then(|_| r.read_exact(&mut len))
+                .map_err(|e| TrieError::Io(e.to_string()))?;
+            // The length is not trusted until the data is read, so the buffer only grows
+            // with the bytes actually in the stream
+            let len = u32::from_be_bytes(len) as usize;
+            let mut data = vec![];
+            r.take(len as u64)
+                .read_to_end(&mut data)
+                .map_err(|e| TrieError::Io(e.to_string()))?;
+            if data.len() != len {
+                return Err(TrieError::Io(
+                    std::io::Error::from(ErrorKind::UnexpectedEof).to_string(),
+                ));
+            }
+
+            if keccak(&data).as_bytes() != hash {
+                return Err(TrieError::InvalidData {
+                    node_hash: Some(H256::from_slice(&hash)),
+                    len: data.len(),
+                });
+            }
+            db.insert(&hash, data)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+            count += 1;
+        }
+    }
+}
+
+/// An empty trie in a new `MemoryDB` of its own that deletes removed nodes, the same as
+/// `EthTrie::new(Arc::new(MemoryDB::new(true)))`.
+impl Default for EthTrie<MemoryDB> {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryDB::new(true)))
+    }
+}
+
+/// Cloning a trie copies its in-memory nodes, so the clone and the original can be modified
+/// independently. Both keep sharing the db, and commits by either one are seen by the
+/// iterators of both, as with `at_root`.
+impl<D, H> Clone for EthTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.deep_clone(),
+            root_hash: self.root_hash,
+
+            cache: self.cache.clone(),
+            passing_keys: self.passing_keys.clone(),
+            gen_keys: self.gen_keys.clone(),
+
+            db: self.db.clone(),
+            delete_on_empty_value: self.delete_on_empty_value,
+            strict_keys: self.strict_keys,
+            root_history: self.root_history,
+            generation: self.generation.clone(),
+            node_fetcher: self.node_fetcher.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<D, H> Trie<D> for EthTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Returns the value for key stored in the trie.
+    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        Ok(self.get_guard(key)?.map(ValueGuard::into_vec))
+    }
+
+    /// Checks that the key is present in the trie
+    fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        let path = &Nibbles::from_raw(key, true);
+        Ok(self.get_at(&self.root, path, 0, &mut 0)?.is_some())
+    }
+
+    /// Inserts value into trie and modifies it if it exists.
+    /// An empty value removes the key, unless disabled with `with_delete_on_empty_value`.
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
+        if value.is_empty() {
+            if !self.delete_on_empty_value {
+                return Err(TrieError::EmptyValue);
+            }
+            self.remove(key)?;
+            return Ok(());
+        }
+        let root = self.root.clone();
+        let path = &Nibbles::from_raw(key, true);
+        self.root = with_err_key(self.insert_at(root, path, 0, value.to_vec()), key)?.0;
+        Ok(())
+    }
+
+    /// Inserts every entry, in order, as if by calling `insert` for each one.
+    ///
+    /// The entries are sorted by key first, so that the trie is descended only once for
+    /// every prefix they share. When a later entry has the same key as an earlier one, the
+    /// later one wins. If inserting empty values is disabled with
+    /// `with_delete_on_empty_value` and the batch holds one, the whole batch is rejected
+    /// before the trie is changed.
+    ///
+    /// With `with_strict_keys`, the entries are inserted one at a time in order instead, and
+    /// the batch stops at the first entry that conflicts, keeping the earlier ones.
+    fn insert_batch(&mut self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> TrieResult<()> {
+        if !self.delete_on_empty_value && entries.iter().any(|(_, value)| value.is_empty()) {
+            return Err(TrieError::EmptyValue);
+        }
+        if self.strict_keys {
+            for (key, value) in entries {
+                self.insert(&key, &value)?;
+            }
+            return Ok(());
+        }
+
+        let mut sorted: Vec<(Nibbles, Vec<u8>)> = entries
+            .into_iter()
+            .map(|(key, value)| (Nibbles::from_raw(&key, true), value))
+            .collect();
+        // The sort is stable, so the last entry for each key is the one to keep
+        sorted.sort_by(|a, b| a.0.get_data().cmp(b.0.get_data()));
+        let mut unique: Vec<(Nibbles, Vec<u8>)> = Vec::with_capacity(sorted.len());
+        for entry in sorted {
+            match unique.last_mut() {
+                Some(last) if last.0 == entry.0 => *last = entry,
+                _ => unique.push(entry),
+            }
+        }
+
+        let (removed, inserted): (Vec<_>, Vec<_>) =
+            unique.into_iter().partition(|(_, value)| value.is_empty());
+        for (path, _) in removed {
+            self.remove(&path.encode_raw().0)?;
+        }
+        let root = self.root.clone();
+        self.root = self.insert_batch_at(root, &inserted, 0)?;
+        Ok(())
+    }
+
+    /// Removes any existing value for key from the trie.
+    fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        let path = &Nibbles::from_raw(key, true);
+        let (n, removed) = with_err_key(self.delete_at(&self.root.clone(), path, 0), key)?;
+        self.root = n;
+        Ok(removed.is_some())
+    }
+
+    /// Saves all the nodes in the db, clears the cache data, recalculates the root.
+    /// Returns the root hash of the trie.
+    fn root_hash(&mut self) -> TrieResult<H256> {
+        self.commit()
+    }
+
+    /// Prove constructs a merkle proof for key. The result contains all encoded nodes
+    /// on the path to the value at key. The value itself is also included in the last
+    /// node and can be retrieved by verifying the proof.
+    ///
+    /// If the trie does not contain a value for key, the returned proof contains all
+    /// nodes of the longest existing prefix of the key (at least the root node), ending
+    /// with the node that proves the absence of the key.
+    fn get_proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let key_path = &Nibbles::from_raw(key, true);
+        let path = with_err_key(
+            self.get_path_at(&self.root, key_path, 0, &mut HashMap::new()),
+            key,
+        )?;
+        Ok(path
+            .into_iter()
+            .rev()
+            .map(|n| self.encode_raw(&n))
+            .collect())
+    }
+
+    /// return value if key exists, None if key not exist, Error if proof is wrong
+    fn verify_proof(
+        &self,
+        root_hash: H256,
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let trie = proof_trie::<H>(root_hash, proof);
+        trie.get(key).or(Err(TrieError::InvalidProof))
+    }
+}
+
+impl<D, H> EthTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    fn get_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        depth: &mut usize,
+    ) -> TrieResult<Option<ValueGuard>> {
+        let partial = &path.offset(path_index);
+        if !matches!(source_node, Node::Empty | Node::Hash(_)) {
+            *depth += 1;
+        }
+        match source_node {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                if &leaf.key == partial {
+                    Ok(Some(ValueGuard {
+                        value: GuardedValue::Leaf(leaf.clone()),
+                    }))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(borrow_branch.value.clone().map(|value| ValueGuard {
+                        value: GuardedValue::Owned(value),
+                    }))
+                } else {
+                    let index = partial.at(0);
+                    self.get_at(&borrow_branch.children[index], path, path_index + 1, depth)
+                }
+            }
+            Node::Extension(extension) => {
+                let extension = extension.read().unwrap();
+
+                let prefix = &extension.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len == prefix.len() {
+                    self.get_at(&extension.node, path, path_index + match_len, depth)
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                self.get_at(&node, path, path_index, depth)
+            }
+        }
+    }
+
+    // Looks up a sorted group of key paths that all lead to source_node, passing the index
+    // and value of each key that exists to on_value.
+    fn get_many_at<F>(
+        &self,
+        source_node: &Node,
+        group: &[(usize, Nibbles)],
+        path_index: usize,
+        keys: &[&[u8]],
+        on_value: &mut F,
+    ) -> TrieResult<()>
+    where
+        F: FnMut(usize, &[u8]),
+    {
+        match source_node {
+            Node::Empty => Ok(()),
+            Node::Leaf(leaf) => {
+                for (i, path) in group {
+                    if leaf.key == path.offset(path_index) {
+                        on_value(*i, &leaf.value);
+                    }
+                }
+                Ok(())
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                // Sorting keeps the paths taking the same child next to each other
+                let mut start = 0;
+                while start < group.len() {
+                    let index = group[start].1.at(path_index);
+                    let end = start
+                        + group[start..]
+                            .iter()
+                            .take_while(|(_, path)| path.at(path_index) == index)
+                            .count();
+                    if index == 16 {
+                        if let Some(value) = &borrow_branch.value {
+                            for (i, _) in &group[start..end] {
+                                on_value(*i, value);
+                            }
+                        }
+                    } else {
+                        self.get_many_at(
+                            &borrow_branch.children[index],
+                            &group[start..end],
+                            path_index + 1,
+                            keys,
+                            on_value,
+                        )?;
+                    }
+                    start = end;
+                }
+                Ok(())
+            }
+            Node::Extension(extension) => {
+                let extension = extension.read().unwrap();
+
+                let prefix = &extension.prefix;
+                let matches =
+                    |path: &Nibbles| path.offset(path_index).common_prefix(prefix) == prefix.len();
+                let start = match group.iter().position(|(_, path)| matches(path)) {
+                    Some(start) => start,
+                    None => return Ok(()),
+                };
+                let end = start
+                    + group[start..]
+                        .iter()
+                        .take_while(|(_, path)| matches(path))
+                        .count();
+                self.get_many_at(
+                    &extension.node,
+                    &group[start..end],
+                    path_index + prefix.len(),
+                    keys,
+                    on_value,
+                )
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let (i, path) = &group[0];
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: Some(keys[*i].to_vec()),
+                        })?;
+                self.get_many_at(&node, group, path_index, keys, on_value)
+            }
+        }
+    }
+
+    fn insert_at(
+        &mut self,
+        n: Node,
+        path: &Nibbles,
+        path_index: usize,
+        value: Vec<u8>,
+    ) -> TrieResult<(Node, Option<Vec<u8>>)> {
+        self.update_at(n, path, path_index, move |_| value)
+    }
+
+    // Like `insert_at`, with the new value computed by update from the old one, or from None
+    // if the key does not exist yet.
+    fn update_at<F>(
+        &mut self,
+        n: Node,
+        path: &Nibbles,
+        path_index: usize,
+        update: F,
+    ) -> TrieResult<(Node, Option<Vec<u8>>)>
+    where
+        F: FnOnce(Option<&[u8]>) -> Vec<u8>,
+    {
+        let partial = path.offset(path_index);
+        match n {
+            Node::Empty => Ok((Node::from_leaf(partial, update(None)), None)),
+            Node::Leaf(leaf) => {
+                let old_partial = &leaf.key;
+                let match_index = partial.common_prefix(old_partial);
+                if match_index == old_partial.len() {
+                    return Ok((
+                        Node::from_leaf(leaf.key.clone(), update(Some(&leaf.value))),
+                        Some(leaf.value.clone()),
+                    ));
+                }
+                // One of the keys ends where they diverge
+                if self.strict_keys
+                    && (partial.at(match_index) == 16 || old_partial.at(match_index) == 16)
+                {
+                    return Err(TrieError::PrefixConflict);
+                }
+
+                let mut branch = BranchNode {
+                    children: empty_children(),
+                    value: None,
+                };
+
+                let n = Node::from_leaf(old_partial.offset(match_index + 1), leaf.value.clone());
+                branch.insert(old_partial.at(match_index), n);
+
+                let n = Node::from_leaf(partial.offset(match_index + 1), update(None));
+                branch.insert(partial.at(match_index), n);
+
+                if match_index == 0 {
+                    return Ok((Node::Branch(Arc::new(RwLock::new(branch))), None));
+                }
+
+                // if include a common prefix
+                Ok((
+                    Node::from_extension(
+                        partial.slice(0, match_index),
+                        Node::Branch(Arc::new(RwLock::new(branch))),
+                    ),
+                    None,
+                ))
+            }
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                // The new key ends above the children of the branch, or continues below
+                // the key whose value the branch holds
+                if self.strict_keys && (partial.at(0) == 0x10 || borrow_branch.value.is_some()) {
+                    return Err(TrieError::PrefixConflict);
+                }
+
+                if partial.at(0) == 0x10 {
+                    let value = update(borrow_branch.value.as_deref());
+                    let old_value = borrow_branch.value.replace(value);
+                    return Ok((Node::Branch(branch.clone()), old_value));
+                }
+
+                let child = borrow_branch.children[partial.at(0)].clone();
+                let (new_child, old_value) = self.update_at(child, path, path_index + 1, update)?;
+                borrow_branch.children[partial.at(0)] = new_child;
+                Ok((Node::Branch(branch.clone()), old_value))
+            }
+            Node::Extension(ext) => {
+                let mut borrow_ext = ext.write().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                let sub_node = borrow_ext.node.clone();
+                let match_index = partial.common_prefix(prefix);
+
+                if match_index == 0 {
+                    let mut branch = BranchNode {
+                        children: empty_children(),
+                        value: None,
+                    };
+                    branch.insert(
+                        prefix.at(0),
+                        if prefix.len() == 1 {
+                            sub_node
+                        } else {
+                            Node::from_extension(prefix.offset(1), sub_node)
+                        },
+                    );
+                    let node = Node::Branch(Arc::new(RwLock::new(branch)));
+
+                    return self.update_at(node, path, path_index, update);
+                }
+
+                if match_index == prefix.len() {
+                    let (new_node, old_value) =
+                        self.update_at(sub_node, path, path_index + match_index, update)?;
+                    return Ok((Node::from_extension(prefix.clone(), new_node), old_value));
+                }
+
+                let new_ext = Node::from_extension(prefix.offset(match_index), sub_node);
+                let (new_node, old_value) =
+                    self.update_at(new_ext, path, path_index + match_index, update)?;
+                borrow_ext.prefix = prefix.slice(0, match_index);
+                borrow_ext.node = new_node;
+                Ok((Node::Extension(ext.clone()), old_value))
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                let result = self.update_at(node, path, path_index, update)?;
+                // Only replaced once the insert below it succeeded
+                self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                Ok(result)
+            }
+        }
+    }
+
+    // Inserts entries sorted by path with no duplicate keys, descending into each child once
+    // for all the entries below it.
+    fn insert_batch_at(
+        &mut self,
+        n: Node,
+        entries: &[(Nibbles, Vec<u8>)],
+        path_index: usize,
+    ) -> TrieResult<Node> {
+        if entries.len() <= 1 {
+            return match entries.first() {
+                Some((path, value)) => Ok(self.insert_at(n, path, path_index, value.clone())?.0),
+                None => Ok(n),
+            };
+        }
+
+        match n {
+            Node::Empty => {
+                // Sorted paths share the common prefix of the first and the last one
+                let first = &entries[0].0;
+                let last = &entries[entries.len() - 1].0;
+                let match_index = first
+                    .offset(path_index)
+                    .common_prefix(&last.offset(path_index));
+                if match_index == 0 {
+                    let branch = Node::from_branch(empty_children(), None);
+                    return self.insert_batch_at(branch, entries, path_index);
+                }
+                let node = self.insert_batch_at(Node::Empty, entries, path_index + match_index)?;
+                Ok(Node::from_extension(
+                    first.slice(path_index, path_index + match_index),
+                    node,
+                ))
+            }
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                let mut start = 0;
+                while start < entries.len() {
+                    let index = entries[start].0.at(path_index);
+                    let end = start
+                        + entries[start..]
+                            .iter()
+                            .take_while(|(path, _)| path.at(path_index) == index)
+                            .count();
+                    if index == 0x10 {
+                        borrow_branch.value = Some(entries[start].1.clone());
+                    } else {
+                        let child = borrow_branch.children[index].clone();
+                        borrow_branch.children[index] =
+                            self.insert_batch_at(child, &entries[start..end], path_index + 1)?;
+                    }
+                    start = end;
+                }
+                Ok(Node::Branch(branch.clone()))
+            }
+            Node::Extension(ref ext) => {
+                let (prefix, sub_node) = {
+                    let borrow_ext = ext.read().unwrap();
+                    (borrow_ext.prefix.clone(), borrow_ext.node.clone())
+                };
+                let shared = entries.iter().all(|(path, _)| {
+                    path.offset(path_index).common_prefix(&prefix) == prefix.len()
+                });
+                if !shared {
+                    return self.insert_each_at(n, entries, path_index);
+                }
+                let node = self.insert_batch_at(sub_node, entries, path_index + prefix.len())?;
+                Ok(Node::from_extension(prefix, node))
+            }
+            Node::Leaf(_) => self.insert_each_at(n, entries, path_index),
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                let path = &entries[0].0;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                self.insert_batch_at(node, entries, path_index)
+            }
+        }
+    }
+
+    // Inserts entries one at a time, where the batch diverges from an existing node.
+    fn insert_each_at(
+        &mut self,
+        mut n: Node,
+        entries: &[(Nibbles, Vec<u8>)],
+        path_index: usize,
+    ) -> TrieResult<Node> {
+        for (path, value) in entries {
+            n = self.insert_at(n, path, path_index, value.clone())?.0;
+        }
+        Ok(n)
+    }
+
+    // Removes the value at path from old_node, returning the new node and the removed
+    // value, or None if there was no value to remove.
+    fn delete_at(
+        &mut self,
+        old_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+    ) -> TrieResult<(Node, Option<Vec<u8>>)> {
+        let partial = &path.offset(path_index);
+        let (new_node, removed) = match old_node {
+            Node::Empty => Ok((Node::Empty, None)),
+            Node::Leaf(leaf) => {
+                if &leaf.key == partial {
+                    return Ok((Node::Empty, Some(leaf.value.clone())));
+                }
+                Ok((Node::Leaf(leaf.clone()), None))
+            }
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                // Removing the value may leave a single child, so the branch still needs
+                // to degenerate
+                let removed = if partial.at(0) == 0x10 {
+                    borrow_branch.value.take()
+                } else {
+                    let index = partial.at(0);
+                    let child = &borrow_branch.children[index];
+
+                    let (new_child, removed) = self.delete_at(child, path, path_index + 1)?;
+                    if removed.is_some() {
+                        borrow_branch.children[index] = new_child;
+                    }
+                    removed
+                };
+
+                Ok((Node::Branch(branch.clone()), removed))
+            }
+            Node::Extension(ext) => {
+                let mut borrow_ext = ext.write().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    let (new_node, removed) =
+                        self.delete_at(&borrow_ext.node, path, path_index + match_len)?;
+
+                    if removed.is_some() {
+                        borrow_ext.node = new_node;
+                    }
+
+                    Ok((Node::Extension(ext.clone()), removed))
+                } else {
+                    Ok((Node::Extension(ext.clone()), None))
+                }
+            }
+            Node::Hash(hash_node) => {
+                let hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash: hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                let result = self.delete_at(&node, path, path_index);
+                // The parent keeps referring to the stored node unless something was deleted
+                // below it, so it is only replaced then
+                if let Ok((_, Some(_))) = result {
+                    self.passing_keys.insert(hash.as_bytes().to_vec());
+                }
+                result
+            }
+        }?;
+
+        if removed.is_some() {
+            Ok((self.degenerate(new_node)?, removed))
+        } else {
+            Ok((new_node, removed))
+        }
+    }
+
+    fn contains_prefix_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+    ) -> TrieResult<bool> {
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Empty => Ok(false),
+            Node::Leaf(leaf) => Ok(leaf.key.common_prefix(partial) == partial.len()),
+            Node::Branch(branch) => {
+                // A branch always holds at least two entries
+                if partial.is_empty() {
+                    return Ok(true);
+                }
+                let borrow_branch = branch.read().unwrap();
+                let index = partial.at(0);
+                self.contains_prefix_at(&borrow_branch.children[index], path, path_index + 1)
+            }
+            Node::Extension(extension) => {
+                let extension = extension.read().unwrap();
+
+                let prefix = &extension.prefix;
+                let match_len = partial.common_prefix(prefix);
+                if match_len == partial.len() {
+                    // The path ends inside the extension, so every key below it matches
+                    Ok(true)
+                } else if match_len == prefix.len() {
+                    self.contains_prefix_at(&extension.node, path, path_index + match_len)
+                } else {
+                    Ok(false)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(path.slice(0, path_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                self.contains_prefix_at(&node, path, path_index)
+            }
+        }
+    }
+
+    fn remove_prefix_at(
+        &mut self,
+        n: Node,
+        prefix: &Nibbles,
+        prefix_index: usize,
+    ) -> TrieResult<(Node, usize)> {
+        let partial = &prefix.offset(prefix_index);
+        if partial.is_empty() {
+            let removed = self.remove_subtree(&n)?;
+            return Ok((Node::Empty, removed));
+        }
+
+        let (new_node, removed) = match n {
+            Node::Empty => (Node::Empty, 0),
+            Node::Leaf(ref leaf) => {
+                if leaf.key.common_prefix(partial) == partial.len() {
+                    (Node::Empty, 1)
+                } else {
+                    (n.clone(), 0)
+                }
+            }
+            Node::Branch(ref branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                let index = partial.at(0);
+                let child = borrow_branch.children[index].clone();
+                let (new_child, removed) =
+                    self.remove_prefix_at(child, prefix, prefix_index + 1)?;
+                if removed > 0 {
+                    borrow_branch.children[index] = new_child;
+                }
+                (Node::Branch(branch.clone()), removed)
+            }
+            Node::Extension(ref ext) => {
+                let (ext_prefix, sub_node) = {
+                    let borrow_ext = ext.read().unwrap();
+                    (borrow_ext.prefix.clone(), borrow_ext.node.clone())
+                };
+                let match_len = partial.common_prefix(&ext_prefix);
+
+                if match_len == ext_prefix.len() {
+                    let (new_node, removed) =
+                        self.remove_prefix_at(sub_node, prefix, prefix_index + match_len)?;
+                    match (removed, new_node) {
+                        (0, _) => (n.clone(), 0),
+                        (removed, Node::Empty) => (Node::Empty, removed),
+                        (removed, new_node) => {
+                            (Node::from_extension(ext_prefix, new_node), removed)
+                        }
+                    }
+                } else if match_len == partial.len() {
+                    // The prefix ends inside the extension, so every key below it matches
+                    (Node::Empty, self.remove_subtree(&n)?)
+                } else {
+                    (n.clone(), 0)
+                }
+            }
+            Node::Hash(ref hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(prefix.slice(0, prefix_index)),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                // Only a node that changes is replaced, and so freed
+                let (new_node, removed) = self.remove_prefix_at(node, prefix, prefix_index)?;
+                if removed == 0 {
+                    return Ok((n.clone(), 0));
+                }
+                self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                return Ok((new_node, removed));
+            }
+        };
+
+        if removed > 0 {
+            Ok((self.degenerate(new_node)?, removed))
+        } else {
+            Ok((new_node, removed))
+        }
+    }
+
+    // Counts the entries below a node that is being dropped, marking the nodes it loads from
+    // the db to be freed.
+    fn remove_subtree(&mut self, n: &Node) -> TrieResult<usize> {
+        match n {
+            Node::Empty => Ok(0),
+            Node::Leaf(_) => Ok(1),
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                let mut removed = borrow_branch.value.is_some() as usize;
+                for child in borrow_branch.children.iter() {
+                    removed += self.remove_subtree(child)?;
+                }
+                Ok(removed)
+            }
+            Node::Extension(ext) => {
+                let sub_node = ext.read().unwrap().node.clone();
+                self.remove_subtree(&sub_node)
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                let node = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: None,
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.remove_subtree(&node)
+            }
+        }
+    }
+
+    // This refactors the trie after a node deletion, as necessary.
+    // For example, if a deletion removes a child of a branch node, leaving only one child left, it
+    // needs to be modified into an extension and maybe combined with its parent and/or child node.
+    fn degenerate(&mut self, n: Node) -> TrieResult<Node> {
+        match n {
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                let mut used_indexs = vec![];
+                for (index, node) in borrow_branch.children.iter().enumerate() {
+                    match node {
+                        Node::Empty => continue,
+                        _ => used_indexs.push(index),
+                    }
+                }
+
+                // if only a value node, transmute to leaf.
+                if used_indexs.is_empty() && borrow_branch.value.is_some() {
+                    let key = Nibbles::from_raw(&[], true);
+                    let value = borrow_branch.value.clone().unwrap();
+                    Ok(Node::from_leaf(key, value))
+                // if only one node. make an extension.
+                } else if used_indexs.len() == 1 && borrow_branch.value.is_none() {
+                    let used_index = used_indexs[0];
+                    let n = borrow_branch.children[used_index].clone();
+
+                    let new_node = Node::from_extension(Nibbles::from_hex(&[used_index as u8]), n);
+                    self.degenerate(new_node)
+                } else {
+                    Ok(Node::Branch(branch.clone()))
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                match borrow_ext.node.clone() {
+                    Node::Extension(sub_ext) => {
+                        let borrow_sub_ext = sub_ext.read().unwrap();
+
+                        let new_prefix = prefix.join(&borrow_sub_ext.prefix);
+                        let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
+                        self.degenerate(new_n)
+                    }
+                    Node::Leaf(leaf) => {
+                        let new_prefix = prefix.join(&leaf.key);
+                        Ok(Node::from_leaf(new_prefix, leaf.value.clone()))
+                    }
+                    // try again after recovering node from the db.
+                    Node::Hash(hash_node) => {
+                        let node_hash = hash_node.hash;
+                        self.passing_keys.insert(node_hash.as_bytes().to_vec());
+
+                        let new_node =
+                            self.recover_from_db(node_hash)?
+                                .ok_or(TrieError::MissingTrieNode {
+                                    node_hash,
+                                    traversed: None,
+                                    root_hash: Some(self.root_hash),
+                                    err_key: None,
+                                })?;
+
+                        let n = Node::from_extension(borrow_ext.prefix.clone(), new_node);
+                        self.degenerate(n)
+                    }
+                    _ => Ok(Node::Extension(ext.clone())),
+                }
+            }
+            _ => Ok(n),
+        }
+    }
+
+    // Get nodes path along the key, only the nodes whose encode length is greater than
+    // hash length are added.
+    // For embedded nodes whose data are already contained in their parent node, we don't need to
+    // add them in the path.
+    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
+    // all data stored in db, including nodes whose encoded data is less than hash length.
+    fn get_path_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        loaded: &mut HashMap<H256, Node>,
+    ) -> TrieResult<Vec<Node>> {
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Empty => Ok(vec![]),
+            Node::Leaf(_) => Ok(vec![source_node.clone()]),
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(vec![source_node.clone()])
+                } else {
+                    let node = &borrow_branch.children[partial.at(0)];
+                    let mut rest = self.get_path_at(node, path, path_index + 1, loaded)?;
+                    rest.push(source_node.clone());
+                    Ok(rest)
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    let mut rest =
+                        self.get_path_at(&borrow_ext.node, path, path_index + match_len, loaded)?;
+                    rest.push(source_node.clone());
+                    Ok(rest)
+                } else {
+                    Ok(vec![source_node.clone()])
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = match loaded.get(&node_hash) {
+                    Some(n) => n.clone(),
+                    None => {
+                        let n =
+                            self.recover_from_db(node_hash)?
+                                .ok_or(TrieError::MissingTrieNode {
+                                    node_hash,
+                                    traversed: None,
+                                    root_hash: Some(self.root_hash),
+                                    err_key: None,
+                                })?;
+                        loaded.insert(node_hash, n.clone());
+                        n
+                    }
+                };
+                self.get_path_at(&n, path, path_index, loaded)
+            }
+        }
+    }
+
+    fn get_proof_bounded_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        max_bytes: usize,
+        size: &mut usize,
+        proof: &mut Vec<Vec<u8>>,
+    ) -> TrieResult<()> {
+        match source_node {
+            Node::Empty => return Ok(()),
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.slice(0, path_index)),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                return self.get_proof_bounded_at(&n, path, path_index, max_bytes, size, proof);
+            }
+            _ => {}
+        }
+
+        let encoded = encode_node_raw_with::<H>(source_node);
+        *size += encoded.len();
+        if *size > max_bytes {
+            return Err(TrieError::ProofTooLarge { size: *size });
+        }
+        proof.push(encoded);
+
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(())
+                } else {
+                    let node = &borrow_branch.children[partial.at(0)];
+                    self.get_proof_bounded_at(node, path, path_index + 1, max_bytes, size, proof)
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                let match_len = partial.common_prefix(prefix);
+
+                if match_len == prefix.len() {
+                    self.get_proof_bounded_at(
+                        &borrow_ext.node,
+                        path,
+                        path_index + match_len,
+                        max_bytes,
+                        size,
+                        proof,
+                    )
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Records the branch indices and extension hops taken while looking up the key.
+    pub(crate) fn path_indices(&self, key: &[u8]) -> TrieResult<Vec<u8>> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut indices = vec![];
+        self.path_indices_at(&self.root, path, 0, &mut indices)?;
+        Ok(indices)
+    }
+
+    fn path_indices_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        indices: &mut Vec<u8>,
+    ) -> TrieResult<()> {
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Empty | Node::Leaf(_) => Ok(()),
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    indices.push(BRANCH_VALUE);
+                    Ok(())
+                } else {
+                    let index = partial.at(0);
+                    indices.push(index as u8);
+                    self.path_indices_at(
+                        &borrow_branch.children[index],
+                        path,
+                        path_index + 1,
+                        indices,
+                    )
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                indices.push(EXTENSION_HOP);
+                if partial.common_prefix(prefix) == prefix.len() {
+                    self.path_indices_at(&borrow_ext.node, path, path_index + prefix.len(), indices)
+                } else {
+                    Ok(())
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.slice(0, path_index)),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.path_indices_at(&n, path, path_index, indices)
+            }
+        }
+    }
+
+    // Looks up the key, also returning the hashes of the nodes loaded on the way, from the
+    // root down.
+    pub(crate) fn get_traced(&self, key: &[u8]) -> TrieResult<(Option<Vec<u8>>, Vec<H256>)> {
+        let path = &Nibbles::from_raw(key, true);
+        let mut visited = vec![];
+        let value = self.get_traced_at(&self.root, path, 0, &mut visited)?;
+        Ok((value, visited))
+    }
+
+    fn get_traced_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        visited: &mut Vec<H256>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                if &leaf.key == partial {
+                    Ok(Some(leaf.value.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                if partial.is_empty() || partial.at(0) == 16 {
+                    Ok(borrow_branch.value.clone())
+                } else {
+                    let index = partial.at(0);
+                    self.get_traced_at(
+                        &borrow_branch.children[index],
+                        path,
+                        path_index + 1,
+                        visited,
+                    )
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                if partial.common_prefix(prefix) == prefix.len() {
+                    self.get_traced_at(&borrow_ext.node, path, path_index + prefix.len(), visited)
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.slice(0, path_index)),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                visited.push(node_hash);
+                self.get_traced_at(&n, path, path_index, visited)
+            }
+        }
+    }
+
+    // Pushes the steps of the minimal proof for path from source_node down.
+    fn minimal_proof_at(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        steps: &mut Vec<MinimalStep>,
+    ) -> TrieResult<()> {
+        let partial = &path.offset(path_index);
+        match source_node {
+            Node::Empty => Ok(()),
+            Node::Leaf(leaf) => {
+                steps.push(MinimalStep::Leaf {
+                    key: leaf.key.slice(0, leaf.key.len() - 1),
+                    value: leaf.value.clone(),
+                });
+                Ok(())
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                let index = if partial.is_empty() {
+                    16
+                } else {
+                    partial.at(0)
+                };
+                let siblings = borrow_branch
+                    .children
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, child)| *i != index && !matches!(child, Node::Empty))
+                    .map(|(i, child)| (i as u8, child_reference::<H>(child)))
+                    .collect();
+                steps.push(MinimalStep::Branch {
+                    index: index as u8,
+                    siblings,
+                    value: borrow_branch.value.clone(),
+                });
+                if index == 16 {
+                    return Ok(());
+                }
+                self.minimal_proof_at(&borrow_branch.children[index], path, path_index + 1, steps)
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let prefix = &borrow_ext.prefix;
+                if partial.common_prefix(prefix) == prefix.len() {
+                    steps.push(MinimalStep::Extension {
+                        prefix: prefix.clone(),
+                        child: None,
+                    });
+                    self.minimal_proof_at(&borrow_ext.node, path, path_index + prefix.len(), steps)
+                } else {
+                    steps.push(MinimalStep::Extension {
+                        prefix: prefix.clone(),
+                        child: Some(child_reference::<H>(&borrow_ext.node)),
+                    });
+                    Ok(())
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.slice(0, path_index)),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.minimal_proof_at(&n, path, path_index, steps)
+            }
+        }
+    }
+
+    // Pushes the changes between the subtree a of self and the subtree b of other, both
+    // found at path, to changes. Where the two subtrees are shaped differently, each side is
+    // expanded one nibble at a time until they line up again.
+    #[allow(clippy::type_complexity)]
+    fn diff_at(
+        &self,
+        other: &Self,
+        a: Node,
+        b: Node,
+        path: &mut Nibbles,
+        changes: &mut Vec<(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>,
+    ) -> TrieResult<()> {
+        if let (Node::Hash(a_hash), Node::Hash(b_hash)) = (&a, &b) {
+            if a_hash.hash == b_hash.hash {
+                return Ok(());
+            }
+        }
+        let a = self.resolve_at(a, path)?;
+        let b = other.resolve_at(b, path)?;
+
+        match (&a, &b) {
+            (Node::Empty, Node::Empty) => return Ok(()),
+            (Node::Leaf(a_leaf), Node::Leaf(b_leaf)) if a_leaf.key == b_leaf.key => {
+                if a_leaf.value != b_leaf.value {
+                    let (key, _) = path.join(&a_leaf.key).encode_raw();
+                    changes.push((key, Some(a_leaf.value.clone()), Some(b_leaf.value.clone())));
+                }
+                return Ok(());
+            }
+            (Node::Branch(a_branch), Node::Branch(b_branch)) if Arc::ptr_eq(a_branch, b_branch) => {
+                return Ok(());
+            }
+            (Node::Extension(a_ext), Node::Extension(b_ext)) => {
+                if Arc::ptr_eq(a_ext, b_ext) {
+                    return Ok(());
+                }
+                let a_ext = a_ext.read().unwrap();
+                let b_ext = b_ext.read().unwrap();
+                if a_ext.prefix == b_ext.prefix {
+                    let len = path.len();
+                    path.extend(&a_ext.prefix);
+                    self.diff_at(other, a_ext.node.clone(), b_ext.node.clone(), path, changes)?;
+                    path.truncate(len);
+                    return Ok(());
+                }
+            }
+            _ => {}
+        }
+
+        let (a_children, a_value) = expand_node(&a);
+        let (b_children, b_value) = expand_node(&b);
+        if a_value != b_value {
+            let (key, _) = path.join(&Nibbles::from_hex(&[16])).encode_raw();
+            changes.push((key, a_value, b_value));
+        }
+        for (i, (a_child, b_child)) in a_children.into_iter().zip(b_children).enumerate() {
+            path.push(i as u8);
+            self.diff_at(other, a_child, b_child, path, changes)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    // Loads the nodes on the way to the given prefix, and all nodes below it, returning n
+    // with the loaded nodes in place of their hash nodes.
+    fn preload_at(&mut self, n: Node, prefix: &Nibbles, prefix_index: usize) -> TrieResult<Node> {
+        let partial = &prefix.offset(prefix_index);
+        let path = prefix.slice(0, prefix_index);
+        if partial.is_empty() {
+            return self.preload_subtree(n, &path);
+        }
+        let n = self.preload_node(n, &path)?;
+        match &n {
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                let index = partial.at(0);
+                let child = borrow_branch.children[index].clone();
+                borrow_branch.children[index] = self.preload_at(child, prefix, prefix_index + 1)?;
+            }
+            Node::Extension(ext) => {
+                let mut borrow_ext = ext.write().unwrap();
+
+                let match_len = partial.common_prefix(&borrow_ext.prefix);
+                let sub_node = borrow_ext.node.clone();
+                if match_len == partial.len() {
+                    // The prefix ends inside the extension, so every key below it matches
+                    let path = path.join(&borrow_ext.prefix);
+                    borrow_ext.node = self.preload_subtree(sub_node, &path)?;
+                } else if match_len == borrow_ext.prefix.len() {
+                    borrow_ext.node =
+                        self.preload_at(sub_node, prefix, prefix_index + match_len)?;
+                }
+            }
+            Node::Empty | Node::Leaf(_) | Node::Hash(_) => {}
+        }
+        Ok(n)
+    }
+
+    // Loads the nodes on the path of a key, along with the nodes that removing the key
+    // would merge into their parent, and returns the value stored at the key. Each loaded
+    // node replaces its hash node in n before the nodes below it are loaded, so a failed
+    // load keeps every node it loaded, as well as its passing key.
+    fn load_path(
+        &mut self,
+        n: &mut Node,
+        path: &Nibbles,
+        path_index: usize,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let partial = &path.offset(path_index);
+        let node_path = path.slice(0, path_index);
+        *n = self.preload_node(n.clone(), &node_path)?;
+        match n {
+            Node::Empty | Node::Hash(_) => Ok(None),
+            Node::Leaf(leaf) => {
+                if &leaf.key == partial {
+                    Ok(Some(leaf.value.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+
+                let index = partial.at(0);
+                let siblings: Vec<usize> = (0..16)
+                    .filter(|&i| i != index && !matches!(borrow_branch.children[i], Node::Empty))
+                    .collect();
+                let has_value = index != 16 && borrow_branch.value.is_some();
+                if siblings.len() == 1 && !has_value {
+                    // Left with one child, the branch is merged with it, and with the child
+                    // below it if that child is an extension
+                    let sibling = siblings[0];
+                    let mut sibling_path = node_path.clone();
+                    sibling_path.push(sibling as u8);
+                    let child = borrow_branch.children[sibling].clone();
+                    borrow_branch.children[sibling] = self.preload_node(child, &sibling_path)?;
+                    if let Node::Extension(ext) = &borrow_branch.children[sibling] {
+                        let mut borrow_ext = ext.write().unwrap();
+                        let sub_node = borrow_ext.node.clone();
+                        let sub_path = sibling_path.join(&borrow_ext.prefix);
+                        borrow_ext.node = self.preload_node(sub_node, &sub_path)?;
+                    }
+                }
+
+                if index == 16 {
+                    Ok(borrow_branch.value.clone())
+                } else {
+                    self.load_path(&mut borrow_branch.children[index], path, path_index + 1)
+                }
+            }
+            Node::Extension(ext) => {
+                let mut borrow_ext = ext.write().unwrap();
+
+                let prefix_len = borrow_ext.prefix.len();
+                if partial.common_prefix(&borrow_ext.prefix) == prefix_len {
+                    self.load_path(&mut borrow_ext.node, path, path_index + prefix_len)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    // Returns n, found at path, with every hash node below it replaced by the loaded node.
+    fn preload_subtree(&mut self, n: Node, path: &Nibbles) -> TrieResult<Node> {
+        let n = self.preload_node(n, path)?;
+        match &n {
+            Node::Branch(branch) => {
+                let mut borrow_branch = branch.write().unwrap();
+                for (i, child) in borrow_branch.children.iter_mut().enumerate() {
+                    let mut child_path = path.clone();
+                    child_path.push(i as u8);
+                    let loaded = self.preload_subtree(child.clone(), &child_path)?;
+                    *child = loaded;
+                }
+            }
+            Node::Extension(ext) => {
+                let mut borrow_ext = ext.write().unwrap();
+                let path = path.join(&borrow_ext.prefix);
+                let sub_node = borrow_ext.node.clone();
+                borrow_ext.node = self.preload_subtree(sub_node, &path)?;
+            }
+            Node::Empty | Node::Leaf(_) | Node::Hash(_) => {}
+        }
+        Ok(n)
+    }
+
+    // Loads n if it is a hash node. The loaded node will be written again by the next
+    // commit, so its hash is a passing key, to be removed if the node has changed by then.
+    fn preload_node(&mut self, n: Node, path: &Nibbles) -> TrieResult<Node> {
+        let node_hash = match &n {
+            Node::Hash(hash_node) => hash_node.hash,
+            _ => return Ok(n),
+        };
+        let node = self.resolve_at(n, path)?;
+        self.passing_keys.insert(node_hash.as_bytes().to_vec());
+        Ok(node)
+    }
+
+    // Loads node from the db if it is a hash node, or returns it unchanged.
+    fn resolve_at(&self, node: Node, path: &Nibbles) -> TrieResult<Node> {
+        match node {
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                self.recover_from_db(node_hash)?
+                    .ok_or_else(|| TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.clone()),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })
+            }
+            node => Ok(node),
+        }
+    }
+
+    // A trie sharing this trie's db and settings, rooted at the given in-memory node. Its
+    // root hash is the hash of the node if the db holds it, and the empty root otherwise,
+    // so the trie only counts as committed once the node is written.
+    fn with_root_node(&self, root: Node) -> TrieResult<Self> {
+        let node_hash = node_hash_with::<H>(&root);
+        let root_hash = if self.is_stored(node_hash)? {
+            node_hash
+        } else {
+            empty_root::<H>()
+        };
+        Ok(Self {
+            root_hash,
+            root,
+
+            cache: HashMap::new(),
+            passing_keys: HashSet::new(),
+            gen_keys: HashSet::new(),
+
+            db: self.db.clone(),
+            delete_on_empty_value: self.delete_on_empty_value,
+            strict_keys: self.strict_keys,
+            root_history: self.root_history,
+            generation: self.generation.clone(),
+            node_fetcher: self.node_fetcher.clone(),
+            hasher: PhantomData,
+        })
+    }
+
+    // Returns true if the node with the given hash is in the db, or is the empty root, which
+    // needs no node.
+    fn is_stored(&self, node_hash: H256) -> TrieResult<bool> {
+        if node_hash == empty_root::<H>() {
+            return Ok(true);
+        }
+        Ok(self
+            .db
+            .get(node_hash.as_bytes())
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .is_some())
+    }
+
+    // Finds the node holding all keys that start with the given prefix, shortening a leaf
+    // or extension node that only partially overlaps with the prefix.
+    fn subtree_at(
+        &self,
+        source_node: &Node,
+        prefix: &Nibbles,
+        prefix_index: usize,
+    ) -> TrieResult<Option<Node>> {
+        let partial = &prefix.offset(prefix_index);
+        if partial.is_empty() {
+            return Ok(Some(source_node.clone()));
+        }
+        match source_node {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                if leaf.key.common_prefix(partial) == partial.len() {
+                    let key = leaf.key.offset(partial.len());
+                    Ok(Some(Node::from_leaf(key, leaf.value.clone())))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                let child = &borrow_branch.children[partial.at(0)];
+                self.subtree_at(child, prefix, prefix_index + 1)
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let ext_prefix = &borrow_ext.prefix;
+                let match_len = partial.common_prefix(ext_prefix);
+                if match_len == ext_prefix.len() {
+                    self.subtree_at(&borrow_ext.node, prefix, prefix_index + match_len)
+                } else if match_len == partial.len() {
+                    Ok(Some(Node::from_extension(
+                        ext_prefix.offset(match_len),
+                        borrow_ext.node.clone(),
+                    )))
+                } else {
+                    Ok(None)
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(prefix.slice(0, prefix_index)),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.subtree_at(&n, prefix, prefix_index)
+            }
+        }
+    }
+
+    fn subtree_roots_at(
+        &self,
+        source_node: &Node,
+        path: Nibbles,
+        depth: usize,
+        roots: &mut Vec<(Nibbles, H256)>,
+    ) -> TrieResult<()> {
+        let remaining = depth - path.len();
+        match source_node {
+            Node::Empty => {}
+            _ if remaining == 0 => roots.push((path, node_hash_with::<H>(source_node))),
+            Node::Leaf(leaf) => {
+                // The key's terminator is not part of the prefix
+                if leaf.key.len() > remaining {
+                    let leaf_path = path.join(&leaf.key.slice(0, remaining));
+                    let subtree = Node::from_leaf(leaf.key.offset(remaining), leaf.value.clone());
+                    roots.push((leaf_path, node_hash_with::<H>(&subtree)));
+                }
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                for (i, child) in borrow_branch.children.iter().enumerate() {
+                    let child_path = path.join(&Nibbles::from_hex(&[i as u8]));
+                    self.subtree_roots_at(child, child_path, depth, roots)?;
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let ext_prefix = &borrow_ext.prefix;
+                if ext_prefix.len() <= remaining {
+                    let child_path = path.join(ext_prefix);
+                    self.subtree_roots_at(&borrow_ext.node, child_path, depth, roots)?;
+                } else {
+                    let ext_path = path.join(&ext_prefix.slice(0, remaining));
+                    let subtree =
+                        Node::from_extension(ext_prefix.offset(remaining), borrow_ext.node.clone());
+                    roots.push((ext_path, node_hash_with::<H>(&subtree)));
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let n = self
+                    .recover_from_db(node_hash)?
+                    .ok_or(TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.clone()),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.subtree_roots_at(&n, path, depth, roots)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Adds source_node and everything below it to stats. `hashed` is true if the node is
+    // stored under its hash, which is always the case for the root.
+    fn stats_at(
+        &self,
+        source_node: &Node,
+        path: &mut Nibbles,
+        depth: usize,
+        hashed: bool,
+        stats: &mut TrieStats,
+        token: &CancellationToken,
+    ) -> TrieResult<()> {
+        token.check()?;
+        if let Node::Hash(hash_node) = source_node {
+            let node_hash = hash_node.hash;
+            let n = self
+                .recover_from_db(node_hash)?
+                .ok_or(TrieError::MissingTrieNode {
+                    node_hash,
+                    traversed: Some(path.clone()),
+                    root_hash: Some(self.root_hash),
+                    err_key: None,
+                })?;
+            return self.stats_at(&n, path, depth, true, stats, token);
+        }
+        if let Node::Empty = source_node {
+            return Ok(());
+        }
+
+        if hashed || encode_node_raw_with::<H>(source_node).len() >= HASHED_LENGTH {
+            stats.hashed_nodes += 1;
+        } else {
+            stats.inline_nodes += 1;
+        }
+        stats.max_depth = stats.max_depth.max(depth);
+        match source_node {
+            Node::Leaf(leaf) => {
+                stats.leaf_nodes += 1;
+                stats.value_bytes += leaf.value.len();
+            }
+            Node::Branch(branch) => {
+                stats.branch_nodes += 1;
+                let borrow_branch = branch.read().unwrap();
+                if let Some(value) = &borrow_branch.value {
+                    stats.value_bytes += value.len();
+                }
+                for (i, child) in borrow_branch.children.iter().enumerate() {
+                    path.push(i as u8);
+                    self.stats_at(child, path, depth + 1, false, stats, token)?;
+                    path.pop();
+                }
+            }
+            Node::Extension(ext) => {
+                stats.extension_nodes += 1;
+                let borrow_ext = ext.read().unwrap();
+
+                let len = path.len();
+                path.extend(&borrow_ext.prefix);
+                self.stats_at(&borrow_ext.node, path, depth + 1, false, stats, token)?;
+                path.truncate(len);
+            }
+            Node::Empty | Node::Hash(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn verify_integrity_at(
+        &self,
+        source_node: &Node,
+        path: &mut Nibbles,
+        token: &CancellationToken,
+    ) -> TrieResult<()> {
+        token.check()?;
+        match source_node {
+            Node::Empty | Node::Leaf(_) => Ok(()),
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+                for (i, child) in borrow_branch.children.iter().enumerate() {
+                    path.push(i as u8);
+                    self.verify_integrity_at(child, path, token)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let len = path.len();
+                path.extend(&borrow_ext.prefix);
+                self.verify_integrity_at(&borrow_ext.node, path, token)?;
+                path.truncate(len);
+                Ok(())
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let data = self
+                    .db
+                    .get(node_hash.as_bytes())
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                    .ok_or_else(|| TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(path.clone()),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                if H::hash(&data) != node_hash {
+                    return Err(TrieError::InvalidData {
+                        node_hash: Some(node_hash),
+                        len: data.len(),
+                    });
+                }
+                self.verify_integrity_at(&decode_node_with_hash(node_hash, &data)?, path, token)
+            }
+        }
+    }
+
+    fn commit(&mut self) -> TrieResult<H256> {
+        self.commit_with(|_, _| Ok(()))
+    }
+
+    // Commits the trie, passing each (hash, encoded node) pair to `on_node` before it is
+    // written to the db.
+    fn commit_with<F>(&mut self, on_node: F) -> TrieResult<H256>
+    where
+        F: FnMut(&[u8], &[u8]) -> TrieResult<()>,
+    {
+        let encoded = self.write_node(&self.root.clone());
+        self.commit_encoded(encoded, on_node)
+    }
+
+    // Finishes a commit once the root has been encoded and every new node is in the cache.
+    fn commit_encoded<F>(&mut self, encoded: EncodedNode, on_node: F) -> TrieResult<H256>
+    where
+        F: FnMut(&[u8], &[u8]) -> TrieResult<()>,
+    {
+        let (root_hash, _) = self.flush_encoded(encoded, on_node)?;
+        self.root = self
+            .recover_from_db(root_hash)?
+            .expect("The root that was just created is missing");
+        Ok(root_hash)
+    }
+
+    // Writes the cache to the db and removes the nodes that were replaced, leaving the
+    // in-memory root as it is. Returns the root hash and the keys that were removed.
+    fn flush_encoded<F>(
+        &mut self,
+        encoded: EncodedNode,
+        mut on_node: F,
+    ) -> TrieResult<(H256, Vec<Vec<u8>>)>
+    where
+        F: FnMut(&[u8], &[u8]) -> TrieResult<()>,
+    {
+        let root_hash = match encoded {
+            EncodedNode::Hash(hash) => hash,
+            EncodedNode::Inline(encoded) => {
+                let hash = H::hash(&encoded);
+                self.cache.insert(hash.as_bytes().to_vec(), encoded);
+                hash
+            }
+        };
+
+        for (k, v) in self.cache.iter() {
+            on_node(k, v)?;
+        }
+
+        let mut keys = Vec::with_capacity(self.cache.len() + 1);
+        let mut values = Vec::with_capacity(self.cache.len() + 1);
+        for (k, v) in self.cache.drain() {
+            keys.push(k.to_vec());
+            values.push(v);
+        }
+        keys.push(LATEST_ROOT_KEY.to_vec());
+        values.push(root_hash.as_bytes().to_vec());
+        if self.root_history > 0 {
+            let mut roots = read_root_history(self.db.as_ref())?;
+            if roots.last() != Some(&root_hash) {
+                roots.push(root_hash);
+            }
+            let dropped = roots.len().saturating_sub(self.root_history);
+            keys.push(ROOT_HISTORY_KEY.to_vec());
+            values.push(
+                roots[dropped..]
+                    .iter()
+                    .flat_map(|r| r.as_bytes().to_vec())
+                    .collect(),
+            );
+        }
+
+        self.db
+            .insert_batch(keys, values)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        let removed_keys: Vec<Vec<u8>> = self
+            .passing_keys
+            .iter()
+            .filter(|h| !self.gen_keys.contains(&h.to_vec()))
+            .map(|h| h.to_vec())
+            .collect();
+
+        self.db
+            .remove_batch(&removed_keys)
+            .map_err(|e| TrieError::DB(e.to_string()))?;
+
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.root_hash = root_hash;
+        self.gen_keys.clear();
+        self.passing_keys.clear();
+        Ok((root_hash, removed_keys))
+    }
+
+    fn write_node(&mut self, to_encode: &Node) -> EncodedNode {
+        // Returns the hash value directly to avoid double counting.
+        if let Node::Hash(hash_node) = to_encode {
+            return EncodedNode::Hash(hash_node.hash);
+        }
+
+        let data = self.encode_raw(to_encode);
+        // Nodes smaller than 32 bytes are stored inside their parent,
+        // Nodes equal to 32 bytes are returned directly
+        if data.len() < HASHED_LENGTH {
+            EncodedNode::Inline(data)
+        } else {
+            let hash = H::hash(&data);
+            self.cache.insert(hash.as_bytes().to_vec(), data);
+
+            self.gen_keys.insert(hash.as_bytes().to_vec());
+            EncodedNode::Hash(hash)
+        }
+    }
+
+    fn encode_raw(&mut self, node: &Node) -> Vec<u8> {
+        match node {
+            Node::Empty => rlp::NULL_RLP.to_vec(),
+            Node::Leaf(leaf) => {
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&leaf.key.encode_compact());
+                stream.append(&leaf.value);
+                stream.out().to_vec()
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read().unwrap();
+
+                let mut stream = RlpStream::new_list(17);
+                for i in 0..16 {
+                    let n = &borrow_branch.children[i];
+                    match self.write_node(n) {
+                        EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
+                        EncodedNode::Inline(data) => stream.append_raw(&data, 1),
+                    };
+                }
+
+                match &borrow_branch.value {
+                    Some(v) => stream.append(v),
+                    None => stream.append_empty_data(),
+                };
+                stream.out().to_vec()
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read().unwrap();
+
+                let mut stream = RlpStream::new_list(2);
+                stream.append(&borrow_ext.prefix.encode_compact());
+                match self.write_node(&borrow_ext.node) {
+                    EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
+                    EncodedNode::Inline(data) => stream.append_raw(&data, 1),
+                };
+                stream.out().to_vec()
+            }
+            Node::Hash(_hash) => unreachable!(),
+        }
+    }
+
+    fn recover_from_db(&self, key: H256) -> TrieResult<Option<Node>> {
+        let node = match self
+            .db
+            .get(key.as_bytes())
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Some(value) => Some(decode_node_with_hash(key, &value)?),
+            None => match self.fetch_node(key)? {
+                Some(value) => Some(decode_node_with_hash(key, &value)?),
+                None => None,
+            },
+        };
+        Ok(node)
+    }
+
+    // Asks the node fetcher for a node missing from the db, and stores it in the db if the
+    // fetched data matches the hash.
+    fn fetch_node(&self, key: H256) -> TrieResult<Option<Vec<u8>>> {
+        let fetcher = match &self.node_fetcher {
+            Some(fetcher) => fetcher,
+            None => return Ok(None),
+        };
+        match (fetcher.0)(key) {
+            Some(data) if H::hash(&data) == key => {
+                self.db
+                    .insert(key.as_bytes(), data.clone())
+                    .map_err(|e| TrieError::DB(e.to_string()))?;
+                Ok(Some(data))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// Reads the roots recorded under ROOT_HISTORY_KEY.
+fn read_root_history<D: DB>(db: &D) -> TrieResult<Vec<H256>> {
+    let data = db
+        .get(ROOT_HISTORY_KEY)
+        .map_err(|e| TrieError::DB(e.to_string()))?
+        .unwrap_or_default();
+    if data.len() % HASHED_LENGTH != 0 {
+        return Err(TrieError::InvalidData {
+            node_hash: None,
+            len: data.len(),
+        });
+    }
+    Ok(data.chunks(HASHED_LENGTH).map(H256::from_slice).collect())
+}
+
+// The root hash of a trie with no entries when nodes are hashed with H.
+fn empty_root<H: Hasher>() -> H256 {
+    H::empty_root()
+}
+
+// Attaches key to a `TrieError::MissingTrieNode`, as the key whose lookup needed the node.
+fn with_err_key<T>(result: TrieResult<T>, key: &[u8]) -> TrieResult<T> {
+    result.map_err(|e| match e {
+        TrieError::MissingTrieNode {
+            node_hash,
+            traversed,
+            root_hash,
+            err_key: _,
+        } => TrieError::MissingTrieNode {
+            node_hash,
+            traversed,
+            root_hash,
+            err_key: Some(key.to_vec()),
+        },
+        e => e,
+    })
+}
+
+// Returns path ending with the leaf terminator, as the paths of byte keys do.
+fn leaf_path(path: &Nibbles) -> Nibbles {
+    let mut path = path.clone();
+    if path.get_data().last() != Some(&16) {
+        path.push(16);
+    }
+    path
+}
+
+// Returns the value of key_path at the end of a path returned by `get_path_at`, which
+// lists the nodes of the lookup from the one where it ended up to the root.
+fn path_value(path: &[Node], key_path: &Nibbles) -> Option<Vec<u8>> {
+    // The lookup ended below the nibbles consumed by the nodes above the last one
+    let consumed: usize = path
+        .iter()
+        .skip(1)
+        .map(|node| match node {
+            Node::Branch(_) => 1,
+            Node::Extension(ext) => ext.read().unwrap().prefix.len(),
+            _ => 0,
+        })
+        .sum();
+    let partial = key_path.offset(consumed);
+    match path.first() {
+        Some(Node::Leaf(leaf)) if leaf.key == partial => Some(leaf.value.clone()),
+        // The lookup also ends at a branch whose child on the path is empty
+        Some(Node::Branch(branch)) if partial.is_empty() || partial.at(0) == 16 => {
+            branch.read().unwrap().value.clone()
+        }
+        _ => None,
+    }
+}
+
+// Splits a loaded node into the 16 children and the value it holds one nibble down,
+// turning leaf and extension nodes into the branch they would be if their first nibble
+// were a branch of its own. An empty node has no children and no value.
+fn expand_node(node: &Node) -> (Vec<Node>, Option<Vec<u8>>) {
+    let mut children = vec![Node::Empty; 16];
+    match node {
+        Node::Empty => (children, None),
+        Node::Leaf(leaf) => {
+            let index = leaf.key.at(0);
+            if index == 16 {
+                return (children, Some(leaf.value.clone()));
+            }
+            children[index] = Node::from_leaf(leaf.key.offset(1), leaf.value.clone());
+            (children, None)
+        }
+        Node::Extension(ext) => {
+            let borrow_ext = ext.read().unwrap();
+            let prefix = &borrow_ext.prefix;
+            children[prefix.at(0)] = if prefix.len() == 1 {
+                borrow_ext.node.clone()
+            } else {
+                Node::from_extension(prefix.offset(1), borrow_ext.node.clone())
+            };
+            (children, None)
+        }
+        Node::Branch(branch) => {
+            let borrow_branch = branch.read().unwrap();
+            (borrow_branch.children.to_vec(), borrow_branch.value.clone())
+        }
+        Node::Hash(_) => unreachable!("hash nodes are loaded before being expanded"),
+    }
+}
+
+// The (hash, encoded node) pairs written by a parallel commit.
+#[cfg(feature = "rayon")]
+type WrittenNodes = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Like `EthTrie::write_node`, but encodes the children of branch nodes in parallel and
+// collects the hashed nodes in `written` instead of the commit cache.
+#[cfg(feature = "rayon")]
+fn write_node_parallel<H: Hasher>(to_encode: &Node, written: &mut WrittenNodes) -> EncodedNode {
+    if let Node::Hash(hash_node) = to_encode {
+        return EncodedNode::Hash(hash_node.hash);
+    }
+
+    let data = encode_raw_parallel::<H>(to_encode, written);
+    if data.len() < HASHED_LENGTH {
+        EncodedNode::Inline(data)
+    } else {
+        let hash = H::hash(&data);
+        written.push((hash.as_bytes().to_vec(), data));
+        EncodedNode::Hash(hash)
+    }
+}
+
+#[cfg(feature = "rayon")]
+fn encode_raw_parallel<H: Hasher>(node: &Node, written: &mut WrittenNodes) -> Vec<u8> {
+    match node {
+        Node::Empty => rlp::NULL_RLP.to_vec(),
+        Node::Leaf(leaf) => {
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&leaf.key.encode_compact());
+            stream.append(&leaf.value);
+            stream.out().to_vec()
+        }
+        Node::Branch(branch) => {
+            let borrow_branch = branch.read().unwrap();
+
+            let children: Vec<(EncodedNode, WrittenNodes)> = borrow_branch
+                .children
+                .par_iter()
+                .map(|child| {
+                    let mut child_written = vec![];
+                    let encoded = write_node_parallel::<H>(child, &mut child_written);
+                    (encoded, child_written)
+                })
+                .collect();
+
+            let mut stream = RlpStream::new_list(17);
+            for (encoded, child_written) in children {
+                match encoded {
+                    EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
+                    EncodedNode::Inline(data) => stream.append_raw(&data, 1),
+                };
+                written.extend(child_written);
+            }
+
+            match &borrow_branch.value {
+                Some(v) => stream.append(v),
+                None => stream.append_empty_data(),
+            };
+            stream.out().to_vec()
+        }
+        Node::Extension(ext) => {
+            let borrow_ext = ext.read().unwrap();
+
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&borrow_ext.prefix.encode_compact());
+            match write_node_parallel::<H>(&borrow_ext.node, written) {
+                EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
+                EncodedNode::Inline(data) => stream.append_raw(&data, 1),
+            };
+            stream.out().to_vec()
+        }
+        Node::Hash(_hash) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Alphanumeric;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    use keccak_hash::{keccak, H256};
+
+    use std::sync::atomic::Ordering;
+
+    use super::{is_empty_root, CancellationToken, EthTrie, Trie, TrieResult, EMPTY_TRIE_ROOT};
+    use crate::db::{MemoryDB, DB};
+    use crate::errors::TrieError;
+    use crate::hasher::{Hasher, KeccakHasher};
+    use crate::nibbles::Nibbles;
+    use crate::node::{decode_node, node_hash, node_hash_with, Node, NodeKind};
+
+    #[test]
+    fn test_trie_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+    }
+
+    #[test]
+    fn test_trie_get() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        let v = trie.get(b"test").unwrap();
+
+        assert_eq!(Some(b"test".to_vec()), v)
+    }
+
+    #[test]
+    fn test_trie_get_missing() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        let v = trie.get(b"no-val").unwrap();
+
+        assert_eq!(None, v)
+    }
+
+    fn corrupt_trie() -> (EthTrie<MemoryDB>, H256, H256) {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let corruptor_db = memdb.clone();
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test1-key", b"really-long-value1-to-prevent-inlining")
+            .unwrap();
+        trie.insert(b"test2-key", b"really-long-value2-to-prevent-inlining")
+            .unwrap();
+        let actual_root_hash = trie.root_hash().unwrap();
+
+        // Manually corrupt the database by removing a trie node
+        // This is the hash for the leaf node for test2-key
+        let node_hash_to_delete = b"\xcb\x15v%j\r\x1e\te_TvQ\x8d\x93\x80\xd1\xa2\xd1\xde\xfb\xa5\xc3hJ\x8c\x9d\xb93I-\xbd";
+        assert_ne!(corruptor_db.get(node_hash_to_delete).unwrap(), None);
+        corruptor_db.remove(node_hash_to_delete).unwrap();
+        assert_eq!(corruptor_db.get(node_hash_to_delete).unwrap(), None);
+
+        (
+            trie,
+            actual_root_hash,
+            H256::from_slice(node_hash_to_delete),
+        )
+    }
+
+    #[test]
+    /// When a database entry is missing, get returns a MissingTrieNode error
+    fn test_trie_get_corrupt() {
+        let (trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.get(b"test2-key");
+
+        if let Err(missing_trie_node) = result {
+            let expected_error = TrieError::MissingTrieNode {
+                node_hash: deleted_node_hash,
+                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
+                root_hash: Some(actual_root_hash),
+                err_key: Some(b"test2-key".to_vec()),
+            };
+            assert_eq!(missing_trie_node, expected_error);
+        } else {
+            // The only acceptable result here was a MissingTrieNode
+            panic!(
+                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_many() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..200u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+        trie.insert(b"key", b"branch-value").unwrap();
+        let root = trie.commit().unwrap();
+
+        let trie = EthTrie::new(memdb).at_root(root);
+        let keys: Vec<&[u8]> = vec![
+            b"key-57", b"key-1", b"missing", b"key", b"key-199", b"key-1", b"key-10", b"",
+        ];
+        let expected: Vec<_> = keys.iter().map(|key| trie.get(key).unwrap()).collect();
+        assert_eq!(trie.get_many(&keys).unwrap(), expected);
+        assert_eq!(expected[0], Some(57u32.to_be_bytes().to_vec()));
+        assert_eq!(expected[2], None);
+        assert!(trie.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_contains_many() {
+        let db = Arc::new(CancellingDB {
+            db: MemoryDB::new(true),
+            token: CancellationToken::new(),
+            reads_left: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        });
+        let mut trie = EthTrie::new(db.clone());
+        for i in 0..200u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+        trie.insert(b"key", b"branch-value").unwrap();
+        let root = trie.commit().unwrap();
+        let trie = trie.at_root(root);
+        let reads = || usize::MAX - db.reads_left.load(Ordering::SeqCst);
+
+        let keys: Vec<&[u8]> = vec![
+            b"key-57", b"key-1", b"missing", b"key", b"key-199", b"key-1", b"key-10", b"",
+        ];
+        let before = reads();
+        let expected: Vec<bool> = keys.iter().map(|key| trie.contains(key).unwrap()).collect();
+        let separate_reads = reads() - before;
+        assert_eq!(
+            expected,
+            vec![true, true, false, true, true, true, true, false]
+        );
+
+        // Nodes shared by the paths of several keys are only read once
+        let before = reads();
+        assert_eq!(trie.contains_many(&keys).unwrap(), expected);
+        let batched_reads = reads() - before;
+        assert!(batched_reads < separate_reads);
+        let before = reads();
+        trie.get_many(&keys).unwrap();
+        assert_eq!(reads() - before, batched_reads);
+        assert!(trie.contains_many(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    /// When a database entry is missing, get_many reports the key that needed it
+    fn test_trie_get_many_corrupt() {
+        let (trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.get_many(&[b"test2-key", b"test1-key"]);
+
+        let expected_error = TrieError::MissingTrieNode {
+            node_hash: deleted_node_hash,
+            traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
+            root_hash: Some(actual_root_hash),
+            err_key: Some(b"test2-key".to_vec()),
+        };
+        assert_eq!(result, Err(expected_error));
+    }
+
+    #[test]
+    /// When a database entry is missing, delete returns a MissingTrieNode error
+    fn test_trie_delete_corrupt() {
+        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.remove(b"test2-key");
+
+        if let Err(missing_trie_node) = result {
+            let expected_error = TrieError::MissingTrieNode {
+                node_hash: deleted_node_hash,
+                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
+                root_hash: Some(actual_root_hash),
+                err_key: Some(b"test2-key".to_vec()),
+            };
+            assert_eq!(missing_trie_node, expected_error);
+        } else {
+            // The only acceptable result here was a MissingTrieNode
+            panic!(
+                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    /// When a database entry is missing, delete returns a MissingTrieNode error
+    fn test_trie_delete_refactor_corrupt() {
+        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.remove(b"test1-key");
+
+        if let Err(missing_trie_node) = result {
+            let expected_error = TrieError::MissingTrieNode {
+                node_hash: deleted_node_hash,
+                traversed: None,
+                root_hash: Some(actual_root_hash),
+                err_key: Some(b"test1-key".to_vec()),
+            };
+            assert_eq!(missing_trie_node, expected_error);
+        } else {
+            // The only acceptable result here was a MissingTrieNode
+            panic!(
+                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    /// When a database entry is missing, get_proof returns a MissingTrieNode error
+    fn test_trie_get_proof_corrupt() {
+        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.get_proof(b"test2-key");
+
+        if let Err(missing_trie_node) = result {
+            let expected_error = TrieError::MissingTrieNode {
+                node_hash: deleted_node_hash,
+                traversed: None,
+                root_hash: Some(actual_root_hash),
+                err_key: Some(b"test2-key".to_vec()),
+            };
+            assert_eq!(missing_trie_node, expected_error);
+        } else {
+            // The only acceptable result here was a MissingTrieNode
+            panic!(
+                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    /// When a database entry is missing, insert returns a MissingTrieNode error
+    fn test_trie_insert_corrupt() {
+        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+
+        let result = trie.insert(b"test2-neighbor", b"any");
+
+        if let Err(missing_trie_node) = result {
+            let expected_error = TrieError::MissingTrieNode {
+                node_hash: deleted_node_hash,
+                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
+                root_hash: Some(actual_root_hash),
+                err_key: Some(b"test2-neighbor".to_vec()),
+            };
+            assert_eq!(missing_trie_node, expected_error);
+        } else {
+            // The only acceptable result here was a MissingTrieNode
+            panic!(
+                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_trie_random_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .map(char::from)
+                .collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val, val).unwrap();
+
+            let v = trie.get(val).unwrap();
+            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_trie_contains() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        assert!(trie.contains(b"test").unwrap());
+        assert!(!trie.contains(b"test2").unwrap());
+    }
+
+    #[test]
+    fn test_trie_remove() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        let removed = trie.remove(b"test").unwrap();
+        assert!(removed)
+    }
+
+    #[test]
+    fn test_trie_random_remove() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+
+        for _ in 0..1000 {
+            let rand_str: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(30)
+                .map(char::from)
+                .collect();
+            let val = rand_str.as_bytes();
+            trie.insert(val, val).unwrap();
+
+            let removed = trie.remove(val).unwrap();
+            assert!(removed);
+        }
+    }
+
+    #[test]
+    fn test_trie_at_root_six_keys() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = EthTrie::new(memdb.clone());
+            trie.insert(b"test", b"test").unwrap();
+            trie.insert(b"test1", b"test").unwrap();
+            trie.insert(b"test2", b"test").unwrap();
+            trie.insert(b"test23", b"test").unwrap();
+            trie.insert(b"test33", b"test").unwrap();
+            trie.insert(b"test44", b"test").unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let mut trie = EthTrie::new(memdb).at_root(root);
+        let v1 = trie.get(b"test33").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v1);
+        let v2 = trie.get(b"test44").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v2);
+        let root2 = trie.root_hash().unwrap();
+        assert_eq!(hex::encode(root), hex::encode(root2));
+    }
+
+    #[test]
+    fn test_trie_at_root_and_insert() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = EthTrie::new(Arc::clone(&memdb));
+            trie.insert(b"test", b"test").unwrap();
+            trie.insert(b"test1", b"test").unwrap();
+            trie.insert(b"test2", b"test").unwrap();
+            trie.insert(b"test23", b"test").unwrap();
+            trie.insert(b"test33", b"test").unwrap();
+            trie.insert(b"test44", b"test").unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let mut trie = EthTrie::new(memdb).at_root(root);
+        trie.insert(b"test55", b"test55").unwrap();
+        trie.root_hash().unwrap();
+        let v = trie.get(b"test55").unwrap();
+        assert_eq!(Some(b"test55".to_vec()), v);
+    }
+
+    #[test]
+    fn test_trie_at_root_and_delete() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root = {
+            let mut trie = EthTrie::new(Arc::clone(&memdb));
+            trie.insert(b"test", b"test").unwrap();
+            trie.insert(b"test1", b"test").unwrap();
+            trie.insert(b"test2", b"test").unwrap();
+            trie.insert(b"test23", b"test").unwrap();
+            trie.insert(b"test33", b"test").unwrap();
+            trie.insert(b"test44", b"test").unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let mut trie = EthTrie::new(memdb).at_root(root);
+        let removed = trie.remove(b"test44").unwrap();
+        assert!(removed);
+        let removed = trie.remove(b"test33").unwrap();
+        assert!(removed);
+        let removed = trie.remove(b"test23").unwrap();
+        assert!(removed);
+    }
+
+    #[test]
+    fn test_multiple_trie_roots() {
+        let k0: ethereum_types::H256 = ethereum_types::H256::zero();
+        let k1: ethereum_types::H256 = ethereum_types::H256::random();
+        let v: ethereum_types::H256 = ethereum_types::H256::random();
+
+        let root1 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = EthTrie::new(memdb);
+            trie.insert(k0.as_bytes(), v.as_bytes()).unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let root2 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = EthTrie::new(memdb);
+            trie.insert(k0.as_bytes(), v.as_bytes()).unwrap();
+            trie.insert(k1.as_bytes(), v.as_bytes()).unwrap();
+            trie.root_hash().unwrap();
+            trie.remove(k1.as_ref()).unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let root3 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie1 = EthTrie::new(Arc::clone(&memdb));
+            trie1.insert(k0.as_bytes(), v.as_bytes()).unwrap();
+            trie1.insert(k1.as_bytes(), v.as_bytes()).unwrap();
+            trie1.root_hash().unwrap();
+            let root = trie1.root_hash().unwrap();
+            let mut trie2 = trie1.at_root(root);
+            trie2.remove(k1.as_bytes()).unwrap();
+            trie2.root_hash().unwrap()
+        };
+
+        assert_eq!(root1, root2);
+        assert_eq!(root2, root3);
+    }
+
+    #[test]
+    fn test_multiple_trie_roots_h256() {
+        let k0 = H256::zero();
+        let k1 = keccak(b"k1");
+        let v = keccak(b"v");
+
+        let root1 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = EthTrie::new(memdb);
+            trie.insert_h256(k0, v.as_bytes()).unwrap();
+            trie.root_hash().unwrap()
+        };
+
+        let root2 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie = EthTrie::new(memdb);
+            trie.insert_h256(k0, v.as_bytes()).unwrap();
+            trie.insert_h256(k1, v.as_bytes()).unwrap();
+            trie.root_hash().unwrap();
+            assert!(trie.contains_h256(k1).unwrap());
+            assert!(trie.remove_h256(k1).unwrap());
+            assert!(!trie.contains_h256(k1).unwrap());
+            trie.root_hash().unwrap()
+        };
+
+        let root3 = {
+            let memdb = Arc::new(MemoryDB::new(true));
+            let mut trie1 = EthTrie::new(Arc::clone(&memdb));
+            trie1.insert_h256(k0, v.as_bytes()).unwrap();
+            trie1.insert_h256(k1, v.as_bytes()).unwrap();
+            let root = trie1.root_hash().unwrap();
+            let mut trie2 = trie1.at_root(root);
+            assert_eq!(trie2.get_h256(k1).unwrap(), Some(v.as_bytes().to_vec()));
+            trie2.remove_h256(k1).unwrap();
+            assert_eq!(trie2.get_h256(k1).unwrap(), None);
+            trie2.root_hash().unwrap()
+        };
+
+        assert_eq!(root1, root2);
+        assert_eq!(root2, root3);
+        assert!(!EthTrie::new(Arc::new(MemoryDB::new(true)))
+            .remove_h256(k0)
+            .unwrap());
+    }
+
+    #[test]
+    // Newer clippy flags the borrow in `keccak(&rlp::NULL_RLP)`, and older clippy lacks the lint
+    #[allow(unknown_lints, clippy::needless_borrows_for_generic_args)]
+    fn test_delete_stale_keys_with_random_insert_and_delete() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..100 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(2..30))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(&random_bytes, &random_bytes).unwrap();
+            keys.push(random_bytes.clone());
+        }
+        trie.root_hash().unwrap();
+        let slice = &mut keys;
+        slice.shuffle(&mut rng);
+
+        for key in slice.iter() {
+            trie.remove(key).unwrap();
+        }
+        trie.root_hash().unwrap();
+
+        let empty_node_key = keccak(&rlp::NULL_RLP);
+        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
+        assert_eq!(value, &rlp::NULL_RLP)
+    }
+
+    #[test]
+    fn insert_full_branch() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+
+        trie.insert(b"test", b"test").unwrap();
+        trie.insert(b"test1", b"test").unwrap();
+        trie.insert(b"test2", b"test").unwrap();
+        trie.insert(b"test23", b"test").unwrap();
+        trie.insert(b"test33", b"test").unwrap();
+        trie.insert(b"test44", b"test").unwrap();
+        trie.root_hash().unwrap();
+
+        let v = trie.get(b"test").unwrap();
+        assert_eq!(Some(b"test".to_vec()), v);
+    }
+
+    #[test]
+    fn iterator_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let root1: H256;
+        let mut kv = HashMap::new();
+        kv.insert(b"test".to_vec(), b"test".to_vec());
+        kv.insert(b"test1".to_vec(), b"test1".to_vec());
+        kv.insert(b"test11".to_vec(), b"test2".to_vec());
+        kv.insert(b"test14".to_vec(), b"test3".to_vec());
+        kv.insert(b"test16".to_vec(), b"test4".to_vec());
+        kv.insert(b"test18".to_vec(), b"test5".to_vec());
+        kv.insert(b"test2".to_vec(), b"test6".to_vec());
+        kv.insert(b"test23".to_vec(), b"test7".to_vec());
+        kv.insert(b"test9".to_vec(), b"test8".to_vec());
+        {
+            let mut trie = EthTrie::new(memdb.clone());
+            let mut kv = kv.clone();
+            kv.iter().for_each(|(k, v)| {
+                trie.insert(k, v).unwrap();
+            });
+            root1 = trie.root_hash().unwrap();
+
+            trie.iter()
+                .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+            assert!(kv.is_empty());
+        }
+
+        {
+            let mut trie = EthTrie::new(memdb.clone());
+            let mut kv2 = HashMap::new();
+            kv2.insert(b"test".to_vec(), b"test11".to_vec());
+            kv2.insert(b"test1".to_vec(), b"test12".to_vec());
+            kv2.insert(b"test14".to_vec(), b"test13".to_vec());
+            kv2.insert(b"test22".to_vec(), b"test14".to_vec());
+            kv2.insert(b"test9".to_vec(), b"test15".to_vec());
+            kv2.insert(b"test16".to_vec(), b"test16".to_vec());
+            kv2.insert(b"test2".to_vec(), b"test17".to_vec());
+            kv2.iter().for_each(|(k, v)| {
+                trie.insert(k, v).unwrap();
+            });
+
+            trie.root_hash().unwrap();
+
+            let mut kv_delete = HashSet::new();
+            kv_delete.insert(b"test".to_vec());
+            kv_delete.insert(b"test1".to_vec());
+            kv_delete.insert(b"test14".to_vec());
+
+            kv_delete.iter().for_each(|k| {
+                trie.remove(k).unwrap();
+            });
+
+            kv2.retain(|k, _| !kv_delete.contains(k));
+
+            trie.root_hash().unwrap();
+            trie.iter()
+                .for_each(|(k, v)| assert_eq!(kv2.remove(&k).unwrap(), v));
+            assert!(kv2.is_empty());
+        }
+
+        let trie = EthTrie::new(memdb).at_root(root1);
+        trie.iter()
+            .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_small_trie_at_root() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"key", b"val").unwrap();
+        let new_root_hash = trie.commit().unwrap();
+
+        let empty_trie = EthTrie::new(memdb);
+        // Can't find key in new trie at empty root
+        assert_eq!(empty_trie.get(b"key").unwrap(), None);
+
+        let trie_view = empty_trie.at_root(new_root_hash);
+        assert_eq!(&trie_view.get(b"key").unwrap().unwrap(), b"val");
+
+        // Previous trie was not modified
+        assert_eq!(empty_trie.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_large_trie_at_root() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(
+            b"pretty-long-key",
+            b"even-longer-val-to-go-more-than-32-bytes",
+        )
+        .unwrap();
+        let new_root_hash = trie.commit().unwrap();
+
+        let empty_trie = EthTrie::new(memdb);
+        // Can't find key in new trie at empty root
+        assert_eq!(empty_trie.get(b"pretty-long-key").unwrap(), None);
+
+        let trie_view = empty_trie.at_root(new_root_hash);
+        assert_eq!(
+            &trie_view.get(b"pretty-long-key").unwrap().unwrap(),
+            b"even-longer-val-to-go-more-than-32-bytes"
+        );
+
+        // Previous trie was not modified
+        assert_eq!(empty_trie.get(b"pretty-long-key").unwrap(), None);
+    }
+
+    #[test]
+    fn test_commit_to_writer() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        trie.insert(b"test1", b"really-long-value1-to-prevent-inlining")
+            .unwrap();
+        trie.insert(b"test2", b"really-long-value2-to-prevent-inlining")
+            .unwrap();
+
+        let mut stream = vec![];
+        let root = trie.commit_to_writer(&mut stream).unwrap();
+
+        let restored_db = Arc::new(MemoryDB::new(true));
+        let mut rest = &stream[..];
+        while !rest.is_empty() {
+            let (hash, tail) = rest.split_at(32);
+            let (len, tail) = tail.split_at(4);
+            let mut len_bytes = [0u8; 4];
+            len_bytes.copy_from_slice(len);
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            let (data, tail) = tail.split_at(len);
+            assert_eq!(keccak(data).as_bytes(), hash);
+            restored_db.insert(hash, data.to_vec()).unwrap();
+            rest = tail;
+        }
+
+        let restored = EthTrie::new(restored_db).at_root(root);
+        assert_eq!(restored.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert_eq!(
+            restored.get(b"test2").unwrap(),
+            Some(b"really-long-value2-to-prevent-inlining".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_load_from_reader_file_round_trip() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(&i.to_be_bytes(), format!("value-{}", i).as_bytes())
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("eth-trie-{}", uuid::Uuid::new_v4()));
+        let root = {
+            let mut file = std::fs::File::create(&path).unwrap();
+            trie.commit_to_writer(&mut file).unwrap()
+        };
+
+        let restored_db = Arc::new(MemoryDB::new(true));
+        let mut file = std::fs::File::open(&path).unwrap();
+        let loaded = EthTrie::load_from_reader(&restored_db, &mut file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, restored_db.len().unwrap());
+
+        let restored = EthTrie::new(restored_db).at_root(root);
+        for i in 0..100u32 {
+            assert_eq!(
+                restored.get(&i.to_be_bytes()).unwrap(),
+                Some(format!("value-{}", i).into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_from_reader_rejects_bad_hash() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"really-long-value-to-prevent-inlining")
+            .unwrap();
+        let mut stream = vec![];
+        trie.commit_to_writer(&mut stream).unwrap();
+
+        // Flip a byte of the stored hash
+        stream[0] ^= 0xff;
+        let restored_db = Arc::new(MemoryDB::new(true));
+        let result = EthTrie::load_from_reader(&restored_db, &mut &stream[..]);
+        assert!(matches!(
+            result,
+            Err(TrieError::InvalidData {
+                node_hash: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_load_from_reader_rejects_short_stream() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"really-long-value-to-prevent-inlining")
+            .unwrap();
+        let mut stream = vec![];
+        trie.commit_to_writer(&mut stream).unwrap();
+
+        // A record cut off in its data
+        let restored_db = Arc::new(MemoryDB::new(true));
+        let truncated = &stream[..stream.len() - 1];
+        let result = EthTrie::load_from_reader(&restored_db, &mut &truncated[..]);
+        assert!(matches!(result, Err(TrieError::Io(_))));
+        assert!(restored_db.is_empty().unwrap());
+
+        // A length far beyond the data that follows it
+        let mut oversized = stream[..32].to_vec();
+        oversized.extend_from_slice(&u32::MAX.to_be_bytes());
+        oversized.extend_from_slice(&stream[36..]);
+        let result = EthTrie::load_from_reader(&restored_db, &mut &oversized[..]);
+        assert!(matches!(result, Err(TrieError::Io(_))));
+        assert!(restored_db.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_rename() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"test", b"test").unwrap();
+        trie.insert(b"test1", b"test1").unwrap();
+        trie.insert(b"test2", b"test2").unwrap();
+        trie.root_hash().unwrap();
+
+        assert!(trie.rename(b"test1", b"test3").unwrap());
+        assert_eq!(trie.get(b"test1").unwrap(), None);
+        assert_eq!(trie.get(b"test3").unwrap(), Some(b"test1".to_vec()));
+        // Renaming onto an existing key overwrites it
+        assert!(trie.rename(b"test3", b"test2").unwrap());
+        assert_eq!(trie.get(b"test2").unwrap(), Some(b"test1".to_vec()));
+        let renamed_root = trie.root_hash().unwrap();
+
+        let mut manual = EthTrie::new(memdb);
+        manual.insert(b"test", b"test").unwrap();
+        manual.insert(b"test2", b"test1").unwrap();
+        assert_eq!(manual.root_hash().unwrap(), renamed_root);
+    }
+
+    #[test]
+    fn test_rename_missing_key() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        assert!(!trie.rename(b"missing", b"test").unwrap());
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert_eq!(trie.root_hash().unwrap(), root);
+    }
+
+    #[test]
+    fn test_rename_error_leaves_trie_unchanged() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        let value = b"really-long-value-to-prevent-inlining".to_vec();
+        trie.insert(b"a1", &value).unwrap();
+        trie.insert(b"a2", b"another-long-value-to-prevent-inlining")
+            .unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // Removing a1 merges its branch with the leaf of a2, which is no longer in the db
+        let proof = trie.get_proof(b"a2").unwrap();
+        memdb
+            .remove(keccak(&proof[proof.len() - 1]).as_bytes())
+            .unwrap();
+        let mut trie = trie.at_root(root);
+        assert!(matches!(
+            trie.rename(b"a1", b"z"),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+        assert_eq!(trie.get(b"a1").unwrap(), Some(value));
+        assert_eq!(trie.get(b"z").unwrap(), None);
+        assert_eq!(trie.root_hash().unwrap(), root);
+    }
+
+    #[test]
+    fn test_in_memory_size() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let empty_size = trie.in_memory_size();
+
+        let mut size = empty_size;
+        for i in 0..100u32 {
+            trie.insert(&i.to_be_bytes(), b"really-long-value-to-prevent-inlining")
+                .unwrap();
+            // Each new key adds to the estimate
+            assert!(trie.in_memory_size() > size);
+            size = trie.in_memory_size();
+        }
+        let loaded_size = trie.in_memory_size();
+        assert!(loaded_size > empty_size);
+
+        trie.root_hash().unwrap();
+        assert!(trie.in_memory_size() < loaded_size);
+    }
+
+    #[test]
+    fn test_iter_while() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u8 {
+            trie.insert(&[i], &[i]).unwrap();
+        }
+
+        let mut calls = 0;
+        let found: Vec<_> = trie
+            .iter_while(|key| {
+                calls += 1;
+                key[0] < 10
+            })
+            .map(|(k, _)| k[0])
+            .collect();
+
+        assert_eq!(found, (0..10).collect::<Vec<u8>>());
+        // The scan stops at the first failing key instead of visiting all 100
+        assert_eq!(calls, 11);
+    }
+
+    #[test]
+    fn test_empty_trie_root() {
+        assert_eq!(
+            hex::encode(EMPTY_TRIE_ROOT),
+            "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+        );
+        assert_eq!(EMPTY_TRIE_ROOT, keccak(rlp::NULL_RLP));
+        assert_eq!(KeccakHasher::empty_root(), EMPTY_TRIE_ROOT);
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert_eq!(trie.root_hash, EMPTY_TRIE_ROOT);
+        assert_eq!(trie.root_hash().unwrap(), EMPTY_TRIE_ROOT);
+    }
+
+    #[test]
+    fn test_insert_empty_value_deletes_by_default() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        trie.insert(b"test", b"").unwrap();
+        assert_eq!(trie.get(b"test").unwrap(), None);
+    }
+
+    #[test]
+    fn test_insert_empty_value_error() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb).with_delete_on_empty_value(false);
+        trie.insert(b"test", b"test").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        assert_eq!(trie.insert(b"test", b""), Err(TrieError::EmptyValue));
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert_eq!(trie.root_hash().unwrap(), root);
+
+        // The setting is kept when moving to another root
+        let mut trie = trie.at_root(root);
+        assert_eq!(trie.insert(b"test", b""), Err(TrieError::EmptyValue));
+    }
+
+    #[test]
+    fn test_node_hash_matches_root_hash() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"key", b"val").unwrap();
+        // Small enough to be an inline node, but the root is still hashed
+        assert_eq!(node_hash(&trie.root_node()), trie.root_hash().unwrap());
+
+        trie.insert(b"test1", b"really-long-value1-to-prevent-inlining")
+            .unwrap();
+        trie.insert(b"test2", b"really-long-value2-to-prevent-inlining")
+            .unwrap();
+        let expected = node_hash(&trie.root_node());
+        assert_eq!(expected, trie.root_hash().unwrap());
+        assert_eq!(node_hash(&trie.root_node()), expected);
+    }
+
+    #[test]
+    fn test_iterator_detects_commit() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(&i.to_be_bytes(), b"really-long-value-to-prevent-inlining")
+                .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+
+        let view = trie.at_root(root);
+        let mut iter = view.iter();
+        assert!(iter.next().is_some());
+        assert_eq!(iter.error(), None);
+
+        trie.remove(&50u32.to_be_bytes()).unwrap();
+        trie.root_hash().unwrap();
+
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.error(), Some(&TrieError::StaleIterator));
+    }
+
+    #[test]
+    fn test_replace() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        trie.insert(b"test1", b"test1").unwrap();
+        trie.insert(b"test12", b"test12").unwrap();
+        trie.root_hash().unwrap();
+
+        let old = trie.replace(b"test1".to_vec(), b"new".to_vec()).unwrap();
+        assert_eq!(old, Some(b"test1".to_vec()));
+        assert_eq!(trie.get(b"test1").unwrap(), Some(b"new".to_vec()));
+
+        // "test" is stored as the value of a branch node
+        let old = trie.replace(b"test".to_vec(), b"new".to_vec()).unwrap();
+        assert_eq!(old, Some(b"test".to_vec()));
+
+        let old = trie.replace(b"test2".to_vec(), b"new".to_vec()).unwrap();
+        assert_eq!(old, None);
+        assert_eq!(trie.get(b"test2").unwrap(), Some(b"new".to_vec()));
+
+        let old = trie.replace(b"test12".to_vec(), vec![]).unwrap();
+        assert_eq!(old, Some(b"test12".to_vec()));
+        assert_eq!(trie.get(b"test12").unwrap(), None);
+
+        // Removing the value of a branch node, and a key that does not exist
+        let old = trie.replace(b"test".to_vec(), vec![]).unwrap();
+        assert_eq!(old, Some(b"new".to_vec()));
+        assert_eq!(trie.get(b"test").unwrap(), None);
+        assert_eq!(trie.replace(b"test3".to_vec(), vec![]).unwrap(), None);
+        assert_eq!(trie.get(b"test1").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_insert_get() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert_eq!(
+            trie.insert_get(b"test".to_vec(), b"a".to_vec()).unwrap(),
+            None
+        );
+        assert_eq!(
+            trie.insert_get(b"test".to_vec(), b"b".to_vec()).unwrap(),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(
+            trie.insert_get(b"test".to_vec(), vec![]).unwrap(),
+            Some(b"b".to_vec())
+        );
+        assert!(trie.is_empty());
+    }
+
+    #[test]
+    fn test_append() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"test", b"a").unwrap();
+        trie.insert(b"test1", b"b").unwrap();
+        trie.root_hash().unwrap();
+
+        // "test" is stored as the value of a branch node, "test1" in a leaf
+        trie.append(b"test", b"-more").unwrap();
+        trie.append(b"test1", b"-more").unwrap();
+        trie.append(b"test2", b"new").unwrap();
+        trie.append(b"test3", b"").unwrap();
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"a-more".to_vec()));
+        assert_eq!(trie.get(b"test1").unwrap(), Some(b"b-more".to_vec()));
+        assert_eq!(trie.get(b"test2").unwrap(), Some(b"new".to_vec()));
+        assert!(!trie.contains(b"test3").unwrap());
+
+        let root = trie.root_hash().unwrap();
+        let mut expected = EthTrie::new(memdb);
+        expected.insert(b"test", b"a-more").unwrap();
+        expected.insert(b"test1", b"b-more").unwrap();
+        expected.insert(b"test2", b"new").unwrap();
+        assert_eq!(expected.root_hash().unwrap(), root);
+    }
+
+    #[test]
+    fn test_estimate_batch_impact() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                &i.to_be_bytes(),
+                format!("long-value-{}-to-prevent-inlining", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+
+        let mut ops = vec![];
+        for i in 0..20u32 {
+            ops.push((i.to_be_bytes().to_vec(), None));
+        }
+        for i in 100..130u32 {
+            let value = format!("new-value-{}-to-prevent-inlining", i).into_bytes();
+            ops.push((i.to_be_bytes().to_vec(), Some(value)));
+        }
+
+        let (added, removed) = trie.estimate_batch_impact(&ops).unwrap();
+        // The trie itself is not modified
+        assert!(trie.get(&0u32.to_be_bytes()).unwrap().is_some());
+        assert_eq!(trie.get(&100u32.to_be_bytes()).unwrap(), None);
+
+        let before = memdb.len().unwrap();
+        for (key, value) in ops.iter() {
+            match value {
+                Some(value) => trie.insert(key, value).unwrap(),
+                None => {
+                    trie.remove(key).unwrap();
                 }
             }
-            Node::Hash(hash_node) => {
-                let node_hash = hash_node.hash;
-                let n = self
-                    .recover_from_db(node_hash)?
-                    .ok_or(TrieError::MissingTrieNode {
-                        node_hash,
-                        traversed: None,
-                        root_hash: Some(self.root_hash),
-                        err_key: None,
-                    })?;
-                self.get_path_at(&n, path, path_index)
-            }
         }
-    }
+        let mut stream = vec![];
+        assert_ne!(trie.commit_to_writer(&mut stream).unwrap(), root);
+        let written =
+            EthTrie::load_from_reader(&Arc::new(MemoryDB::new(true)), &mut &stream[..]).unwrap();
+
+        assert_eq!(added, written);
+        assert_eq!(memdb.len().unwrap(), before + added - removed);
+    }
+
+    #[test]
+    fn test_get_guard() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"branch-value").unwrap();
+        trie.insert(b"test1", b"leaf-value").unwrap();
+
+        let first = trie.get_guard(b"test1").unwrap().unwrap();
+        let second = trie.get_guard(b"test1").unwrap().unwrap();
+        assert_eq!(&*first, b"leaf-value");
+        // Both guards borrow the same bytes from the leaf node
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        let branch_value = trie.get_guard(b"test").unwrap().unwrap();
+        assert_eq!(&*branch_value, b"branch-value");
+        assert!(trie.get_guard(b"test2").unwrap().is_none());
+
+        // The guard keeps its value after the key is overwritten
+        drop(second);
+        trie.insert(b"test1", b"new-value").unwrap();
+        assert_eq!(&*first, b"leaf-value");
+        assert_eq!(first.into_vec(), b"leaf-value".to_vec());
+    }
+
+    #[test]
+    fn test_get_into() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"branch-value").unwrap();
+        trie.insert(b"test1", b"leaf-value").unwrap();
+
+        let mut out = b"prefix:".to_vec();
+        assert!(trie.get_into(b"test1", &mut out).unwrap());
+        assert_eq!(out, b"prefix:leaf-value".to_vec());
+
+        out.clear();
+        assert!(trie.get_into(b"test", &mut out).unwrap());
+        assert_eq!(out, b"branch-value".to_vec());
+
+        assert!(!trie.get_into(b"test2", &mut out).unwrap());
+        assert_eq!(out, b"branch-value".to_vec());
+    }
+
+    #[test]
+    fn test_depth_of() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"key", b"value").unwrap();
+        assert_eq!(trie.depth_of(b"key").unwrap(), Some(1));
+
+        trie.insert(b"test", b"shallow").unwrap();
+        trie.insert(b"test1", b"deeper").unwrap();
+        trie.insert(b"test12", b"deepest").unwrap();
+        let root = trie.commit().unwrap();
+
+        let trie = EthTrie::new(memdb).at_root(root);
+        let shallow = trie.depth_of(b"key").unwrap().unwrap();
+        let deep = trie.depth_of(b"test1").unwrap().unwrap();
+        let deepest = trie.depth_of(b"test12").unwrap().unwrap();
+        assert!(shallow < deep);
+        assert!(deep < deepest);
+        assert_eq!(trie.depth_of(b"test123").unwrap(), None);
+        assert_eq!(trie.depth_of(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_diff() {
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut old = EthTrie::new(memdb.clone());
+        for i in 0..200u32 {
+            old.insert(format!("key-{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        old.insert(b"key", b"branch-value").unwrap();
+        let old_root = old.commit().unwrap();
+
+        let mut new = old.at_root(old_root);
+        new.insert(b"key-7", b"changed").unwrap();
+        new.insert(b"key-150", b"").unwrap();
+        new.insert(b"key-1000", b"added").unwrap();
+        new.insert(b"key", b"").unwrap();
+        new.insert(b"other", b"added").unwrap();
+        new.commit().unwrap();
+
+        let changes = old.diff(&new).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                (b"key".to_vec(), Some(b"branch-value".to_vec()), None),
+                (b"key-1000".to_vec(), None, Some(b"added".to_vec())),
+                (b"key-150".to_vec(), Some(b"value".to_vec()), None),
+                (
+                    b"key-7".to_vec(),
+                    Some(b"value".to_vec()),
+                    Some(b"changed".to_vec())
+                ),
+                (b"other".to_vec(), None, Some(b"added".to_vec())),
+            ]
+        );
+
+        // The reverse diff swaps old and new values
+        let reverse = new.diff(&old).unwrap();
+        let swapped: Vec<_> = changes.into_iter().map(|(k, a, b)| (k, b, a)).collect();
+        assert_eq!(reverse, swapped);
+
+        assert!(old.diff(&old.at_root(old_root)).unwrap().is_empty());
+        let empty = EthTrie::new(memdb);
+        assert_eq!(empty.diff(&old).unwrap().len(), 201);
+    }
+
+    #[test]
+    fn test_nibble_keys() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let odd = Nibbles::from_hex(&[1, 2, 3]);
+        let short = Nibbles::from_hex(&[1, 2]);
+        let long = Nibbles::from_hex(&[1, 2, 3, 4, 5]);
+        trie.insert_nibbles(&odd, b"odd").unwrap();
+        trie.insert_nibbles(&short, b"short").unwrap();
+        trie.insert_nibbles(&long, b"long").unwrap();
+
+        assert_eq!(trie.get_nibbles(&odd).unwrap(), Some(b"odd".to_vec()));
+        assert_eq!(trie.get_nibbles(&short).unwrap(), Some(b"short".to_vec()));
+        assert_eq!(trie.get_nibbles(&long).unwrap(), Some(b"long".to_vec()));
+        assert_eq!(
+            trie.get_nibbles(&Nibbles::from_hex(&[1, 2, 3, 4])).unwrap(),
+            None
+        );
+        // A nibble path of whole bytes is the same key as those bytes
+        assert_eq!(trie.get(&[0x12]).unwrap(), Some(b"short".to_vec()));
+        trie.insert(&[0x12, 0x34], b"byte-key").unwrap();
+        assert_eq!(
+            trie.get_nibbles(&Nibbles::from_raw(&[0x12, 0x34], true))
+                .unwrap(),
+            Some(b"byte-key".to_vec())
+        );
+        trie.remove(&[0x12, 0x34]).unwrap();
+
+        let paths: Vec<Nibbles> = trie.iter_nibbles().map(|(path, _)| path).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Nibbles::from_hex(&[1, 2, 16]),
+                Nibbles::from_hex(&[1, 2, 3, 16]),
+                Nibbles::from_hex(&[1, 2, 3, 4, 5, 16]),
+            ]
+        );
+
+        assert!(trie.remove_nibbles(&odd).unwrap());
+        assert!(!trie.remove_nibbles(&odd).unwrap());
+        assert_eq!(trie.get_nibbles(&odd).unwrap(), None);
+        assert_eq!(trie.get_nibbles(&long).unwrap(), Some(b"long".to_vec()));
+        trie.insert_nibbles(&long, b"").unwrap();
+        assert_eq!(trie.get_nibbles(&long).unwrap(), None);
+    }
+
+    #[test]
+    fn test_checkpoint_rollback() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"committed", b"value").unwrap();
+        trie.commit().unwrap();
+
+        trie.insert(b"key-a", b"value-a").unwrap();
+        let root_a = trie.clone().root_hash().unwrap();
+        let checkpoint = trie.checkpoint();
+
+        // Changes after the checkpoint, including in-place updates of saved nodes
+        trie.insert(b"key-b", b"value-b").unwrap();
+        trie.insert(b"key-a", b"changed").unwrap();
+        trie.remove(b"committed").unwrap();
+
+        trie.rollback(checkpoint);
+        assert_eq!(trie.get(b"key-a").unwrap(), Some(b"value-a".to_vec()));
+        assert_eq!(trie.get(b"key-b").unwrap(), None);
+        assert_eq!(trie.get(b"committed").unwrap(), Some(b"value".to_vec()));
+
+        let root = trie.commit().unwrap();
+        assert_eq!(root, root_a);
+        assert_eq!(EthTrie::new(memdb).at_root(root).verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_root_node_rlp() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert_eq!(trie.root_node_rlp().unwrap(), rlp::NULL_RLP.to_vec());
+
+        // A tiny trie has a root node shorter than a hash
+        trie.insert(b"k", b"v").unwrap();
+        let encoded = trie.root_node_rlp().unwrap();
+        assert!(encoded.len() < 32);
+        assert_eq!(keccak(&encoded), trie.root_hash().unwrap());
+
+        for i in 0..10u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        let encoded = trie.root_node_rlp().unwrap();
+        assert_eq!(keccak(&encoded), trie.root_hash().unwrap());
+        assert!(decode_node(&encoded).is_ok());
+    }
+
+    // Keccak over the data with a fixed prefix, standing in for another hash function
+    struct PrefixedHasher;
+
+    impl Hasher for PrefixedHasher {
+        fn hash(data: &[u8]) -> H256 {
+            keccak([b"custom:", data].concat())
+        }
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut keccak_trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        let mut trie = EthTrie::<_, PrefixedHasher>::with_hasher(memdb.clone());
+        assert_eq!(
+            trie.root_hash().unwrap(),
+            PrefixedHasher::hash(&rlp::NULL_RLP)
+        );
+        assert!(trie.is_empty_trie());
+
+        for i in 0..100u32 {
+            let key = format!("key-{}", i);
+            let value = format!("value-{}", i);
+            trie.insert(key.as_bytes(), value.as_bytes()).unwrap();
+            keccak_trie
+                .insert(key.as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        let root = trie.commit().unwrap();
+        let keccak_root = keccak_trie.commit().unwrap();
+        assert_ne!(root, keccak_root);
+        assert_eq!(root, node_hash_with::<PrefixedHasher>(&trie.root));
+        assert!(memdb.get(root.as_bytes()).unwrap().is_some());
+
+        let mut trie = trie.at_root(root);
+        assert_eq!(trie.get(b"key-42").unwrap(), Some(b"value-42".to_vec()));
+        assert_eq!(trie.verify_integrity(), Ok(()));
+
+        let proof = trie.get_proof(b"key-42").unwrap();
+        assert_eq!(
+            trie.verify_proof(root, b"key-42", proof.clone()).unwrap(),
+            Some(b"value-42".to_vec())
+        );
+        assert_eq!(
+            keccak_trie.verify_proof(root, b"key-42", proof),
+            Err(TrieError::InvalidProof)
+        );
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let mut entries: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        for i in 0..100u32 {
+            entries.insert(
+                format!("key-{}", i).into_bytes(),
+                format!("value-{}", i).into_bytes(),
+            );
+        }
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut expected = EthTrie::new(memdb.clone());
+        for (key, value) in entries.iter() {
+            expected.insert(key, value).unwrap();
+        }
+
+        let mut trie = EthTrie::from_iter(memdb.clone(), entries).unwrap();
+        assert_eq!(trie.root_hash, EMPTY_TRIE_ROOT);
+        assert_eq!(trie.root_hash().unwrap(), expected.root_hash().unwrap());
+
+        // An empty value leaves the key out
+        let trie = EthTrie::from_iter(
+            memdb,
+            vec![
+                (b"key".to_vec(), b"value".to_vec()),
+                (b"key".to_vec(), vec![]),
+                (b"other".to_vec(), b"value".to_vec()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(trie.get(b"key").unwrap(), None);
+        assert_eq!(trie.get(b"other").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_readonly_view() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..50u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        trie.root_hash().unwrap();
+        trie.insert(b"uncommitted", b"value").unwrap();
+        // Proofs include the changes that are not committed yet
+        let root = trie.clone().root_hash().unwrap();
+
+        let view = trie.as_readonly();
+        assert_eq!(view.get(b"key-7").unwrap(), Some(b"value-7".to_vec()));
+        assert!(view.contains(b"uncommitted").unwrap());
+        assert_eq!(view.iter().count(), 51);
+
+        let proof = view.get_proof(b"key-7").unwrap();
+        assert_eq!(
+            super::verify_proof(root, b"key-7", proof).unwrap(),
+            Some(b"value-7".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_commit_no_reload() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let expected = trie.clone().root_hash().unwrap();
+        let root = trie.commit_no_reload().unwrap();
+        assert_eq!(root, expected);
+        assert!(!matches!(trie.root, Node::Hash(_)));
+        assert!(memdb.get(root.as_bytes()).unwrap().is_some());
+        assert_eq!(
+            EthTrie::new(memdb.clone()).at_root(root).verify_integrity(),
+            Ok(())
+        );
 
-    fn commit(&mut self) -> TrieResult<H256> {
-        let root_hash = match self.write_node(&self.root.clone()) {
-            EncodedNode::Hash(hash) => hash,
-            EncodedNode::Inline(encoded) => {
-                let hash = keccak(&encoded);
-                self.cache.insert(hash.as_bytes().to_vec(), encoded);
-                hash
-            }
-        };
+        // The kept nodes can still be read and changed
+        assert_eq!(trie.get(b"key-7").unwrap(), Some(b"value-7".to_vec()));
+        trie.insert(b"key-7", b"changed").unwrap();
+        let mut reference = EthTrie::new(memdb.clone()).at_root(root);
+        reference.insert(b"key-7", b"changed").unwrap();
+        let new_root = trie.root_hash().unwrap();
+        assert_eq!(new_root, reference.root_hash().unwrap());
+        assert_eq!(
+            EthTrie::new(memdb).at_root(new_root).verify_integrity(),
+            Ok(())
+        );
+    }
 
-        let mut keys = Vec::with_capacity(self.cache.len());
-        let mut values = Vec::with_capacity(self.cache.len());
-        for (k, v) in self.cache.drain() {
-            keys.push(k.to_vec());
-            values.push(v);
+    #[test]
+    fn test_node_fetcher() {
+        let remote = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(remote.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
         }
+        let root = trie.root_hash().unwrap();
+
+        let local = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(local.clone()).at_root(root);
+        assert!(matches!(
+            trie.get(b"key-7"),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+
+        // A fetcher returning the wrong data is not trusted
+        trie.set_node_fetcher(Box::new(|_| Some(b"garbage".to_vec())));
+        assert!(matches!(
+            trie.get(b"key-7"),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+        assert!(local.is_empty().unwrap());
+
+        let fetched = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fetched.clone();
+        trie.set_node_fetcher(Box::new(move |hash| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            remote.get(hash.as_bytes()).unwrap()
+        }));
+        assert_eq!(trie.get(b"key-7").unwrap(), Some(b"value-7".to_vec()));
+        let count = fetched.load(Ordering::SeqCst);
+        assert!(count > 0);
+        assert_eq!(local.len().unwrap(), count);
+
+        // Fetched nodes are kept in the local db
+        assert_eq!(trie.get(b"key-7").unwrap(), Some(b"value-7".to_vec()));
+        assert_eq!(fetched.load(Ordering::SeqCst), count);
+        assert_eq!(
+            trie.at_root(root).get(b"key-8").unwrap(),
+            Some(b"value-8".to_vec())
+        );
+    }
 
-        self.db
-            .insert_batch(keys, values)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
-
-        let removed_keys: Vec<Vec<u8>> = self
-            .passing_keys
-            .iter()
-            .filter(|h| !self.gen_keys.contains(&h.to_vec()))
-            .map(|h| h.to_vec())
-            .collect();
+    #[test]
+    fn test_export_import() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let blob = trie.export().unwrap();
+        let imported = EthTrie::import(Arc::new(MemoryDB::new(true)), &blob).unwrap();
+        assert_eq!(imported.root_hash, EMPTY_TRIE_ROOT);
+
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let blob = trie.export().unwrap();
 
-        self.db
-            .remove_batch(&removed_keys)
-            .map_err(|e| TrieError::DB(e.to_string()))?;
+        let restored_db = Arc::new(MemoryDB::new(true));
+        let mut imported = EthTrie::import(restored_db.clone(), &blob).unwrap();
+        assert_eq!(imported.root_hash().unwrap(), root);
+        assert_eq!(imported.verify_integrity(), Ok(()));
+        assert_eq!(
+            imported.iter().collect::<Vec<_>>(),
+            trie.iter().collect::<Vec<_>>()
+        );
 
-        self.root_hash = root_hash;
-        self.gen_keys.clear();
-        self.passing_keys.clear();
-        self.root = self
-            .recover_from_db(root_hash)?
-            .expect("The root that was just created is missing");
-        Ok(root_hash)
+        // Corrupt blobs are rejected without writing anything
+        let empty_db = Arc::new(MemoryDB::new(true));
+        for len in 0..blob.len() {
+            assert!(EthTrie::import(empty_db.clone(), &blob[..len]).is_err());
+        }
+        let mut corrupt = blob.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(matches!(
+            EthTrie::import(empty_db.clone(), &corrupt),
+            Err(TrieError::InvalidData { .. })
+        ));
+        assert!(empty_db.is_empty().unwrap());
     }
 
-    fn write_node(&mut self, to_encode: &Node) -> EncodedNode {
-        // Returns the hash value directly to avoid double counting.
-        if let Node::Hash(hash_node) = to_encode {
-            return EncodedNode::Hash(hash_node.hash);
+    #[test]
+    fn test_get_in_memory() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert_eq!(trie.get_in_memory(b"key-7"), Ok(None));
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        trie.insert(b"key", b"value").unwrap();
+        for i in 0..100u32 {
+            let key = format!("key-{}", i);
+            assert_eq!(
+                trie.get_in_memory(key.as_bytes()).unwrap(),
+                trie.get(key.as_bytes()).unwrap()
+            );
         }
+        assert_eq!(trie.get_in_memory(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(trie.get_in_memory(b"key-100").unwrap(), None);
+        assert_eq!(trie.get_in_memory(b"other").unwrap(), None);
+
+        // After a commit only the root is loaded
+        let root = trie.root_hash().unwrap();
+        assert!(matches!(
+            trie.get_in_memory(b"key-7"),
+            Err(TrieError::NodeNotLoaded(_))
+        ));
+        assert_eq!(
+            trie.at_root(root).get_in_memory(b"key-7"),
+            Err(TrieError::NodeNotLoaded(root))
+        );
+        assert_eq!(trie.get(b"key-7").unwrap(), Some(b"value-7".to_vec()));
+    }
 
-        let data = self.encode_raw(to_encode);
-        // Nodes smaller than 32 bytes are stored inside their parent,
-        // Nodes equal to 32 bytes are returned directly
-        if data.len() < HASHED_LENGTH {
-            EncodedNode::Inline(data)
-        } else {
-            let hash = keccak(&data);
-            self.cache.insert(hash.as_bytes().to_vec(), data);
+    #[test]
+    fn test_iter_with_proofs_reads() {
+        let db = Arc::new(CancellingDB {
+            db: MemoryDB::new(true),
+            token: CancellationToken::new(),
+            reads_left: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        });
+        let mut trie = EthTrie::new(db.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}-long-enough-to-be-hashed", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let reads = || usize::MAX - db.reads_left.load(Ordering::SeqCst);
 
-            self.gen_keys.insert(hash.as_bytes().to_vec());
-            EncodedNode::Hash(hash)
+        let keys: Vec<Vec<u8>> = (0..100u32)
+            .rev()
+            .map(|i| format!("key-{}", i).into_bytes())
+            .chain(vec![b"key-1000".to_vec(), b"missing".to_vec()])
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let trie = trie.at_root(root);
+        let before = reads();
+        let results = trie.iter_with_proofs(&key_refs).unwrap();
+        let walk_reads = reads() - before;
+
+        // Every stored node on the paths is read once
+        let nodes = trie.get_proofs(&key_refs).unwrap();
+        assert_eq!(walk_reads, nodes.len());
+        for ((key, value, _), expected_key) in results.into_iter().zip(key_refs) {
+            assert_eq!(key, expected_key);
+            assert_eq!(value, trie.get(&key).unwrap());
         }
     }
 
-    fn encode_raw(&mut self, node: &Node) -> Vec<u8> {
-        match node {
-            Node::Empty => rlp::NULL_RLP.to_vec(),
-            Node::Leaf(leaf) => {
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&leaf.key.encode_compact());
-                stream.append(&leaf.value);
-                stream.out().to_vec()
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
-
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = &borrow_branch.children[i];
-                    match self.write_node(n) {
-                        EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
-                        EncodedNode::Inline(data) => stream.append_raw(&data, 1),
-                    };
-                }
+    #[test]
+    fn test_preload_prefix() {
+        let db = Arc::new(CancellingDB {
+            db: MemoryDB::new(true),
+            token: CancellationToken::new(),
+            reads_left: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        });
+        let mut trie = EthTrie::new(db.clone());
+        for i in 0..100u32 {
+            let value = format!("value-{}-long-enough-to-be-hashed", i);
+            trie.insert(format!("account-{}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+            trie.insert(
+                format!("other-{}", i).as_bytes(),
+                &[value.as_bytes(), b"!"].concat(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let reads = || usize::MAX - db.reads_left.load(Ordering::SeqCst);
 
-                match &borrow_branch.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                stream.out().to_vec()
-            }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
+        let trie = trie.at_root(root);
+        let before = reads();
+        for i in 0..100u32 {
+            trie.get(format!("account-{}", i).as_bytes()).unwrap();
+        }
+        let cold_reads = reads() - before;
+
+        // Once preloaded, reads under the prefix no longer touch the db
+        let mut trie = trie.at_root(root);
+        trie.preload_prefix(b"acc").unwrap();
+        let before = reads();
+        for i in 0..100u32 {
+            let key = format!("account-{}", i);
+            let value = format!("value-{}-long-enough-to-be-hashed", i).into_bytes();
+            assert_eq!(trie.get(key.as_bytes()).unwrap(), Some(value.clone()));
+            assert_eq!(trie.get_in_memory(key.as_bytes()).unwrap(), Some(value));
+        }
+        assert_eq!(reads() - before, 0);
+        assert!(cold_reads >= 100);
+        assert!(matches!(
+            trie.get_in_memory(b"other-1"),
+            Err(TrieError::NodeNotLoaded(_))
+        ));
+
+        // The preloaded nodes are committed like loaded ones
+        trie.insert(b"account-7", b"changed").unwrap();
+        let nodes = db.len().unwrap();
+        let root = trie.root_hash().unwrap();
+        assert!(db.len().unwrap() <= nodes + 1);
+        let trie = trie.at_root(root);
+        assert_eq!(trie.get(b"account-7").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(
+            trie.get(b"other-7").unwrap(),
+            Some(b"value-7-long-enough-to-be-hashed!".to_vec())
+        );
+        trie.verify_integrity().unwrap();
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_ext.prefix.encode_compact());
-                match self.write_node(&borrow_ext.node) {
-                    EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
-                    EncodedNode::Inline(data) => stream.append_raw(&data, 1),
-                };
-                stream.out().to_vec()
+    #[test]
+    fn test_is_inlined() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        trie.insert(b"horses", &[0u8; 40]).unwrap();
+
+        for committed in [false, true].iter() {
+            if *committed {
+                trie.root_hash().unwrap();
             }
-            Node::Hash(_hash) => unreachable!(),
+            assert_eq!(trie.is_inlined(b"doge").unwrap(), Some(true));
+            assert_eq!(trie.is_inlined(b"dog").unwrap(), Some(true));
+            assert_eq!(trie.is_inlined(b"do").unwrap(), Some(false));
+            assert_eq!(trie.is_inlined(b"horses").unwrap(), Some(false));
+            assert_eq!(trie.is_inlined(b"cat").unwrap(), None);
+            assert_eq!(trie.is_inlined(b"horse").unwrap(), Some(false));
         }
-    }
 
-    fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
-        let r = Rlp::new(data);
+        // A single leaf is the root
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        trie.insert(b"a", b"b").unwrap();
+        assert_eq!(trie.is_inlined(b"a").unwrap(), Some(false));
+    }
 
-        match r.prototype()? {
-            Prototype::Data(0) => Ok(Node::Empty),
-            Prototype::List(2) => {
-                let key = r.at(0)?.data()?;
-                let key = Nibbles::from_compact(key);
+    #[test]
+    fn test_strict_keys() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone()).with_strict_keys(true);
+        trie.insert(b"abc", b"1").unwrap();
+        trie.insert(b"abd", b"2").unwrap();
+        trie.insert(b"xyz", b"3").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // Prefixes and extensions of existing keys, against leaf, branch and extension nodes
+        for key in [&b"ab"[..], b"a", b"", b"abcd", b"abc\x00", b"x", b"xyzz"].iter() {
+            assert_eq!(
+                trie.insert(key, b"4"),
+                Err(TrieError::PrefixConflict),
+                "key {:?}",
+                key
+            );
+        }
+        assert_eq!(trie.root_hash().unwrap(), root);
+        assert_eq!(trie.at_root(root).verify_integrity(), Ok(()));
 
-                if key.is_leaf() {
-                    Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
-                } else {
-                    let n = self.decode_node(r.at(1)?.as_raw())?;
+        // Updates and unrelated keys are fine
+        trie.insert(b"abc", b"5").unwrap();
+        trie.insert(b"abe", b"6").unwrap();
+        trie.insert(b"b", b"7").unwrap();
+        assert_eq!(trie.get(b"abc").unwrap(), Some(b"5".to_vec()));
 
-                    Ok(Node::from_extension(key, n))
-                }
-            }
-            Prototype::List(17) => {
-                let mut nodes = empty_children();
-                #[allow(clippy::needless_range_loop)]
-                for i in 0..nodes.len() {
-                    let rlp_data = r.at(i)?;
-                    let n = self.decode_node(rlp_data.as_raw())?;
-                    nodes[i] = n;
-                }
+        assert_eq!(
+            trie.insert_batch(vec![
+                (b"c".to_vec(), b"8".to_vec()),
+                (b"bb".to_vec(), b"9".to_vec()),
+            ]),
+            Err(TrieError::PrefixConflict)
+        );
+        assert_eq!(trie.get(b"c").unwrap(), Some(b"8".to_vec()));
 
-                // The last element is a value node.
-                let value_rlp = r.at(16)?;
-                let value = if value_rlp.is_empty() {
-                    None
-                } else {
-                    Some(value_rlp.data()?.to_vec())
-                };
+        // The default mode accepts them
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"abc", b"1").unwrap();
+        trie.insert(b"ab", b"2").unwrap();
+        trie.insert(b"abcd", b"3").unwrap();
+        assert_eq!(trie.get(b"ab").unwrap(), Some(b"2".to_vec()));
+    }
 
-                Ok(Node::from_branch(nodes, value))
-            }
-            _ => {
-                if r.is_data() && r.size() == HASHED_LENGTH {
-                    let hash = H256::from_slice(r.data()?);
-                    Ok(Node::from_hash(hash))
-                } else {
-                    Err(TrieError::InvalidData)
-                }
+    #[test]
+    fn test_corrupt_node_reports_hash() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let mut children = vec![];
+        decode_node(&memdb.get(root.as_bytes()).unwrap().unwrap())
+            .unwrap()
+            .child_hashes(&mut children);
+
+        // Bad RLP, and a leaf whose path has no flag
+        for corrupt in [vec![0xff], vec![0xc2, 0x80, 0x80]].iter() {
+            for child in children.iter() {
+                memdb.insert(child.as_bytes(), corrupt.clone()).unwrap();
             }
+            let result = trie.at_root(root).len();
+            assert_eq!(
+                result,
+                Err(TrieError::InvalidData {
+                    node_hash: Some(children[0]),
+                    len: corrupt.len(),
+                })
+            );
         }
+        assert!(matches!(
+            decode_node(&[0xc2, 0x80, 0x80]),
+            Err(TrieError::InvalidData {
+                node_hash: None,
+                len: 3
+            })
+        ));
     }
 
-    fn recover_from_db(&self, key: H256) -> TrieResult<Option<Node>> {
-        let node = match self
-            .db
-            .get(key.as_bytes())
-            .map_err(|e| TrieError::DB(e.to_string()))?
-        {
-            Some(value) => Some(self.decode_node(&value)?),
-            None => None,
-        };
-        Ok(node)
+    #[test]
+    fn test_stats() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        assert_eq!(trie.stats().unwrap(), super::TrieStats::default());
+
+        trie.insert(b"do", b"verb").unwrap();
+        trie.insert(b"dog", b"puppy").unwrap();
+        trie.insert(b"doge", b"coin").unwrap();
+        trie.insert(b"horse", b"stallion").unwrap();
+        let uncommitted = trie.stats().unwrap();
+        trie.root_hash().unwrap();
+        let stats = trie.stats().unwrap();
+        assert_eq!(stats, uncommitted);
+        assert_eq!(stats.leaf_nodes, 2);
+        assert_eq!(stats.extension_nodes, 3);
+        assert_eq!(stats.branch_nodes, 3);
+        assert_eq!(stats.max_depth, 7);
+        assert_eq!(stats.value_bytes, 21);
+        assert_eq!(stats.inline_nodes + stats.hashed_nodes, 8);
+        let stored = memdb
+            .keys()
+            .unwrap()
+            .into_iter()
+            .filter(|k| k.len() == 32)
+            .count();
+        assert_eq!(stats.hashed_nodes, stored);
+        assert_eq!(stats.inline_nodes, 4);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rand::distributions::Alphanumeric;
-    use rand::seq::SliceRandom;
-    use rand::{thread_rng, Rng};
-    use std::collections::{HashMap, HashSet};
-    use std::sync::Arc;
+    #[test]
+    fn test_compute_root() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        assert_eq!(trie.compute_root().unwrap(), EMPTY_TRIE_ROOT);
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let expected = trie.compute_root().unwrap();
+        assert!(memdb.is_empty().unwrap());
+        assert_eq!(trie.root_hash().unwrap(), expected);
+        assert_eq!(trie.compute_root().unwrap(), expected);
+
+        // Pending changes on top of committed nodes
+        let size = memdb.len().unwrap();
+        trie.insert(b"key-7", b"changed").unwrap();
+        trie.remove(b"key-8").unwrap();
+        let expected = trie.compute_root().unwrap();
+        assert_eq!(memdb.len().unwrap(), size);
+        assert_eq!(trie.root_hash().unwrap(), expected);
+
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        trie.insert(b"a", b"b").unwrap();
+        assert_eq!(trie.compute_root().unwrap(), trie.root_hash().unwrap());
+    }
 
-    use keccak_hash::{keccak, H256};
+    #[test]
+    fn test_commit_with_report() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let report = trie.commit_with_report().unwrap();
+        assert_eq!(report.root, trie.root_hash().unwrap());
+        assert!(report.written.contains(&report.root));
+        assert!(report.deleted.is_empty());
+        for hash in report.written.iter() {
+            assert!(memdb.get(hash.as_bytes()).unwrap().is_some());
+        }
 
-    use super::{EthTrie, Trie};
-    use crate::db::{MemoryDB, DB};
-    use crate::errors::TrieError;
-    use crate::nibbles::Nibbles;
+        trie.insert(b"key-7", b"changed").unwrap();
+        let report2 = trie.commit_with_report().unwrap();
+        assert!(!report2.deleted.is_empty());
+        for hash in report2.deleted.iter() {
+            assert!(!report2.written.contains(hash));
+            assert!(memdb.get(hash.as_bytes()).unwrap().is_none());
+        }
+        for hash in report2.written.iter() {
+            assert!(memdb.get(hash.as_bytes()).unwrap().is_some());
+        }
+        assert_eq!(
+            EthTrie::new(memdb).at_root(report2.root).verify_integrity(),
+            Ok(())
+        );
+    }
 
     #[test]
-    fn test_trie_insert() {
+    fn test_verify_key_set() {
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb);
-        trie.insert(b"test", b"test").unwrap();
+        let mut expected = HashSet::new();
+        for i in 0..50u32 {
+            trie.insert(&i.to_be_bytes(), b"value").unwrap();
+            expected.insert(i.to_be_bytes().to_vec());
+        }
+        trie.root_hash().unwrap();
+        assert!(trie.verify_key_set(&expected).unwrap().is_empty());
+
+        expected.remove(&7u32.to_be_bytes()[..]);
+        expected.remove(&3u32.to_be_bytes()[..]);
+        expected.insert(b"not-in-trie".to_vec());
+
+        let diff = trie.verify_key_set(&expected).unwrap();
+        assert_eq!(diff.missing, vec![b"not-in-trie".to_vec()]);
+        assert_eq!(
+            diff.extra,
+            vec![3u32.to_be_bytes().to_vec(), 7u32.to_be_bytes().to_vec()]
+        );
     }
 
     #[test]
-    fn test_trie_get() {
+    fn test_is_empty_root() {
+        assert!(is_empty_root(EMPTY_TRIE_ROOT));
+        assert!(!is_empty_root(H256::zero()));
+        assert!(!is_empty_root(keccak(b"")));
+
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb);
+        assert!(trie.is_empty_trie());
+
         trie.insert(b"test", b"test").unwrap();
-        let v = trie.get(b"test").unwrap();
+        let root = trie.root_hash().unwrap();
+        assert!(!trie.is_empty_trie());
+        assert!(!is_empty_root(root));
 
-        assert_eq!(Some(b"test".to_vec()), v)
+        trie.remove(b"test").unwrap();
+        trie.root_hash().unwrap();
+        assert!(trie.is_empty_trie());
     }
 
     #[test]
-    fn test_trie_get_missing() {
+    fn test_extract_subtree() {
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb);
-        trie.insert(b"test", b"test").unwrap();
-        let v = trie.get(b"no-val").unwrap();
+        let mut keys = vec![];
+        for i in 0..100u32 {
+            let key = format!("account-{}/slot-{}", i % 3, i).into_bytes();
+            trie.insert(&key, format!("value-{}", i).as_bytes())
+                .unwrap();
+            keys.push(key);
+        }
+        trie.insert(b"other", b"value").unwrap();
+        trie.root_hash().unwrap();
 
-        assert_eq!(None, v)
+        let (subtree, stripped) = trie.extract_subtree(b"account-1/").unwrap().unwrap();
+        assert_eq!(stripped, Nibbles::from_raw(b"account-1/", false));
+        let mut count = 0;
+        for key in keys.iter().filter(|k| k.starts_with(b"account-1/")) {
+            let relative = &key[b"account-1/".len()..];
+            assert_eq!(subtree.get(relative).unwrap(), trie.get(key).unwrap());
+            count += 1;
+        }
+        assert_eq!(subtree.iter().count(), count);
+        assert_eq!(subtree.get(b"other").unwrap(), None);
+        // The subtree root is a new node, shortened from the node that held the prefix, so
+        // the subtree counts as committed only once that node is written
+        assert!(subtree.is_empty_trie());
+
+        // A prefix ending inside a leaf
+        let (subtree, _) = trie.extract_subtree(b"oth").unwrap().unwrap();
+        assert_eq!(subtree.get(b"er").unwrap(), Some(b"value".to_vec()));
+        assert!(subtree.is_empty_trie());
+
+        assert!(trie.extract_subtree(b"missing").unwrap().is_none());
+
+        // A subtree rooted at a stored branch is committed already
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        for i in 0..16u8 {
+            trie.insert(&[b'a', i << 4], b"really-long-value-to-prevent-inlining")
+                .unwrap();
+        }
+        trie.root_hash().unwrap();
+        let (subtree, _) = trie.extract_subtree(b"a").unwrap().unwrap();
+        assert!(!subtree.is_empty_trie());
+        assert_eq!(subtree.fork().unwrap().iter().count(), 16);
     }
 
-    fn corrupt_trie() -> (EthTrie<MemoryDB>, H256, H256) {
+    #[test]
+    fn test_with_prefix() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let corruptor_db = memdb.clone();
-        let mut trie = EthTrie::new(memdb);
-        trie.insert(b"test1-key", b"really-long-value1-to-prevent-inlining")
-            .unwrap();
-        trie.insert(b"test2-key", b"really-long-value2-to-prevent-inlining")
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..50u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
             .unwrap();
-        let actual_root_hash = trie.root_hash().unwrap();
-
-        // Manually corrupt the database by removing a trie node
-        // This is the hash for the leaf node for test2-key
-        let node_hash_to_delete = b"\xcb\x15v%j\r\x1e\te_TvQ\x8d\x93\x80\xd1\xa2\xd1\xde\xfb\xa5\xc3hJ\x8c\x9d\xb93I-\xbd";
-        assert_ne!(corruptor_db.get(node_hash_to_delete).unwrap(), None);
-        corruptor_db.remove(node_hash_to_delete).unwrap();
-        assert_eq!(corruptor_db.get(node_hash_to_delete).unwrap(), None);
+        }
+        let root = trie.root_hash().unwrap();
+
+        let mut prefixed = trie.at_root(root).with_prefix(b"ns/").unwrap();
+        let mut expected = EthTrie::new(memdb);
+        for i in 0..50u32 {
+            let key = format!("key-{}", i).into_bytes();
+            let value = trie.get(&key).unwrap();
+            assert_eq!(prefixed.get(&[b"ns/", &key[..]].concat()).unwrap(), value);
+            expected
+                .insert(&[b"ns/", &key[..]].concat(), &value.unwrap())
+                .unwrap();
+        }
+        assert_eq!(prefixed.get(b"key-1").unwrap(), None);
+        // The new root node is not in the db until the prefixed trie is committed
+        assert!(prefixed.is_empty_trie());
+        assert_eq!(prefixed.root_hash().unwrap(), expected.root_hash().unwrap());
+        assert!(!prefixed.is_empty_trie());
+
+        // Prefixing a single leaf, and leaving the original unchanged
+        let mut single = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        single.insert(b"key", b"value").unwrap();
+        let mut prefixed = single.with_prefix(b"ns/").unwrap();
+        prefixed.insert(b"ns/other", b"other").unwrap();
+        assert_eq!(prefixed.get(b"ns/key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(single.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(single.get(b"ns/other").unwrap(), None);
+
+        // Prefixing a committed branch root only writes the new extension node
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..16u8 {
+            trie.insert(&[i << 4], b"really-long-value-to-prevent-inlining")
+                .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let mut prefixed = trie.at_root(root).with_prefix(b"ns").unwrap();
+        let report = prefixed.commit_with_report().unwrap();
+        assert_eq!(report.written, vec![report.root]);
+        assert_eq!(prefixed.iter().count(), 16);
+        assert_eq!(
+            prefixed.get(b"ns\x10").unwrap(),
+            Some(b"really-long-value-to-prevent-inlining".to_vec())
+        );
+        assert_eq!(trie.verify_integrity(), Ok(()));
+    }
 
-        (
-            trie,
-            actual_root_hash,
-            H256::from_slice(node_hash_to_delete),
-        )
+    #[test]
+    fn test_seal() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        trie.insert(b"test", b"test").unwrap();
+        let root = trie.root_hash().unwrap();
+        trie.insert(b"test1", b"test1").unwrap();
+
+        let mut expected = trie.at_root(root);
+        expected.insert(b"test1", b"test1").unwrap();
+        let expected_root = expected.root_hash().unwrap();
+
+        // Pending changes are committed by the seal
+        let sealed = trie.seal().unwrap();
+        assert_eq!(sealed.root_hash(), expected_root);
+        assert_eq!(sealed.get(b"test").unwrap(), Some(b"test".to_vec()));
+        assert!(sealed.contains(b"test1").unwrap());
+        assert!(!sealed.contains(b"test2").unwrap());
+        assert_eq!(sealed.iter().count(), 2);
+
+        let proof = sealed.get_proof(b"test1").unwrap();
+        assert_eq!(proof, expected.get_proof(b"test1").unwrap());
+        assert_eq!(
+            expected
+                .verify_proof(sealed.root_hash(), b"test1", proof)
+                .unwrap(),
+            Some(b"test1".to_vec())
+        );
     }
 
     #[test]
-    /// When a database entry is missing, get returns a MissingTrieNode error
-    fn test_trie_get_corrupt() {
-        let (trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+    fn test_subtree_roots() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let mut rng = thread_rng();
+        for _ in 0..200 {
+            let key: Vec<u8> = (0..8).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+
+        let roots = trie.subtree_roots(0).unwrap();
+        assert_eq!(roots, vec![(Nibbles::from_hex(&[]), root)]);
+
+        let mut first_bytes: Vec<u8> = trie.iter().map(|(k, _)| k[0]).collect();
+        first_bytes.dedup();
+        let roots = trie.subtree_roots(2).unwrap();
+        assert_eq!(roots.len(), first_bytes.len());
+        for ((prefix, hash), byte) in roots.iter().zip(first_bytes) {
+            assert_eq!(prefix, &Nibbles::from_raw(&[byte], false));
+            let (mut subtree, _) = trie.extract_subtree(&[byte]).unwrap().unwrap();
+            assert_eq!(*hash, subtree.root_hash().unwrap());
+        }
 
-        let result = trie.get(b"test2-key");
+        let mut first_nibbles: Vec<u8> = trie.iter().map(|(k, _)| k[0] >> 4).collect();
+        first_nibbles.dedup();
+        assert_eq!(trie.subtree_roots(1).unwrap().len(), first_nibbles.len());
 
-        if let Err(missing_trie_node) = result {
-            let expected_error = TrieError::MissingTrieNode {
-                node_hash: deleted_node_hash,
-                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
-                root_hash: Some(actual_root_hash),
-                err_key: Some(b"test2-key".to_vec()),
-            };
-            assert_eq!(missing_trie_node, expected_error);
-        } else {
-            // The only acceptable result here was a MissingTrieNode
-            panic!(
-                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
-                result
-            );
-        }
+        // Below the keys there is nothing left
+        assert!(trie.subtree_roots(17).unwrap().is_empty());
     }
 
     #[test]
-    /// When a database entry is missing, delete returns a MissingTrieNode error
-    fn test_trie_delete_corrupt() {
-        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+    fn test_validate_store() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        assert!(EthTrie::validate_store(&memdb).unwrap().is_empty());
+
+        // Tamper with the root node
+        let root_data = memdb.get(root.as_bytes()).unwrap().unwrap();
+        let mut tampered = root_data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        memdb.insert(root.as_bytes(), tampered).unwrap();
+        assert_eq!(EthTrie::validate_store(&memdb).unwrap(), vec![root]);
+        memdb.insert(root.as_bytes(), root_data.clone()).unwrap();
+
+        // Remove a child of the root, leaving a dangling reference
+        let mut children = vec![];
+        decode_node(&root_data).unwrap().child_hashes(&mut children);
+        memdb.remove(children[0].as_bytes()).unwrap();
+        assert_eq!(EthTrie::validate_store(&memdb).unwrap(), vec![root]);
+
+        // Entries that are not nodes are ignored
+        memdb.insert(b"metadata", b"value".to_vec()).unwrap();
+        assert_eq!(EthTrie::validate_store(&memdb).unwrap(), vec![root]);
+    }
 
-        let result = trie.remove(b"test2-key");
+    #[test]
+    fn test_mark_and_sweep() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        let mut other = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), b"value")
+                .unwrap();
+            // Different values, so that no node is shared with the first trie
+            other
+                .insert(format!("other-{}", i).as_bytes(), b"other-value")
+                .unwrap();
+        }
+        let old_root = trie.commit().unwrap();
+        let other_root = other.commit().unwrap();
+        // Commits never free the old root node, so it is left unreachable
+        trie.insert(b"key-0", b"changed").unwrap();
+        let root = trie.commit().unwrap();
+        assert!(memdb.get(old_root.as_bytes()).unwrap().is_some());
+        memdb.insert(b"metadata", b"value".to_vec()).unwrap();
+
+        // A root that is not in the db is rejected before anything is removed
+        let size = memdb.len().unwrap();
+        assert!(EthTrie::mark_and_sweep(&memdb, &[root, H256::zero()]).is_err());
+        assert_eq!(memdb.len().unwrap(), size);
 
-        if let Err(missing_trie_node) = result {
-            let expected_error = TrieError::MissingTrieNode {
-                node_hash: deleted_node_hash,
-                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
-                root_hash: Some(actual_root_hash),
-                err_key: Some(b"test2-key".to_vec()),
-            };
-            assert_eq!(missing_trie_node, expected_error);
-        } else {
-            // The only acceptable result here was a MissingTrieNode
-            panic!(
-                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
-                result
-            );
+        assert_eq!(
+            EthTrie::mark_and_sweep(&memdb, &[root, other_root]).unwrap(),
+            1
+        );
+        assert!(memdb.get(old_root.as_bytes()).unwrap().is_none());
+        for root in [root, other_root].iter() {
+            let trie = EthTrie::new(memdb.clone()).at_root(*root);
+            assert_eq!(trie.verify_integrity(), Ok(()));
+            assert_eq!(trie.len().unwrap(), 100);
         }
+
+        // Dropping a root removes the nodes only it used
+        assert!(EthTrie::mark_and_sweep(&memdb, &[root]).unwrap() > 0);
+        assert_eq!(
+            EthTrie::new(memdb.clone()).at_root(root).verify_integrity(),
+            Ok(())
+        );
+        assert!(EthTrie::new(memdb.clone())
+            .at_root(other_root)
+            .verify_integrity()
+            .is_err());
+        assert!(EthTrie::validate_store(&memdb).unwrap().is_empty());
+        assert_eq!(memdb.get(b"metadata").unwrap(), Some(b"value".to_vec()));
     }
 
     #[test]
-    /// When a database entry is missing, delete returns a MissingTrieNode error
-    fn test_trie_delete_refactor_corrupt() {
-        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
-
-        let result = trie.remove(b"test1-key");
+    fn test_gc() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut empty = EthTrie::new(memdb.clone());
+        empty.commit().unwrap();
 
-        if let Err(missing_trie_node) = result {
-            let expected_error = TrieError::MissingTrieNode {
-                node_hash: deleted_node_hash,
-                traversed: None,
-                root_hash: Some(actual_root_hash),
-                err_key: Some(b"test1-key".to_vec()),
-            };
-            assert_eq!(missing_trie_node, expected_error);
-        } else {
-            // The only acceptable result here was a MissingTrieNode
-            panic!(
-                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
-                result
-            );
+        let mut trie = EthTrie::new(memdb.clone());
+        let mut abandoned = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), b"value")
+                .unwrap();
+            abandoned
+                .insert(format!("abandoned-{}", i).as_bytes(), b"abandoned-value")
+                .unwrap();
         }
+        let first_root = trie.commit().unwrap();
+        let abandoned_root = abandoned.commit().unwrap();
+        trie.insert(b"key-0", b"changed").unwrap();
+        let root = trie.commit().unwrap();
+
+        // Both the current root of the trie and the live roots are kept
+        assert!(trie.gc(&[first_root]).unwrap() > 0);
+        assert_eq!(trie.gc(&[first_root]).unwrap(), 0);
+        assert!(memdb.get(abandoned_root.as_bytes()).unwrap().is_none());
+        assert!(memdb.get(EMPTY_TRIE_ROOT.as_bytes()).unwrap().is_some());
+        assert_eq!(trie.verify_integrity(), Ok(()));
+        assert_eq!(trie.get(b"key-0").unwrap(), Some(b"changed".to_vec()));
+
+        assert_eq!(trie.gc(&[]).unwrap(), 1);
+        assert!(memdb.get(first_root.as_bytes()).unwrap().is_none());
+        assert_eq!(trie.root_hash().unwrap(), root);
+        assert!(EthTrie::validate_store(&memdb).unwrap().is_empty());
     }
 
-    #[test]
-    /// When a database entry is missing, get_proof returns a MissingTrieNode error
-    fn test_trie_get_proof_corrupt() {
-        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+    // Cancels the token after a fixed number of reads, which also lets tests count reads.
+    #[derive(Debug)]
+    struct CancellingDB {
+        db: MemoryDB,
+        token: CancellationToken,
+        reads_left: std::sync::atomic::AtomicUsize,
+    }
 
-        let result = trie.get_proof(b"test2-key");
+    impl DB for CancellingDB {
+        type Error = <MemoryDB as DB>::Error;
 
-        if let Err(missing_trie_node) = result {
-            let expected_error = TrieError::MissingTrieNode {
-                node_hash: deleted_node_hash,
-                traversed: None,
-                root_hash: Some(actual_root_hash),
-                err_key: Some(b"test2-key".to_vec()),
-            };
-            assert_eq!(missing_trie_node, expected_error);
-        } else {
-            // The only acceptable result here was a MissingTrieNode
-            panic!(
-                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
-                result
-            );
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+            if self.reads_left.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.token.cancel();
+            }
+            self.db.get(key)
         }
-    }
 
-    #[test]
-    /// When a database entry is missing, insert returns a MissingTrieNode error
-    fn test_trie_insert_corrupt() {
-        let (mut trie, actual_root_hash, deleted_node_hash) = corrupt_trie();
+        fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+            self.db.insert(key, value)
+        }
 
-        let result = trie.insert(b"test2-neighbor", b"any");
+        fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+            self.db.remove(key)
+        }
 
-        if let Err(missing_trie_node) = result {
-            let expected_error = TrieError::MissingTrieNode {
-                node_hash: deleted_node_hash,
-                traversed: Some(Nibbles::from_hex(&[7, 4, 6, 5, 7, 3, 7, 4, 3, 2])),
-                root_hash: Some(actual_root_hash),
-                err_key: Some(b"test2-neighbor".to_vec()),
-            };
-            assert_eq!(missing_trie_node, expected_error);
-        } else {
-            // The only acceptable result here was a MissingTrieNode
-            panic!(
-                "Must get a MissingTrieNode when database entry is missing, but got {:?}",
-                result
-            );
+        fn flush(&self) -> Result<(), Self::Error> {
+            self.db.flush()
+        }
+
+        fn keys(&self) -> TrieResult<Vec<Vec<u8>>> {
+            self.db.keys()
+        }
+
+        fn len(&self) -> Result<usize, Self::Error> {
+            self.db.len()
+        }
+
+        fn is_empty(&self) -> Result<bool, Self::Error> {
+            self.db.is_empty()
         }
     }
 
     #[test]
-    fn test_trie_random_insert() {
+    fn test_verify_integrity() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = EthTrie::new(memdb);
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        assert_eq!(trie.verify_integrity(), Ok(()));
 
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(30)
-                .map(char::from)
-                .collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val, val).unwrap();
+        let token = CancellationToken::new();
+        token.cancel();
+        assert_eq!(
+            trie.verify_integrity_cancellable(&token),
+            Err(TrieError::Cancelled)
+        );
+        assert_eq!(
+            EthTrie::validate_store_cancellable(&memdb, &token),
+            Err(TrieError::Cancelled)
+        );
 
-            let v = trie.get(val).unwrap();
-            assert_eq!(v.map(|v| v.to_vec()), Some(val.to_vec()));
+        let mut children = vec![];
+        decode_node(&memdb.get(root.as_bytes()).unwrap().unwrap())
+            .unwrap()
+            .child_hashes(&mut children);
+        // A node whose data no longer matches the hash its parent refers to
+        let data = memdb.get(children[0].as_bytes()).unwrap().unwrap();
+        let mut tampered = data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        memdb.insert(children[0].as_bytes(), tampered).unwrap();
+        assert_eq!(
+            trie.at_root(root).verify_integrity(),
+            Err(TrieError::InvalidData {
+                node_hash: Some(children[0]),
+                len: data.len(),
+            })
+        );
+        memdb.insert(children[0].as_bytes(), data).unwrap();
+        assert_eq!(trie.at_root(root).verify_integrity(), Ok(()));
+
+        memdb.remove(children[0].as_bytes()).unwrap();
+        match trie.at_root(root).verify_integrity() {
+            Err(TrieError::MissingTrieNode { node_hash, .. }) => assert_eq!(node_hash, children[0]),
+            other => panic!("unexpected result {:?}", other),
         }
     }
 
     #[test]
-    fn test_trie_contains() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = EthTrie::new(memdb);
-        trie.insert(b"test", b"test").unwrap();
-        assert!(trie.contains(b"test").unwrap());
-        assert!(!trie.contains(b"test2").unwrap());
+    fn test_verify_integrity_cancelled_mid_walk() {
+        let token = CancellationToken::new();
+        let db = Arc::new(CancellingDB {
+            db: MemoryDB::new(true),
+            token: token.clone(),
+            reads_left: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        });
+        let mut trie = EthTrie::new(db.clone());
+        for i in 0..1000u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        db.reads_left.store(3, Ordering::SeqCst);
+        assert_eq!(
+            trie.verify_integrity_cancellable(&token),
+            Err(TrieError::Cancelled)
+        );
+        // The walk stopped right after the read that cancelled it
+        assert_eq!(db.reads_left.load(Ordering::SeqCst), 0);
     }
 
-    #[test]
-    fn test_trie_remove() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = EthTrie::new(memdb);
-        trie.insert(b"test", b"test").unwrap();
-        let removed = trie.remove(b"test").unwrap();
-        assert!(removed)
+    // A committed trie of 1000 keys on a db whose token is not cancelled yet.
+    fn cancelling_trie() -> (Arc<CancellingDB>, EthTrie<CancellingDB>, CancellationToken) {
+        let token = CancellationToken::new();
+        let db = Arc::new(CancellingDB {
+            db: MemoryDB::new(true),
+            token: token.clone(),
+            reads_left: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        });
+        let mut trie = EthTrie::new(db.clone());
+        for i in 0..1000u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        (db, trie.at_root(root), token)
     }
 
     #[test]
-    fn test_trie_random_remove() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = EthTrie::new(memdb);
-
-        for _ in 0..1000 {
-            let rand_str: String = thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(30)
-                .map(char::from)
-                .collect();
-            let val = rand_str.as_bytes();
-            trie.insert(val, val).unwrap();
+    fn test_iter_cancelled_mid_walk() {
+        let (db, trie, token) = cancelling_trie();
+        assert_eq!(trie.iter().cancellable(&token).count(), 1000);
+
+        db.reads_left.store(5, Ordering::SeqCst);
+        let mut iter = trie.iter().cancellable(&token);
+        let count = iter.by_ref().count();
+        assert!(count > 0 && count < 1000);
+        assert_eq!(iter.error(), Some(&TrieError::Cancelled));
+        assert_eq!(db.reads_left.load(Ordering::SeqCst), 0);
+        // Iterators without the token are not affected
+        assert_eq!(trie.iter().count(), 1000);
+    }
 
-            let removed = trie.remove(val).unwrap();
-            assert!(removed);
-        }
+    #[test]
+    fn test_stats_cancelled_mid_walk() {
+        let (db, trie, token) = cancelling_trie();
+        let stats = trie.stats_cancellable(&token).unwrap();
+
+        db.reads_left.store(3, Ordering::SeqCst);
+        assert_eq!(trie.stats_cancellable(&token), Err(TrieError::Cancelled));
+        assert_eq!(db.reads_left.load(Ordering::SeqCst), 0);
+        assert_eq!(trie.stats().unwrap(), stats);
     }
 
     #[test]
-    fn test_trie_at_root_six_keys() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let root = {
-            let mut trie = EthTrie::new(memdb.clone());
-            trie.insert(b"test", b"test").unwrap();
-            trie.insert(b"test1", b"test").unwrap();
-            trie.insert(b"test2", b"test").unwrap();
-            trie.insert(b"test23", b"test").unwrap();
-            trie.insert(b"test33", b"test").unwrap();
-            trie.insert(b"test44", b"test").unwrap();
-            trie.root_hash().unwrap()
-        };
+    fn test_gc_cancelled_mid_walk() {
+        let (db, mut trie, token) = cancelling_trie();
+        let first_root = trie.root_hash().unwrap();
+        for i in 0..100u32 {
+            trie.insert(format!("key-{}", i).as_bytes(), b"changed")
+                .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let len = db.db.len().unwrap();
+
+        // Cancelled while marking, before anything is removed
+        db.reads_left.store(3, Ordering::SeqCst);
+        assert_eq!(trie.gc_cancellable(&[], &token), Err(TrieError::Cancelled));
+        assert_eq!(db.db.len().unwrap(), len);
+        db.reads_left.store(3, Ordering::SeqCst);
+        assert_eq!(
+            EthTrie::mark_and_sweep_cancellable(&db, &[root], &token),
+            Err(TrieError::Cancelled)
+        );
+        assert_eq!(db.db.len().unwrap(), len);
 
-        let mut trie = EthTrie::new(memdb).at_root(root);
-        let v1 = trie.get(b"test33").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v1);
-        let v2 = trie.get(b"test44").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v2);
-        let root2 = trie.root_hash().unwrap();
-        assert_eq!(hex::encode(root), hex::encode(root2));
+        assert!(trie.gc(&[first_root]).is_ok());
+        assert_eq!(db.db.len().unwrap(), len);
+        assert!(EthTrie::mark_and_sweep(&db, &[root]).unwrap() > 0);
+        assert_eq!(trie.verify_integrity(), Ok(()));
     }
 
     #[test]
-    fn test_trie_at_root_and_insert() {
-        let memdb = Arc::new(MemoryDB::new(true));
-        let root = {
-            let mut trie = EthTrie::new(Arc::clone(&memdb));
-            trie.insert(b"test", b"test").unwrap();
-            trie.insert(b"test1", b"test").unwrap();
-            trie.insert(b"test2", b"test").unwrap();
-            trie.insert(b"test23", b"test").unwrap();
-            trie.insert(b"test33", b"test").unwrap();
-            trie.insert(b"test44", b"test").unwrap();
-            trie.root_hash().unwrap()
-        };
+    fn test_insert_batch() {
+        let mut rng = thread_rng();
+        let mut entries = vec![];
+        for i in 0..2000u32 {
+            let key: Vec<u8> = (0..rng.gen_range(1..6))
+                .map(|_| rng.gen_range(0..4))
+                .collect();
+            let value = if i % 10 == 0 {
+                vec![]
+            } else {
+                format!("value-{}", i).into_bytes()
+            };
+            entries.push((key, value));
+        }
 
-        let mut trie = EthTrie::new(memdb).at_root(root);
-        trie.insert(b"test55", b"test55").unwrap();
-        trie.root_hash().unwrap();
-        let v = trie.get(b"test55").unwrap();
-        assert_eq!(Some(b"test55".to_vec()), v);
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut expected = EthTrie::new(memdb.clone());
+        let mut trie = EthTrie::new(memdb.clone());
+        for (key, value) in entries[..1000].iter() {
+            expected.insert(key, value).unwrap();
+        }
+        trie.insert_batch(entries[..1000].to_vec()).unwrap();
+        let root = trie.root_hash().unwrap();
+        assert_eq!(root, expected.root_hash().unwrap());
+
+        // Into a trie loaded from the db
+        for (key, value) in entries[1000..].iter() {
+            expected.insert(key, value).unwrap();
+        }
+        let mut trie = trie.at_root(root);
+        trie.insert_batch(entries[1000..].to_vec()).unwrap();
+        assert_eq!(trie.root_hash().unwrap(), expected.root_hash().unwrap());
+        for (key, _) in entries.iter() {
+            assert_eq!(trie.get(key).unwrap(), expected.get(key).unwrap());
+        }
+
+        let root = trie.root_hash().unwrap();
+        let mut trie = trie.with_delete_on_empty_value(false);
+        assert_eq!(
+            trie.insert_batch(vec![
+                (b"new".to_vec(), b"value".to_vec()),
+                (b"empty".to_vec(), vec![])
+            ]),
+            Err(TrieError::EmptyValue)
+        );
+        assert_eq!(trie.root_hash().unwrap(), root);
     }
 
     #[test]
-    fn test_trie_at_root_and_delete() {
+    fn test_remove_branch_value() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let root = {
-            let mut trie = EthTrie::new(Arc::clone(&memdb));
-            trie.insert(b"test", b"test").unwrap();
-            trie.insert(b"test1", b"test").unwrap();
-            trie.insert(b"test2", b"test").unwrap();
-            trie.insert(b"test23", b"test").unwrap();
-            trie.insert(b"test33", b"test").unwrap();
-            trie.insert(b"test44", b"test").unwrap();
-            trie.root_hash().unwrap()
-        };
-
-        let mut trie = EthTrie::new(memdb).at_root(root);
-        let removed = trie.remove(b"test44").unwrap();
-        assert!(removed);
-        let removed = trie.remove(b"test33").unwrap();
-        assert!(removed);
-        let removed = trie.remove(b"test23").unwrap();
-        assert!(removed);
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"key", b"value").unwrap();
+        trie.insert(b"key1", b"value1").unwrap();
+        assert!(trie.remove(b"key").unwrap());
+        assert!(!trie.remove(b"key").unwrap());
+
+        let mut expected = EthTrie::new(memdb);
+        expected.insert(b"key1", b"value1").unwrap();
+        assert_eq!(trie.root_hash().unwrap(), expected.root_hash().unwrap());
     }
 
     #[test]
-    fn test_multiple_trie_roots() {
-        let k0: ethereum_types::H256 = ethereum_types::H256::zero();
-        let k1: ethereum_types::H256 = ethereum_types::H256::random();
-        let v: ethereum_types::H256 = ethereum_types::H256::random();
-
-        let root1 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie = EthTrie::new(memdb);
-            trie.insert(k0.as_bytes(), v.as_bytes()).unwrap();
-            trie.root_hash().unwrap()
-        };
+    fn test_remove_existing() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}-long-enough-to-be-hashed", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let len = memdb.len().unwrap();
 
-        let root2 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie = EthTrie::new(memdb);
-            trie.insert(k0.as_bytes(), v.as_bytes()).unwrap();
-            trie.insert(k1.as_bytes(), v.as_bytes()).unwrap();
-            trie.root_hash().unwrap();
-            trie.remove(k1.as_ref()).unwrap();
-            trie.root_hash().unwrap()
-        };
+        // A missing key below stored nodes leaves them all in place
+        let mut trie = trie.at_root(root);
+        assert_eq!(
+            trie.remove_existing(b"key-1000"),
+            Err(TrieError::KeyNotFound)
+        );
+        assert_eq!(trie.remove_existing(b"other"), Err(TrieError::KeyNotFound));
+        assert_eq!(trie.root_hash().unwrap(), root);
+        assert_eq!(memdb.len().unwrap(), len);
+        let mut trie = trie.at_root(root);
+        for i in 0..100u32 {
+            assert!(trie.contains(format!("key-{}", i).as_bytes()).unwrap());
+        }
 
-        let root3 = {
-            let memdb = Arc::new(MemoryDB::new(true));
-            let mut trie1 = EthTrie::new(Arc::clone(&memdb));
-            trie1.insert(k0.as_bytes(), v.as_bytes()).unwrap();
-            trie1.insert(k1.as_bytes(), v.as_bytes()).unwrap();
-            trie1.root_hash().unwrap();
-            let root = trie1.root_hash().unwrap();
-            let mut trie2 = trie1.at_root(root);
-            trie2.remove(k1.as_bytes()).unwrap();
-            trie2.root_hash().unwrap()
-        };
+        trie.remove_existing(b"key-7").unwrap();
+        assert_eq!(trie.get(b"key-7").unwrap(), None);
+        assert_eq!(trie.remove_existing(b"key-7"), Err(TrieError::KeyNotFound));
+        assert!(!trie.remove(b"key-7").unwrap());
+    }
 
-        assert_eq!(root1, root2);
-        assert_eq!(root2, root3);
+    #[test]
+    fn test_iter_from() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let mut rng = thread_rng();
+        let mut keys = vec![];
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+            keys.push(key);
+        }
+        keys.sort();
+        keys.dedup();
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        let mut starts = vec![vec![], vec![0xff, 0xff, 0xff, 0xff], keys[0].clone()];
+        for _ in 0..200 {
+            starts.push(keys[rng.gen_range(0..keys.len())].clone());
+            starts.push((0..rng.gen_range(1..5)).map(|_| rng.gen()).collect());
+        }
+        for start in starts {
+            let expected: Vec<Vec<u8>> = keys.iter().filter(|k| **k >= start).cloned().collect();
+            let found: Vec<Vec<u8>> = trie.iter_from(&start).map(|(k, _)| k).collect();
+            assert_eq!(found, expected, "start {:?}", start);
+        }
     }
 
     #[test]
-    fn test_delete_stale_keys_with_random_insert_and_delete() {
+    fn test_count_range() {
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb);
+        assert_eq!(trie.count_range(b"", b"\xff").unwrap(), 0);
+        let mut rng = thread_rng();
+        let mut keys = vec![];
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+            keys.push(key);
+        }
+        keys.sort();
+        keys.dedup();
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        // The whole trie, and empty ranges
+        assert_eq!(trie.count_range(b"", &[0xff; 4]).unwrap(), keys.len());
+        assert_eq!(trie.count_range(&keys[0], &keys[0]).unwrap(), 0);
+        assert_eq!(trie.count_range(&[0xff; 4], b"").unwrap(), 0);
+        assert_eq!(trie.count_range(&[0xff; 4], &[0xff; 5]).unwrap(), 0);
+
+        for _ in 0..200 {
+            let mut bounds = [
+                keys[rng.gen_range(0..keys.len())].clone(),
+                (0..rng.gen_range(1..5)).map(|_| rng.gen()).collect(),
+            ];
+            bounds.sort();
+            let (start, end) = (&bounds[0], &bounds[1]);
+            let expected = keys.iter().filter(|k| *k >= start && *k < end).count();
+            assert_eq!(trie.count_range(start, end).unwrap(), expected);
+        }
+    }
 
-        let mut rng = rand::thread_rng();
+    #[test]
+    fn test_iter_prefix() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        let mut rng = thread_rng();
         let mut keys = vec![];
-        for _ in 0..100 {
-            let random_bytes: Vec<u8> = (0..rng.gen_range(2..30))
-                .map(|_| rand::random::<u8>())
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+            keys.push(key);
+        }
+        keys.sort();
+        keys.dedup();
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        let mut prefixes = vec![vec![], vec![0xff, 0xff, 0xff, 0xff]];
+        for _ in 0..200 {
+            let key = &keys[rng.gen_range(0..keys.len())];
+            prefixes.push(key[..rng.gen_range(0..=key.len())].to_vec());
+            prefixes.push((0..rng.gen_range(1..3)).map(|_| rng.gen()).collect());
+        }
+        for prefix in prefixes {
+            let expected: Vec<Vec<u8>> = keys
+                .iter()
+                .filter(|k| k.starts_with(&prefix))
+                .cloned()
                 .collect();
-            trie.insert(&random_bytes, &random_bytes).unwrap();
-            keys.push(random_bytes.clone());
+            let found: Vec<Vec<u8>> = trie.iter_prefix(&prefix).map(|(k, _)| k).collect();
+            assert_eq!(found, expected, "prefix {:?}", prefix);
         }
-        trie.root_hash().unwrap();
-        let slice = &mut keys;
-        slice.shuffle(&mut rng);
 
-        for key in slice.iter() {
-            trie.remove(key).unwrap();
-        }
-        trie.root_hash().unwrap();
+        // A prefix ending inside an extension, and one ending at a branch value
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new(true)));
+        trie.insert(b"abc1", b"1").unwrap();
+        trie.insert(b"abc2", b"2").unwrap();
+        trie.insert(b"abc", b"3").unwrap();
+        trie.insert(b"b", b"4").unwrap();
+        let found: Vec<Vec<u8>> = trie.iter_prefix(b"a").map(|(k, _)| k).collect();
+        assert_eq!(
+            found,
+            vec![b"abc".to_vec(), b"abc1".to_vec(), b"abc2".to_vec()]
+        );
+        let found: Vec<(Vec<u8>, Vec<u8>)> = trie.iter_prefix(b"abc").collect();
+        assert_eq!(found[0], (b"abc".to_vec(), b"3".to_vec()));
+        assert_eq!(found.len(), 3);
+        assert_eq!(trie.iter_prefix(b"ab").count(), 3);
+        assert_eq!(trie.iter_prefix(b"abd").count(), 0);
+        assert_eq!(
+            trie.iter_prefix(b"b").collect::<Vec<_>>(),
+            vec![(b"b".to_vec(), b"4".to_vec())]
+        );
+    }
 
-        let empty_node_key = keccak(&rlp::NULL_RLP);
-        let value = trie.db.get(empty_node_key.as_ref()).unwrap().unwrap();
-        assert_eq!(value, &rlp::NULL_RLP)
+    #[test]
+    fn test_iter_max_depth() {
+        let remote = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(remote.clone());
+        let mut rng = thread_rng();
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = trie.iter().collect();
+
+        let mut last = 0;
+        for depth in 0..8 {
+            let found: Vec<(Vec<u8>, Vec<u8>)> = trie.iter_max_depth(depth).collect();
+            assert!(found.len() >= last);
+            assert!(found.iter().all(|entry| entries.contains(entry)));
+            last = found.len();
+        }
+        assert_eq!(trie.iter_max_depth(usize::MAX).collect::<Vec<_>>(), entries);
+        assert_eq!(trie.iter_max_depth(6).collect::<Vec<_>>(), entries);
+
+        // Only the nodes within the bound are loaded
+        let local = Arc::new(MemoryDB::new(true));
+        local
+            .insert(
+                root.as_bytes(),
+                remote.get(root.as_bytes()).unwrap().unwrap(),
+            )
+            .unwrap();
+        let mut trie = EthTrie::new(local).at_root(root);
+        let fetched = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fetched.clone();
+        trie.set_node_fetcher(Box::new(move |hash| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            remote.get(hash.as_bytes()).unwrap()
+        }));
+        assert_eq!(trie.iter_max_depth(0).count(), 0);
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+        trie.iter_max_depth(1).count();
+        assert_eq!(fetched.load(Ordering::SeqCst), 16);
     }
 
     #[test]
-    fn insert_full_branch() {
+    fn test_into_iter() {
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb);
+        let mut rng = thread_rng();
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1..4)).map(|_| rng.gen()).collect();
+            trie.insert(&key, &key).unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        // Some committed nodes, some changed in memory and some shared with a clone
+        trie.insert(b"key", b"value").unwrap();
+        trie.insert(b"\x00\x01", b"changed").unwrap();
+        let clone = trie.clone();
+        trie.insert(b"other", b"value").unwrap();
+
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = trie.iter().collect();
+        let mut iter = trie.into_iter();
+        assert_eq!(iter.by_ref().collect::<Vec<_>>(), entries);
+        assert!(iter.error().is_none());
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = clone.iter().collect();
+        assert_eq!(clone.into_iter().collect::<Vec<_>>(), expected);
+        let trie = EthTrie::new(Arc::new(MemoryDB::new(true))).at_root(root);
+        assert_eq!(trie.into_iter().count(), 0);
+    }
 
-        trie.insert(b"test", b"test").unwrap();
-        trie.insert(b"test1", b"test").unwrap();
-        trie.insert(b"test2", b"test").unwrap();
-        trie.insert(b"test23", b"test").unwrap();
-        trie.insert(b"test33", b"test").unwrap();
-        trie.insert(b"test44", b"test").unwrap();
-        trie.root_hash().unwrap();
+    #[test]
+    fn test_iter_refs() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        // Stored in a branch node
+        trie.insert(b"key", b"value").unwrap();
+        let root = trie.root_hash().unwrap();
+        let mut trie = trie.at_root(root);
+
+        let refs: Vec<(Vec<u8>, super::ValueGuard)> = trie.iter_refs().collect();
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = trie.iter().collect();
+        assert_eq!(refs.len(), entries.len());
+        for ((key, guard), (expected_key, expected_value)) in refs.iter().zip(entries.iter()) {
+            assert_eq!(key, expected_key);
+            assert_eq!(&guard[..], &expected_value[..]);
+        }
 
-        let v = trie.get(b"test").unwrap();
-        assert_eq!(Some(b"test".to_vec()), v);
+        // The guards outlive changes to the trie
+        trie.insert(b"key-7", b"changed").unwrap();
+        let (_, guard) = refs.iter().find(|(k, _)| k == b"key-7").unwrap();
+        assert_eq!(&guard[..], b"value-7");
     }
 
     #[test]
-    fn iterator_trie() {
+    fn test_keys_and_values() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let root1: H256;
-        let mut kv = HashMap::new();
-        kv.insert(b"test".to_vec(), b"test".to_vec());
-        kv.insert(b"test1".to_vec(), b"test1".to_vec());
-        kv.insert(b"test11".to_vec(), b"test2".to_vec());
-        kv.insert(b"test14".to_vec(), b"test3".to_vec());
-        kv.insert(b"test16".to_vec(), b"test4".to_vec());
-        kv.insert(b"test18".to_vec(), b"test5".to_vec());
-        kv.insert(b"test2".to_vec(), b"test6".to_vec());
-        kv.insert(b"test23".to_vec(), b"test7".to_vec());
-        kv.insert(b"test9".to_vec(), b"test8".to_vec());
-        {
-            let mut trie = EthTrie::new(memdb.clone());
-            let mut kv = kv.clone();
-            kv.iter().for_each(|(k, v)| {
-                trie.insert(k, v).unwrap();
-            });
-            root1 = trie.root_hash().unwrap();
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        trie.insert(b"key", b"value").unwrap();
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        let (keys, values): (Vec<_>, Vec<_>) = trie.iter().unzip();
+        assert_eq!(keys.len(), 101);
+        assert_eq!(trie.keys().collect::<Vec<_>>(), keys);
+        assert_eq!(trie.values().collect::<Vec<_>>(), values);
+    }
 
-            trie.iter()
-                .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
-            assert!(kv.is_empty());
+    #[test]
+    fn test_iter_nibbles() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
         }
+        trie.insert(b"key", b"value").unwrap();
+        let root = trie.root_hash().unwrap();
+        let trie = trie.at_root(root);
+
+        let entries: Vec<_> = trie.iter().collect();
+        let paths: Vec<_> = trie.iter_nibbles().collect();
+        assert_eq!(paths.len(), 101);
+        for ((path, value), (key, expected)) in paths.into_iter().zip(entries) {
+            assert_eq!(path, Nibbles::from_raw(&key, true));
+            assert_eq!(path.encode_raw().0, key);
+            assert_eq!(value, expected);
+        }
+    }
 
-        {
-            let mut trie = EthTrie::new(memdb.clone());
-            let mut kv2 = HashMap::new();
-            kv2.insert(b"test".to_vec(), b"test11".to_vec());
-            kv2.insert(b"test1".to_vec(), b"test12".to_vec());
-            kv2.insert(b"test14".to_vec(), b"test13".to_vec());
-            kv2.insert(b"test22".to_vec(), b"test14".to_vec());
-            kv2.insert(b"test9".to_vec(), b"test15".to_vec());
-            kv2.insert(b"test16".to_vec(), b"test16".to_vec());
-            kv2.insert(b"test2".to_vec(), b"test17".to_vec());
-            kv2.iter().for_each(|(k, v)| {
-                trie.insert(k, v).unwrap();
-            });
+    #[test]
+    fn test_len() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb);
+        assert!(trie.is_empty());
+        assert_eq!(trie.len().unwrap(), 0);
+
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
+        }
+        // Stored in a branch node, since other keys extend it
+        trie.insert(b"key-1", b"value").unwrap();
+        trie.insert(b"key", b"value").unwrap();
+        assert!(!trie.is_empty());
+        assert_eq!(trie.len().unwrap(), 101);
+
+        let root = trie.root_hash().unwrap();
+        assert_eq!(trie.at_root(root).len().unwrap(), 101);
+        assert!(trie.at_root(EMPTY_TRIE_ROOT).is_empty());
+    }
 
-            trie.root_hash().unwrap();
+    #[test]
+    fn test_contains_prefix() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        assert!(!trie.contains_prefix(b"").unwrap());
+
+        trie.insert(b"account-1/slot-1", b"value").unwrap();
+        assert!(trie.contains_prefix(b"").unwrap());
+        assert!(trie.contains_prefix(b"account").unwrap());
+        assert!(trie.contains_prefix(b"account-1/slot-1").unwrap());
+        assert!(!trie.contains_prefix(b"account-1/slot-10").unwrap());
+        assert!(!trie.contains_prefix(b"account-2").unwrap());
+
+        trie.insert(b"account-1/slot-2", b"value").unwrap();
+        trie.insert(b"account-1/", b"branch-value").unwrap();
+        trie.insert(b"account-3/slot-1", b"value").unwrap();
+        let root = trie.commit().unwrap();
+        let trie = EthTrie::new(memdb).at_root(root);
+
+        // Ending inside an extension, at a branch, and at a branch value
+        assert!(trie.contains_prefix(b"acc").unwrap());
+        assert!(trie.contains_prefix(b"account-1/slot-").unwrap());
+        assert!(trie.contains_prefix(b"account-1/").unwrap());
+        assert!(trie.contains_prefix(b"account-3/").unwrap());
+        assert!(!trie.contains_prefix(b"account-2/").unwrap());
+        assert!(!trie.contains_prefix(b"account-1/slot-3").unwrap());
+        assert!(!trie.contains_prefix(b"other").unwrap());
+    }
 
-            let mut kv_delete = HashSet::new();
-            kv_delete.insert(b"test".to_vec());
-            kv_delete.insert(b"test1".to_vec());
-            kv_delete.insert(b"test14".to_vec());
+    #[test]
+    fn test_remove_prefix() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        let expected_db = Arc::new(MemoryDB::new(true));
+        let mut expected = EthTrie::new(expected_db.clone());
+        for i in 0..300u32 {
+            let key = format!("account-{}/slot-{}", i % 3, i).into_bytes();
+            let value = format!("value-{}", i).into_bytes();
+            trie.insert(&key, &value).unwrap();
+            if i % 3 != 1 {
+                expected.insert(&key, &value).unwrap();
+            }
+        }
+        // A value stored in the branch the prefix leads to
+        trie.insert(b"account-1/", b"value").unwrap();
+        trie.insert(b"other", b"value").unwrap();
+        expected.insert(b"other", b"value").unwrap();
+        let old_root = trie.root_hash().unwrap();
+
+        assert_eq!(trie.remove_prefix(b"account-1/").unwrap(), 101);
+        assert_eq!(trie.remove_prefix(b"account-1/").unwrap(), 0);
+        assert_eq!(trie.root_hash().unwrap(), expected.root_hash().unwrap());
+        assert_eq!(trie.verify_integrity(), Ok(()));
+
+        // The nodes of the removed entries are freed. Commits never free the old root.
+        let mut stale: Vec<Vec<u8>> = memdb
+            .keys()
+            .unwrap()
+            .into_iter()
+            .filter(|k| expected_db.get(k).unwrap().is_none())
+            .collect();
+        stale.retain(|k| k[..] != old_root[..]);
+        assert!(stale.is_empty());
+
+        // A prefix that diverges from, or ends inside, the nibbles of an extension
+        assert_eq!(trie.remove_prefix(b"accz").unwrap(), 0);
+        assert_eq!(trie.remove_prefix(b"acc").unwrap(), 200);
+        assert_eq!(trie.remove_prefix(b"").unwrap(), 1);
+        assert!(trie.is_empty());
+        assert_eq!(trie.root_hash().unwrap(), EMPTY_TRIE_ROOT);
+    }
 
-            kv_delete.iter().for_each(|k| {
-                trie.remove(k).unwrap();
-            });
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_root_hash_parallel() {
+        let sequential_db = Arc::new(MemoryDB::new(false));
+        let parallel_db = Arc::new(MemoryDB::new(false));
+        let mut sequential = EthTrie::new(sequential_db.clone());
+        let mut parallel = EthTrie::new(parallel_db.clone());
+
+        let mut rng = thread_rng();
+        for round in 0..2 {
+            for _ in 0..10_000 {
+                let key: Vec<u8> = (0..rng.gen_range(1..32)).map(|_| rng.gen()).collect();
+                let value: Vec<u8> = (0..rng.gen_range(1..40)).map(|_| rng.gen()).collect();
+                sequential.insert(&key, &value).unwrap();
+                parallel.insert(&key, &value).unwrap();
+            }
+            assert_eq!(
+                parallel.root_hash_parallel().unwrap(),
+                sequential.root_hash().unwrap(),
+                "round {}",
+                round
+            );
+        }
 
-            kv2.retain(|k, _| !kv_delete.contains(k));
+        let mut sequential_keys = sequential_db.keys().unwrap();
+        let mut parallel_keys = parallel_db.keys().unwrap();
+        sequential_keys.sort();
+        parallel_keys.sort();
+        assert_eq!(parallel_keys, sequential_keys);
+    }
 
-            trie.root_hash().unwrap();
-            trie.iter()
-                .for_each(|(k, v)| assert_eq!(kv2.remove(&k).unwrap(), v));
-            assert!(kv2.is_empty());
+    #[test]
+    fn test_clone() {
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = EthTrie::new(memdb);
+        for i in 0..100u32 {
+            trie.insert(
+                format!("key-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+            .unwrap();
         }
-
-        let trie = EthTrie::new(memdb).at_root(root1);
-        trie.iter()
-            .for_each(|(k, v)| assert_eq!(kv.remove(&k).unwrap(), v));
-        assert!(kv.is_empty());
+        trie.root_hash().unwrap();
+        trie.insert(b"pending", b"value").unwrap();
+
+        let mut copy = trie.clone();
+        copy.insert(b"key-1", b"changed").unwrap();
+        copy.remove(b"key-2").unwrap();
+        assert_eq!(trie.get(b"key-1").unwrap(), Some(b"value-1".to_vec()));
+        assert_eq!(trie.get(b"key-2").unwrap(), Some(b"value-2".to_vec()));
+        assert_eq!(copy.get(b"pending").unwrap(), Some(b"value".to_vec()));
+
+        let copy_root = copy.root_hash().unwrap();
+        let root = trie.root_hash().unwrap();
+        assert_ne!(root, copy_root);
+        assert_eq!(copy.get(b"key-1").unwrap(), Some(b"changed".to_vec()));
+        assert_eq!(
+            trie.at_root(root).get(b"key-1").unwrap(),
+            Some(b"value-1".to_vec())
+        );
     }
 
     #[test]
-    fn test_small_trie_at_root() {
+    fn test_open_latest() {
         let memdb = Arc::new(MemoryDB::new(true));
-        let mut trie = EthTrie::new(memdb.clone());
-        trie.insert(b"key", b"val").unwrap();
-        let new_root_hash = trie.commit().unwrap();
+        let trie = EthTrie::open_latest(memdb.clone()).unwrap();
+        assert!(trie.is_empty());
 
-        let empty_trie = EthTrie::new(memdb);
-        // Can't find key in new trie at empty root
-        assert_eq!(empty_trie.get(b"key").unwrap(), None);
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"test", b"test").unwrap();
+        trie.root_hash().unwrap();
+        trie.insert(b"test1", b"test1").unwrap();
+        let root = trie.root_hash().unwrap();
 
-        let trie_view = empty_trie.at_root(new_root_hash);
-        assert_eq!(&trie_view.get(b"key").unwrap().unwrap(), b"val");
+        let mut trie = EthTrie::open_latest(memdb.clone()).unwrap();
+        assert_eq!(trie.get(b"test1").unwrap(), Some(b"test1".to_vec()));
+        assert_eq!(trie.root_hash().unwrap(), root);
 
-        // Previous trie was not modified
-        assert_eq!(empty_trie.get(b"key").unwrap(), None);
+        // The entry is not a node
+        assert!(EthTrie::validate_store(&memdb).unwrap().is_empty());
     }
 
     #[test]
-    fn test_large_trie_at_root() {
+    fn test_root_node() {
         let memdb = Arc::new(MemoryDB::new(true));
         let mut trie = EthTrie::new(memdb.clone());
-        trie.insert(
-            b"pretty-long-key",
-            b"even-longer-val-to-go-more-than-32-bytes",
-        )
-        .unwrap();
-        let new_root_hash = trie.commit().unwrap();
+        for i in 0..100u32 {
+            let value = format!("value-{}-long-enough-to-be-hashed", i);
+            trie.insert(format!("key-{}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        trie.insert(b"key", b"branch-value").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // Walk the whole trie, rebuilding the key of every value
+        let mut found = vec![];
+        let mut stack = vec![(Nibbles::from_hex(&[]), trie.root_node())];
+        while let Some((path, node)) = stack.pop() {
+            let node = node.resolve(memdb.as_ref()).unwrap();
+            match node.kind() {
+                NodeKind::Leaf => {
+                    let path = path.join(&node.prefix().unwrap());
+                    found.push((path.encode_raw().0, node.value().unwrap()));
+                }
+                NodeKind::Extension => {
+                    stack.push((path.join(&node.prefix().unwrap()), node.child().unwrap()));
+                }
+                NodeKind::Branch => {
+                    if let Some(value) = node.value() {
+                        found.push((path.encode_raw().0, value));
+                    }
+                    for (i, child) in node.children() {
+                        let mut child_path = path.clone();
+                        child_path.push(i);
+                        stack.push((child_path, child));
+                    }
+                }
+                kind => panic!("unexpected {:?} node", kind),
+            }
+        }
+        found.sort();
+        assert_eq!(found, trie.iter().collect::<Vec<_>>());
+        assert!(trie.root_node().hash().is_none());
+        assert_eq!(trie.at_root(root).root_node().kind(), NodeKind::Hash);
+    }
 
-        let empty_trie = EthTrie::new(memdb);
-        // Can't find key in new trie at empty root
-        assert_eq!(empty_trie.get(b"pretty-long-key").unwrap(), None);
+    #[test]
+    fn test_fork() {
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = EthTrie::new(memdb.clone());
+        for i in 0..50u32 {
+            trie.insert(&i.to_be_bytes(), format!("value-{}", i).as_bytes())
+                .unwrap();
+        }
+        let root = trie.root_hash().unwrap();
+        let nodes = memdb.len().unwrap();
 
-        let trie_view = empty_trie.at_root(new_root_hash);
+        let mut fork = trie.fork().unwrap();
+        fork.insert(&7u32.to_be_bytes(), b"forked").unwrap();
+        fork.remove(&8u32.to_be_bytes()).unwrap();
+        trie.insert(&7u32.to_be_bytes(), b"original").unwrap();
         assert_eq!(
-            &trie_view.get(b"pretty-long-key").unwrap().unwrap(),
-            b"even-longer-val-to-go-more-than-32-bytes"
+            trie.get(&8u32.to_be_bytes()).unwrap(),
+            Some(b"value-8".to_vec())
         );
 
-        // Previous trie was not modified
-        assert_eq!(empty_trie.get(b"pretty-long-key").unwrap(), None);
+        let fork_root = fork.root_hash().unwrap();
+        let new_root = trie.root_hash().unwrap();
+        assert_ne!(fork_root, new_root);
+        assert_ne!(fork_root, root);
+        assert_eq!(
+            fork.get(&7u32.to_be_bytes()).unwrap(),
+            Some(b"forked".to_vec())
+        );
+        assert_eq!(
+            trie.get(&7u32.to_be_bytes()).unwrap(),
+            Some(b"original".to_vec())
+        );
+        assert_eq!(fork.get(&8u32.to_be_bytes()).unwrap(), None);
+
+        // Only the changed paths were written again
+        assert!(memdb.len().unwrap() - nodes < 20);
+
+        trie.insert(b"uncommitted", b"value").unwrap();
+        assert!(matches!(trie.fork(), Err(TrieError::UncommittedChanges)));
+
+        // A trie built around a new root node is not committed until that node is written
+        let mut prefixed = fork.with_prefix(b"ns").unwrap();
+        assert!(matches!(
+            prefixed.fork(),
+            Err(TrieError::UncommittedChanges)
+        ));
+        prefixed.root_hash().unwrap();
+        let forked = prefixed.fork().unwrap();
+        assert_eq!(
+            forked
+                .get(&[b"ns", &7u32.to_be_bytes()[..]].concat())
+                .unwrap(),
+            Some(b"forked".to_vec())
+        );
+
+        // A root that is not in the db
+        let missing = trie.at_root(keccak(b"missing"));
+        assert!(matches!(missing.fork(), Err(TrieError::UncommittedChanges)));
+    }
+
+    #[test]
+    fn test_default() {
+        let mut trie = EthTrie::default();
+        assert!(trie.is_empty());
+        trie.insert(b"test", b"test").unwrap();
+        let root = trie.root_hash().unwrap();
+
+        // Each default trie has a db of its own
+        let other = EthTrie::default();
+        assert!(other.is_empty());
+        assert!(matches!(
+            other.at_root(root).get(b"test"),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+        assert_eq!(trie.get(b"test").unwrap(), Some(b"test".to_vec()));
+    }
+
+    #[test]
+    fn test_root_history() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = EthTrie::new(memdb.clone());
+        trie.insert(b"test", b"test").unwrap();
+        trie.root_hash().unwrap();
+        assert!(EthTrie::root_history(&memdb).unwrap().is_empty());
+
+        let mut trie = trie.with_root_history(3);
+        let mut roots = vec![];
+        for i in 0..5u8 {
+            trie.insert(&[i], b"value").unwrap();
+            roots.push(trie.root_hash().unwrap());
+        }
+        // Committing without changes records nothing
+        trie.root_hash().unwrap();
+        assert_eq!(EthTrie::root_history(&memdb).unwrap(), roots[2..].to_vec());
+
+        // Tries derived from it record to the same list
+        let mut other = trie.at_root(roots[4]);
+        other.insert(b"other", b"value").unwrap();
+        roots.push(other.root_hash().unwrap());
+        assert_eq!(EthTrie::root_history(&memdb).unwrap(), roots[3..].to_vec());
     }
 }
@@ -0,0 +1,155 @@
+use std::marker::PhantomData;
+
+use keccak_hash::H256;
+use rlp::{Decodable, Encodable};
+
+use crate::db::DB;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, Trie, TrieResult};
+
+/// A trie whose values are of type T, stored as their RLP encoding, such as the accounts of
+/// a state trie or the receipts of a block.
+///
+/// Values are encoded on insert and decoded on read, and a stored value that does not
+/// decode as T is reported as `TrieError::Decoder`. Everything else is done by the wrapped
+/// `EthTrie`, which `inner` and `inner_mut` give access to for the byte-level API.
+#[derive(Debug)]
+pub struct TypedTrie<T, D, H = KeccakHasher>
+where
+    T: Encodable + Decodable,
+    D: DB,
+    H: Hasher,
+{
+    trie: EthTrie<D, H>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T, D, H> TypedTrie<T, D, H>
+where
+    T: Encodable + Decodable,
+    D: DB,
+    H: Hasher,
+{
+    /// Wraps a trie whose values are RLP-encoded T.
+    pub fn new(trie: EthTrie<D, H>) -> Self {
+        TypedTrie {
+            trie,
+            value: PhantomData,
+        }
+    }
+
+    /// Returns the value for key, decoded as T.
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<T>> {
+        match self.trie.get(key)? {
+            Some(data) => Ok(Some(rlp::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that the key is present in the trie
+    pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.contains(key)
+    }
+
+    /// Inserts the RLP encoding of value at key.
+    pub fn insert(&mut self, key: &[u8], value: &T) -> TrieResult<()> {
+        self.trie.insert(key, &rlp::encode(value))
+    }
+
+    /// Removes any existing value for key from the trie.
+    pub fn remove(&mut self, key: &[u8]) -> TrieResult<bool> {
+        self.trie.remove(key)
+    }
+
+    /// Commits the trie and returns its root hash, like `Trie::root_hash`.
+    pub fn root_hash(&mut self) -> TrieResult<H256> {
+        self.trie.root_hash()
+    }
+
+    /// Returns the proof for key, in the same form as `Trie::get_proof`.
+    pub fn get_proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.get_proof(key)
+    }
+
+    pub fn inner(&self) -> &EthTrie<D, H> {
+        &self.trie
+    }
+
+    pub fn inner_mut(&mut self) -> &mut EthTrie<D, H> {
+        &mut self.trie
+    }
+
+    pub fn into_inner(self) -> EthTrie<D, H> {
+        self.trie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rlp::{DecoderError, Rlp, RlpStream};
+
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::errors::TrieError;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Account {
+        nonce: u64,
+        balance: u64,
+    }
+
+    impl Encodable for Account {
+        fn rlp_append(&self, s: &mut RlpStream) {
+            s.begin_list(2);
+            s.append(&self.nonce);
+            s.append(&self.balance);
+        }
+    }
+
+    impl Decodable for Account {
+        fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+            Ok(Account {
+                nonce: rlp.val_at(0)?,
+                balance: rlp.val_at(1)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_typed_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = TypedTrie::new(EthTrie::new(memdb));
+        let account = Account {
+            nonce: 1,
+            balance: 1000,
+        };
+        trie.insert(b"alice", &account).unwrap();
+        trie.insert(
+            b"bob",
+            &Account {
+                nonce: 0,
+                balance: 5,
+            },
+        )
+        .unwrap();
+        assert_eq!(trie.get(b"alice").unwrap(), Some(account.clone()));
+        assert_eq!(trie.get(b"carol").unwrap(), None);
+        assert!(trie.remove(b"bob").unwrap());
+        assert!(!trie.contains(b"bob").unwrap());
+
+        // The values are stored as their RLP encoding
+        let root = trie.root_hash().unwrap();
+        let mut raw = trie.into_inner();
+        assert_eq!(
+            raw.get(b"alice").unwrap(),
+            Some(rlp::encode(&account).to_vec())
+        );
+        assert_eq!(raw.root_hash().unwrap(), root);
+
+        raw.insert(b"bob", b"not an account").unwrap();
+        let trie: TypedTrie<Account, _> = TypedTrie::new(raw);
+        assert!(matches!(trie.get(b"bob"), Err(TrieError::Decoder(_))));
+    }
+}